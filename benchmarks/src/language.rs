@@ -177,7 +177,7 @@ async fn sequential_write() -> Result<(), Error> {
     let mut desc = spdk_rs::bdev::SpdkBdevDesc::new();
 
     // check whether device has volatile write cache enabled
-    // if it's true, we may want to call `spdk_bdev_flush()` to flush the writes (not implemented for now)
+    // if it's true, the writes are only durable after `spdk_rs::bdev::flush()`
     let is_write_cache_enabled = spdk_rs::bdev::has_write_cache(bdev.clone());
     debug!("is_write_cache_enabled: {}", is_write_cache_enabled);
 
@@ -297,7 +297,7 @@ async fn latency() -> Result<(), Error> {
     let mut desc = spdk_rs::bdev::SpdkBdevDesc::new();
 
     // check whether device has volatile write cache enabled
-    // if it's true, we may want to call `spdk_bdev_flush()` to flush the writes (not implemented for now)
+    // if it's true, the writes are only durable after `spdk_rs::bdev::flush()`
     let is_write_cache_enabled = spdk_rs::bdev::has_write_cache(bdev.clone());
     dbg!(is_write_cache_enabled);
 
@@ -0,0 +1,406 @@
+/*************************************************************************
+  > File Name:       alloc.rs
+  > Author:          Zeyuan Hu
+  > Mail:            iamzeyuanhu@utexas.edu
+  > Created Time:    10/17/26
+  > Description:
+
+    Free space management for the data area of a device.
+
+    BlockAllocator keeps one bit per block (the same bitmap that is stored
+    on disk) and hands out contiguous runs, trying the block right after
+    the previous run first so that sequential appends stay contiguous.
+    Freed blocks are only reused after the next sync: until then the tree
+    on disk may still point at them.
+
+    Blocks is the per-inode side: the extents (page -> block runs) of one
+    file or directory. Its blocks go back to the allocator when it is
+    dropped, that is when the last reference to the inode goes away.
+ ************************************************************************/
+
+use crate::error::{FsError, FsResult};
+use crate::extent::ExtentMap;
+use crate::layout::{Extent, Superblock, BLOCK_SIZE};
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+pub type RcAllocator = Arc<Mutex<BlockAllocator>>;
+
+pub struct BlockAllocator {
+    bits: Vec<u8>,
+    start: u64, // first data block
+    end: u64,   // number of blocks
+    available: u64,
+    pending: Vec<(u64, u64)>, // freed, reusable after the next sync
+    pending_count: u64
+}
+
+impl BlockAllocator {
+    /// An allocator where every data block of `sb` is free.
+    pub fn new(sb: &Superblock) -> BlockAllocator {
+        let mut allocator = BlockAllocator {
+            bits: vec![0u8; sb.bitmap_blocks as usize * BLOCK_SIZE],
+            start: sb.data_start,
+            end: sb.num_blocks,
+            available: sb.num_blocks,
+            pending: Vec::new(),
+            pending_count: 0
+        };
+        for block in 0..sb.data_start { allocator.set(block); }
+        allocator
+    }
+
+    pub fn into_rc(self) -> RcAllocator {
+        Arc::new(Mutex::new(self))
+    }
+
+    pub fn bitmap(&self) -> &[u8] {
+        &self.bits
+    }
+
+    /// The bitmap as it will be after commit.
+    pub fn committed_bitmap(&self) -> Vec<u8> {
+        let mut bits = self.bits.clone();
+        for &(first, len) in self.pending.iter() {
+            for block in first..(first + len) {
+                bits[(block / 8) as usize] &= !(1 << (block % 8));
+            }
+        }
+        bits
+    }
+
+    pub fn is_used(&self, block: u64) -> bool {
+        self.bits[(block / 8) as usize] & (1 << (block % 8)) != 0
+    }
+
+    #[inline(always)]
+    fn set(&mut self, block: u64) {
+        self.bits[(block / 8) as usize] |= 1 << (block % 8);
+        self.available -= 1;
+    }
+
+    #[inline(always)]
+    fn clear(&mut self, block: u64) {
+        self.bits[(block / 8) as usize] &= !(1 << (block % 8));
+        self.available += 1;
+    }
+
+    /// Blocks that are not in use by any file, counting the ones that will
+    /// be reusable after the next sync.
+    pub fn free_blocks(&self) -> u64 {
+        self.available + self.pending_count
+    }
+
+    /// Blocks in use, metadata included.
+    pub fn used_blocks(&self) -> u64 {
+        self.end - self.free_blocks()
+    }
+
+    // Length of the free run starting at `block`, at most `max`
+    fn run_at(&self, block: u64, max: u64) -> u64 {
+        let mut len = 0;
+        while len < max && block + len < self.end && !self.is_used(block + len) {
+            len += 1;
+        }
+        len
+    }
+
+    // First free block in [from, to)
+    fn next_free(&self, mut from: u64, to: u64) -> Option<u64> {
+        while from < to {
+            // Skip full bytes at once
+            if from % 8 == 0 && self.bits[(from / 8) as usize] == 0xff {
+                from += 8;
+                continue;
+            }
+            if !self.is_used(from) { return Some(from); }
+            from += 1;
+        }
+        None
+    }
+
+    /// Allocates a run of at most `count` blocks and returns its first block
+    /// and length. The run starting at `goal` is taken if it is free;
+    /// otherwise the first run of `count` blocks after `goal`, and if there
+    /// is none the longest run there is.
+    pub fn alloc(&mut self, count: u64, goal: u64) -> FsResult<(u64, u64)> {
+        if count == 0 { return Err(FsError::EINVAL); }
+        if self.available == 0 { return Err(FsError::ENOSPC); }
+
+        let goal = if goal >= self.start && goal < self.end { goal } else { self.start };
+        let mut best = (goal, self.run_at(goal, count));
+
+        // Search [goal, end) and then wrap around to [start, goal)
+        for &(from, to) in [(goal, self.end), (self.start, goal)].iter() {
+            let mut block = from;
+            while best.1 < count {
+                block = match self.next_free(block, to) {
+                    Some(b) => b,
+                    None => break
+                };
+                let len = self.run_at(block, count);
+                if len > best.1 { best = (block, len); }
+                block += len;
+            }
+        }
+
+        let (first, len) = best;
+        for block in first..(first + len) { self.set(block); }
+        Ok(best)
+    }
+
+    /// Marks a run as in use. Fails with EIO if any block already is; used
+    /// when rebuilding the bitmap from the inodes.
+    pub fn claim(&mut self, first: u64, len: u64) -> FsResult<()> {
+        let end = first.checked_add(len).ok_or(FsError::EIO)?;
+        if first < self.start || end > self.end { return Err(FsError::EIO); }
+        if (first..end).any(|b| self.is_used(b)) { return Err(FsError::EIO); }
+        for block in first..end { self.set(block); }
+        Ok(())
+    }
+
+    /// Frees a run. The blocks stay marked until commit.
+    pub fn free(&mut self, first: u64, len: u64) {
+        if len == 0 { return; }
+        self.pending.push((first, len));
+        self.pending_count += len;
+    }
+
+    /// Gives back a run that was allocated but never used, so it can be
+    /// reused right away.
+    pub fn cancel(&mut self, first: u64, len: u64) {
+        for block in first..(first + len) { self.clear(block); }
+    }
+
+    /// Makes the blocks freed since the last call reusable. Called once the
+    /// tree that no longer points at them is on disk.
+    pub fn commit(&mut self) {
+        for (first, len) in self.pending.split_off(0) {
+            self.cancel(first, len);
+        }
+        self.pending_count = 0;
+    }
+}
+
+/// The blocks of one inode on the device. Without an allocator (a file
+/// system that only lives in memory) no blocks are ever mapped.
+pub struct Blocks {
+    alloc: Option<RcAllocator>,
+    extents: ExtentMap,
+    /// Blocks holding the extents that do not fit in the inode record
+    overflow: Vec<u64>,
+    version: u64 // bumped whenever a page is mapped or unmapped
+}
+
+impl Blocks {
+    pub fn new(alloc: Option<RcAllocator>) -> Blocks {
+        Blocks { alloc: alloc, extents: ExtentMap::new(), overflow: Vec::new(), version: 0 }
+    }
+
+    /// Blocks read back from disk. The caller has already claimed them.
+    pub fn from_disk(alloc: RcAllocator, extents: ExtentMap, overflow: Vec<u64>) -> Blocks {
+        Blocks { alloc: Some(alloc), extents: extents, overflow: overflow, version: 0 }
+    }
+
+    pub fn allocator(&self) -> Option<&RcAllocator> {
+        self.alloc.as_ref()
+    }
+
+    pub fn extents(&self) -> &ExtentMap {
+        &self.extents
+    }
+
+    pub fn overflow(&self) -> &[u64] {
+        &self.overflow
+    }
+
+    /// Changes whenever the mapping from pages to blocks does.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// The block page `page` is mapped to, if any.
+    pub fn lookup(&self, page: u64) -> Option<u64> {
+        self.extents.lookup(page)
+    }
+
+    // Where the block for `page` would best go: right after the block of the
+    // page before it, or after the last extent.
+    fn goal(&self, page: u64) -> u64 {
+        if page > 0 {
+            if let Some(block) = self.lookup(page - 1) { return block + 1; }
+        }
+        self.extents.last().map_or(0, |e| e.physical + e.len)
+    }
+
+    /// Maps every page in [first, first + count) that is not mapped yet,
+    /// and returns the extents added. Either all of them get a block or, on
+    /// ENOSPC, none.
+    pub fn reserve(&mut self, first: u64, count: u64) -> FsResult<Vec<Extent>> {
+        let alloc = match self.alloc {
+            Some(ref alloc) => alloc.clone(),
+            None => return Ok(Vec::new())
+        };
+
+        let end = first + count;
+        let mut added: Vec<Extent> = Vec::new();
+        let mut page = first;
+        while page < end {
+            if self.lookup(page).is_some() {
+                page += 1;
+                continue;
+            }
+
+            // Length of the unmapped run starting at `page`
+            let mut len = 1;
+            while page + len < end && self.lookup(page + len).is_none() { len += 1; }
+
+            let result = alloc.lock().alloc(len, self.goal(page));
+            match result {
+                Ok((physical, got)) => {
+                    let e = Extent { logical: page, physical: physical, len: got };
+                    added.push(e);
+                    self.extents.insert(e);
+                    page += got;
+                }
+                Err(e) => {
+                    let mut alloc = alloc.lock();
+                    for a in added {
+                        for (first, len) in self.extents.remove(a.logical, a.logical + a.len) {
+                            alloc.cancel(first, len);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        if !added.is_empty() { self.version += 1; }
+        Ok(added)
+    }
+
+    /// Frees the blocks of every page in [from, to), and returns them as
+    /// (first block, count) runs.
+    pub fn release(&mut self, from: u64, to: u64) -> Vec<(u64, u64)> {
+        let freed = self.extents.remove(from, to);
+        if !freed.is_empty() { self.version += 1; }
+        if let Some(ref alloc) = self.alloc {
+            let mut alloc = alloc.lock();
+            for &(first, len) in freed.iter() { alloc.free(first, len); }
+        }
+        freed
+    }
+
+    /// Replaces the overflow blocks with `count` new ones. The old ones are
+    /// freed, they stay untouched until the next commit.
+    pub fn replace_overflow(&mut self, count: usize) -> FsResult<()> {
+        if self.overflow.is_empty() && count == 0 { return Ok(()); }
+        let alloc = self.alloc.clone().ok_or(FsError::EINVAL)?;
+        let mut alloc = alloc.lock();
+
+        let mut blocks = Vec::with_capacity(count);
+        while blocks.len() < count {
+            let goal = blocks.last().map_or(0, |b| b + 1);
+            match alloc.alloc(1, goal) {
+                Ok((block, _)) => blocks.push(block),
+                Err(e) => {
+                    for block in blocks { alloc.cancel(block, 1); }
+                    return Err(e);
+                }
+            }
+        }
+
+        for block in self.overflow.drain(..) { alloc.free(block, 1); }
+        self.overflow = blocks;
+        Ok(())
+    }
+}
+
+impl Drop for Blocks {
+    fn drop(&mut self) {
+        self.release(0, u64::max_value());
+        if let Some(ref alloc) = self.alloc {
+            let mut alloc = alloc.lock();
+            for block in self.overflow.drain(..) { alloc.free(block, 1); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockAllocator, Blocks};
+    use crate::error::FsError;
+    use crate::layout::{Extent, Superblock};
+
+    fn allocator(num_blocks: u64) -> BlockAllocator {
+        BlockAllocator::new(&Superblock::new(num_blocks, 16, [0; 16]).unwrap())
+    }
+
+    #[test]
+    fn test_alloc_runs() {
+        let mut a = allocator(64);
+        let start = a.start;
+        let free = a.free_blocks();
+
+        assert_eq!(a.alloc(4, 0), Ok((start, 4)));
+        // The goal is taken when it is free
+        assert_eq!(a.alloc(2, start + 10), Ok((start + 10, 2)));
+        // A run that does not fit at the goal goes to the next hole that fits
+        assert_eq!(a.alloc(8, start + 4), Ok((start + 12, 8)));
+        assert_eq!(a.free_blocks(), free - 14);
+        assert_eq!(a.used_blocks(), 64 - free + 14);
+
+        // Freed blocks are only reused after commit
+        a.free(start, 4);
+        assert_eq!(a.free_blocks(), free - 10);
+        assert_eq!(a.alloc(4, start), Ok((start + 4, 4)));
+        a.commit();
+        assert_eq!(a.alloc(4, start), Ok((start, 4)));
+    }
+
+    #[test]
+    fn test_alloc_full() {
+        let mut a = allocator(64);
+        let free = a.free_blocks();
+        let start = a.start;
+
+        // Only the longest run is handed out when nothing fits
+        a.alloc(free, 0).unwrap();
+        a.free(start + 2, 3);
+        a.free(start + 8, 1);
+        a.commit();
+        assert_eq!(a.alloc(10, 0), Ok((start + 2, 3)));
+        assert_eq!(a.alloc(10, 0), Ok((start + 8, 1)));
+        assert_eq!(a.alloc(1, 0), Err(FsError::ENOSPC));
+        assert_eq!(a.claim(start, 1), Err(FsError::EIO));
+    }
+
+    #[test]
+    fn test_blocks() {
+        let alloc = allocator(64).into_rc();
+        let start = alloc.lock().start;
+        let free = alloc.lock().free_blocks();
+        let mut blocks = Blocks::new(Some(alloc.clone()));
+
+        // Appends extend the same extent
+        assert_eq!(blocks.reserve(0, 3), Ok(vec![Extent { logical: 0, physical: start, len: 3 }]));
+        blocks.reserve(3, 2).unwrap();
+        assert_eq!(blocks.extents().len(), 1);
+        assert_eq!(blocks.reserve(0, 5), Ok(Vec::new()));
+        assert_eq!(blocks.lookup(4), Some(start + 4));
+        assert_eq!(blocks.lookup(5), None);
+
+        // Punching out the middle splits the extent
+        blocks.release(1, 3);
+        assert_eq!(blocks.extents().len(), 2);
+        assert_eq!(blocks.lookup(1), None);
+        assert_eq!(blocks.lookup(3), Some(start + 3));
+        assert_eq!(alloc.lock().free_blocks(), free - 3);
+
+        // A reservation that does not fit leaves nothing behind
+        assert_eq!(blocks.reserve(10, 1000), Err(FsError::ENOSPC));
+        assert_eq!(blocks.extents().len(), 2);
+
+        drop(blocks);
+        assert_eq!(alloc.lock().free_blocks(), free);
+    }
+}
@@ -0,0 +1,124 @@
+/*************************************************************************
+  > File Name:       cred.rs
+  > Author:          Zeyuan Hu
+  > Mail:            iamzeyuanhu@utexas.edu
+  > Created Time:    10/17/26
+  > Description:
+
+    Credentials of a Proc and the POSIX permission checks done with them.
+    Every file has an owner, a group and nine permission bits (read, write
+    and execute/search for the owner, the group and everyone else); the
+    class the caller falls in is picked first and only its bits are looked
+    at. uid 0 is the superuser and passes every check, except that it can
+    only execute files that have at least one execute bit set.
+ ************************************************************************/
+
+use crate::error::{FsError, FsResult};
+use crate::inode::Attr;
+
+/// Permission requests, as passed to Proc::access.
+pub const F_OK: u16 = 0;
+pub const X_OK: u16 = 1;
+pub const W_OK: u16 = 2;
+pub const R_OK: u16 = 4;
+
+pub const S_ISUID: u16 = 0o4000;
+pub const S_ISGID: u16 = 0o2000;
+/// On a directory: only the owner of an entry (or of the directory) may
+/// remove or rename it.
+pub const S_ISVTX: u16 = 0o1000;
+
+/// Who a Proc acts as. Files it creates belong to `uid` and `gid` and get
+/// the bits in `umask` cleared from their mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cred {
+    pub uid: u32,
+    pub gid: u32,
+    pub groups: Vec<u32>, // supplementary groups
+    pub umask: u16
+}
+
+impl Cred {
+    pub fn new(uid: u32, gid: u32, groups: Vec<u32>) -> Cred {
+        Cred { uid: uid, gid: gid, groups: groups, umask: 0o022 }
+    }
+
+    pub fn root() -> Cred {
+        Cred::new(0, 0, Vec::new())
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+
+    /// Whether the caller may do everything in `want` (a mix of R_OK, W_OK
+    /// and X_OK) to a file with the given metadata.
+    pub fn permits(&self, attr: &Attr, is_dir: bool, want: u16) -> bool {
+        if self.is_root() {
+            return (want & X_OK) == 0 || is_dir || (attr.mode & 0o111) != 0;
+        }
+
+        let shift = if self.uid == attr.uid {
+            6
+        } else if self.in_group(attr.gid) {
+            3
+        } else {
+            0
+        };
+        let granted = (attr.mode >> shift) & 0o7;
+        (want & !granted) == 0
+    }
+
+    /// Same as permits, failing with EACCES.
+    pub fn check(&self, attr: &Attr, is_dir: bool, want: u16) -> FsResult<()> {
+        if self.permits(attr, is_dir, want) { Ok(()) } else { Err(FsError::EACCES) }
+    }
+
+    /// Whether the caller may change the mode or times of a file: only its
+    /// owner and the superuser can.
+    pub fn owns(&self, attr: &Attr) -> bool {
+        self.is_root() || self.uid == attr.uid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cred, R_OK, W_OK, X_OK};
+    use crate::inode::Attr;
+
+    #[test]
+    fn test_permits() {
+        let mut attr = Attr::new(0o640);
+        attr.uid = 10;
+        attr.gid = 20;
+
+        let owner = Cred::new(10, 99, Vec::new());
+        assert!(owner.permits(&attr, false, R_OK | W_OK));
+        assert!(!owner.permits(&attr, false, X_OK));
+
+        // Supplementary groups count; the owner class wins over the group one
+        let member = Cred::new(11, 99, vec![20]);
+        assert!(member.permits(&attr, false, R_OK));
+        assert!(!member.permits(&attr, false, W_OK));
+        attr.mode = 0o077;
+        assert!(!owner.permits(&attr, false, R_OK));
+        assert!(member.permits(&attr, false, R_OK | W_OK | X_OK));
+
+        let other = Cred::new(12, 99, Vec::new());
+        attr.mode = 0o604;
+        assert!(other.permits(&attr, false, R_OK));
+        assert!(!other.permits(&attr, false, R_OK | W_OK));
+
+        let root = Cred::root();
+        attr.mode = 0;
+        assert!(root.permits(&attr, false, R_OK | W_OK));
+        assert!(!root.permits(&attr, false, X_OK));
+        assert!(root.permits(&attr, true, X_OK));
+        attr.mode = 0o001;
+        assert!(root.permits(&attr, false, X_OK));
+    }
+}
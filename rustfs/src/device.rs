@@ -0,0 +1,619 @@
+/*************************************************************************
+  > File Name:       device.rs
+  > Author:          Zeyuan Hu
+  > Mail:            iamzeyuanhu@utexas.edu
+  > Created Time:    10/17/26
+  > Description:
+
+    The block device abstraction the file system stores its data and
+    metadata on. All I/O is done in whole blocks and is asynchronous so that
+    the SPDK implementation can run on the reactor without blocking it.
+
+    - SpdkDevice: a bdev opened through spdk_rs (SpdkBdevDesc + SpdkIoChannel)
+    - MemDevice: a plain in-memory vector, handy for tests
+    - FileDevice: a regular file on the host file system
+
+    Pages of files are kept in IoBufs, in the memory the device asks for
+    (see BlockDevice::page_memory). An SpdkDevice asks for DMA memory, so
+    its pages are env::Bufs that writev_blocks and readv_blocks hand to the
+    bdev as they are; other devices copy through their own I/O path.
+ ************************************************************************/
+
+use crate::error::{FsError, FsResult};
+use spdk_rs::bdev;
+use spdk_rs::bdev::SpdkBdevDesc;
+use spdk_rs::env;
+use spdk_rs::thread;
+use spdk_rs::thread::SpdkIoChannel;
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::pin::Pin;
+use std::slice;
+use std::thread::ThreadId;
+
+pub type IoFuture<'a, T> = Pin<Box<dyn Future<Output = FsResult<T>> + 'a>>;
+
+/// The memory IoBufs are allocated in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageMemory {
+    /// Ordinary memory
+    Heap,
+    /// DMA memory from spdk_dma_zmalloc(), aligned as given
+    Dma(usize)
+}
+
+enum Memory {
+    Heap(Box<[u8]>),
+    Dma(env::Buf, usize, usize) // buffer, length and alignment
+}
+
+/// A buffer that a device can do I/O on in place, if it is in the memory
+/// the device asks for. Dereferences to its bytes.
+pub struct IoBuf {
+    memory: Memory
+}
+
+// A DMA buffer is plain memory owned by the IoBuf, like a Box
+unsafe impl Send for IoBuf {}
+unsafe impl Sync for IoBuf {}
+
+impl IoBuf {
+    /// A buffer of `len` zeros.
+    pub fn zeroed(len: usize, memory: PageMemory) -> IoBuf {
+        let memory = match memory {
+            PageMemory::Heap => Memory::Heap(vec![0u8; len].into_boxed_slice()),
+            PageMemory::Dma(align) => Memory::Dma(env::dma_zmalloc(len, align), len, align)
+        };
+        IoBuf { memory: memory }
+    }
+
+    pub fn memory(&self) -> PageMemory {
+        match self.memory {
+            Memory::Heap(_) => PageMemory::Heap,
+            Memory::Dma(_, _, align) => PageMemory::Dma(align)
+        }
+    }
+
+    /// The DMA buffer behind this one, to hand to SPDK without a copy, or
+    /// None if it is ordinary memory.
+    pub fn env_buf(&self) -> Option<&env::Buf> {
+        match self.memory {
+            Memory::Heap(_) => None,
+            Memory::Dma(ref buf, _, _) => Some(buf)
+        }
+    }
+}
+
+impl Deref for IoBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.memory {
+            Memory::Heap(ref data) => data,
+            Memory::Dma(ref buf, len, _) => unsafe { slice::from_raw_parts(buf.to_raw() as *const u8, len) }
+        }
+    }
+}
+
+impl DerefMut for IoBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self.memory {
+            Memory::Heap(ref mut data) => data,
+            Memory::Dma(ref buf, len, _) => unsafe { slice::from_raw_parts_mut(buf.to_raw() as *mut u8, len) }
+        }
+    }
+}
+
+impl Clone for IoBuf {
+    /// A copy in the same kind of memory.
+    fn clone(&self) -> IoBuf {
+        let mut copy = IoBuf::zeroed(self.len(), self.memory());
+        copy.copy_from_slice(self);
+        copy
+    }
+}
+
+impl Drop for IoBuf {
+    fn drop(&mut self) {
+        if let Memory::Dma(ref buf, _, _) = self.memory {
+            env::dma_free(buf.clone());
+        }
+    }
+}
+
+// The bytes of `bufs` back to back
+fn gather(bufs: &[&IoBuf]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(bufs.iter().map(|buf| buf.len()).sum());
+    for buf in bufs {
+        data.extend_from_slice(buf);
+    }
+    data
+}
+
+// Spreads `data` over `bufs` in order
+fn scatter(data: &[u8], bufs: &mut [IoBuf]) {
+    let mut done = 0;
+    for buf in bufs.iter_mut() {
+        let len = buf.len();
+        buf.copy_from_slice(&data[done..(done + len)]);
+        done += len;
+    }
+}
+
+/// Devices are shared by every thread working on a FileSystem, hence Send
+/// and Sync. Besides mount, sync and unmount, the file system reads from
+/// the device when a page of a file is first used, which may happen while
+/// a sync is writing.
+pub trait BlockDevice: Send + Sync {
+    /// Size in bytes of a block, the unit of every transfer.
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device.
+    fn num_blocks(&self) -> u64;
+
+    /// Reads `buf.len() / block_size()` blocks starting at block `offset_blocks`.
+    fn read_blocks<'a>(&'a self, offset_blocks: u64, buf: &'a mut [u8]) -> IoFuture<'a, ()>;
+
+    /// Writes `buf.len() / block_size()` blocks starting at block `offset_blocks`.
+    fn write_blocks<'a>(&'a self, offset_blocks: u64, buf: &'a [u8]) -> IoFuture<'a, ()>;
+
+    /// Makes every completed write durable.
+    fn flush<'a>(&'a self) -> IoFuture<'a, ()>;
+
+    /// Tells the device the given blocks are no longer in use. Their content
+    /// is undefined afterwards.
+    fn unmap_blocks<'a>(&'a self, offset_blocks: u64, num_blocks: u64) -> IoFuture<'a, ()>;
+
+    /// Fills the given blocks with zeros, without a buffer to send where the
+    /// device can do it itself.
+    fn write_zeroes_blocks<'a>(&'a self, offset_blocks: u64, num_blocks: u64) -> IoFuture<'a, ()>;
+
+    /// The memory to keep pages in for writev_blocks and readv_blocks to
+    /// use them in place.
+    fn page_memory(&self) -> PageMemory {
+        PageMemory::Heap
+    }
+
+    /// Writes `bufs` one after the other starting at block `offset_blocks`,
+    /// like write_blocks of them put together. By default they are.
+    fn writev_blocks<'a>(&'a self, offset_blocks: u64, bufs: &'a [&'a IoBuf]) -> IoFuture<'a, ()> {
+        Box::pin(async move { await!(self.write_blocks(offset_blocks, &gather(bufs))) })
+    }
+
+    /// Fills `bufs` one after the other with the blocks starting at
+    /// `offset_blocks`, like read_blocks into them put together. By default
+    /// that is what happens, followed by a copy.
+    fn readv_blocks<'a>(&'a self, offset_blocks: u64, bufs: &'a mut [IoBuf]) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            let mut data = vec![0u8; bufs.iter().map(|buf| buf.len()).sum()];
+            await!(self.read_blocks(offset_blocks, &mut data))?;
+            scatter(&data, bufs);
+            Ok(())
+        })
+    }
+}
+
+/// Checks that `len` bytes starting at block `offset_blocks` is a whole
+/// number of blocks that fits on `dev`, and returns the number of blocks.
+fn check_range<D: BlockDevice + ?Sized>(dev: &D, offset_blocks: u64, len: usize) -> FsResult<u64> {
+    if len % dev.block_size() != 0 { return Err(FsError::EINVAL); }
+    let num_blocks = (len / dev.block_size()) as u64;
+    check_blocks(dev, offset_blocks, num_blocks)?;
+    Ok(num_blocks)
+}
+
+fn check_blocks<D: BlockDevice + ?Sized>(dev: &D, offset_blocks: u64, num_blocks: u64) -> FsResult<()> {
+    match offset_blocks.checked_add(num_blocks) {
+        Some(end) if end <= dev.num_blocks() => Ok(()),
+        _ => Err(FsError::EINVAL)
+    }
+}
+
+/// A device backed by a vector in memory. Nothing survives the process, but
+/// the content survives unmount/mount cycles as long as the MemDevice lives.
+pub struct MemDevice {
+    block_size: usize,
+    num_blocks: u64,
+    data: RwLock<Vec<u8>>
+}
+
+impl MemDevice {
+    pub fn new(block_size: usize, num_blocks: u64) -> MemDevice {
+        MemDevice {
+            block_size: block_size,
+            num_blocks: num_blocks,
+            data: RwLock::new(vec![0; block_size * num_blocks as usize])
+        }
+    }
+}
+
+impl BlockDevice for MemDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_blocks<'a>(&'a self, offset_blocks: u64, buf: &'a mut [u8]) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            check_range(self, offset_blocks, buf.len())?;
+            let start = offset_blocks as usize * self.block_size;
+            buf.copy_from_slice(&self.data.read()[start..(start + buf.len())]);
+            Ok(())
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, offset_blocks: u64, buf: &'a [u8]) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            check_range(self, offset_blocks, buf.len())?;
+            let start = offset_blocks as usize * self.block_size;
+            self.data.write()[start..(start + buf.len())].copy_from_slice(buf);
+            Ok(())
+        })
+    }
+
+    fn flush<'a>(&'a self) -> IoFuture<'a, ()> {
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn unmap_blocks<'a>(&'a self, offset_blocks: u64, num_blocks: u64) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            check_blocks(self, offset_blocks, num_blocks)?;
+            let start = offset_blocks as usize * self.block_size;
+            let end = start + num_blocks as usize * self.block_size;
+            for byte in self.data.write()[start..end].iter_mut() { *byte = 0; }
+            Ok(())
+        })
+    }
+
+    fn write_zeroes_blocks<'a>(&'a self, offset_blocks: u64, num_blocks: u64) -> IoFuture<'a, ()> {
+        self.unmap_blocks(offset_blocks, num_blocks)
+    }
+}
+
+/// A device backed by a regular file on the host. The file is accessed with
+/// positional reads and writes; flush is fdatasync(2). Errors from the host
+/// keep their errno.
+pub struct FileDevice {
+    block_size: usize,
+    num_blocks: u64,
+    file: fs::File
+}
+
+impl FileDevice {
+    /// Creates (or truncates) the file at `path` and sizes it to hold
+    /// `num_blocks` blocks.
+    pub fn create<P: AsRef<Path>>(path: P, block_size: usize, num_blocks: u64) -> FsResult<FileDevice> {
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true)
+            .open(path)?;
+        file.set_len(block_size as u64 * num_blocks)?;
+        Ok(FileDevice { block_size: block_size, num_blocks: num_blocks, file: file })
+    }
+
+    /// Opens an existing file; the device size is the file size rounded down
+    /// to a whole number of blocks.
+    pub fn open<P: AsRef<Path>>(path: P, block_size: usize) -> FsResult<FileDevice> {
+        let file = fs::OpenOptions::new().read(true).write(true)
+            .open(path)?;
+        let len = file.metadata()?.len();
+        Ok(FileDevice { block_size: block_size, num_blocks: len / block_size as u64, file: file })
+    }
+}
+
+impl BlockDevice for FileDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_blocks<'a>(&'a self, offset_blocks: u64, buf: &'a mut [u8]) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            check_range(self, offset_blocks, buf.len())?;
+            let offset = offset_blocks * self.block_size as u64;
+            self.file.read_exact_at(buf, offset).map_err(FsError::from)
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, offset_blocks: u64, buf: &'a [u8]) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            check_range(self, offset_blocks, buf.len())?;
+            let offset = offset_blocks * self.block_size as u64;
+            self.file.write_all_at(buf, offset).map_err(FsError::from)
+        })
+    }
+
+    fn flush<'a>(&'a self) -> IoFuture<'a, ()> {
+        Box::pin(async move { self.file.sync_data().map_err(FsError::from) })
+    }
+
+    fn unmap_blocks<'a>(&'a self, offset_blocks: u64, num_blocks: u64) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            check_blocks(self, offset_blocks, num_blocks)?;
+            let zeroes = vec![0u8; self.block_size];
+            for block in offset_blocks..(offset_blocks + num_blocks) {
+                self.file.write_all_at(&zeroes, block * self.block_size as u64)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_zeroes_blocks<'a>(&'a self, offset_blocks: u64, num_blocks: u64) -> IoFuture<'a, ()> {
+        self.unmap_blocks(offset_blocks, num_blocks)
+    }
+}
+
+/// A device backed by an SPDK bdev. Transfers of plain buffers go through a
+/// DMA-able bounce buffer allocated with spdk_dma_zmalloc(); pages, which
+/// are DMA memory (see page_memory), go to the bdev as they are with
+/// writev_blocks and readv_blocks. I/O goes through an I/O
+/// channel of the thread polling the future, got on its first transfer, so
+/// any SPDK thread can use the device; on other threads it fails with EIO.
+/// The device must be dropped on the thread that opened it. Flushing is a
+/// no-op unless the bdev has a volatile write cache.
+pub struct SpdkDevice {
+    desc: SpdkBdevDesc,
+    channels: Mutex<HashMap<ThreadId, SpdkIoChannel>>,
+    block_size: usize,
+    num_blocks: u64,
+    buf_align: usize,
+    write_cache: bool
+}
+
+impl SpdkDevice {
+    /// Opens the bdev called `name` for writing and gets an I/O channel for
+    /// the calling thread, which must be an SPDK thread.
+    pub fn open(name: &str) -> FsResult<SpdkDevice> {
+        let bdev = bdev::get_by_name(name).map_err(|_| FsError::ENOENT)?;
+        let mut desc = SpdkBdevDesc::new();
+        bdev::open(bdev.clone(), true, &mut desc).map_err(|_| FsError::EIO)?;
+        let channel = match bdev::get_io_channel(desc.clone()) {
+            Ok(channel) => channel,
+            Err(_) => {
+                bdev::close(desc);
+                return Err(FsError::EIO);
+            }
+        };
+
+        let mut channels = HashMap::new();
+        channels.insert(std::thread::current().id(), channel);
+        Ok(SpdkDevice {
+            desc: desc,
+            channels: Mutex::new(channels),
+            block_size: bdev::get_block_size(bdev.clone()) as usize,
+            num_blocks: bdev::get_num_blocks(bdev.clone()),
+            buf_align: bdev::get_buf_align(bdev.clone()),
+            write_cache: bdev::has_write_cache(bdev)
+        })
+    }
+
+    /// The I/O channel of the calling thread, got from SPDK the first time
+    /// the thread asks.
+    fn channel(&self) -> FsResult<SpdkIoChannel> {
+        let mut channels = self.channels.lock();
+        let id = std::thread::current().id();
+        if let Some(channel) = channels.get(&id) {
+            return Ok(channel.clone());
+        }
+        let channel = bdev::get_io_channel(self.desc.clone()).map_err(|_| FsError::EIO)?;
+        channels.insert(id, channel.clone());
+        Ok(channel)
+    }
+}
+
+// A bdev descriptor is meant to be shared by threads, each getting its own
+// I/O channel from it. A channel is only used by the thread it was got on
+// (see channel(): the futures are not Send, so they are polled where they
+// started), and spdk_put_io_channel() hands it back to that thread itself.
+unsafe impl Send for SpdkDevice {}
+unsafe impl Sync for SpdkDevice {}
+
+impl Drop for SpdkDevice {
+    fn drop(&mut self) {
+        for (_, channel) in self.channels.lock().drain() {
+            thread::put_io_channel(channel);
+        }
+        bdev::close(self.desc.clone());
+    }
+}
+
+impl BlockDevice for SpdkDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_blocks<'a>(&'a self, offset_blocks: u64, buf: &'a mut [u8]) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            check_range(self, offset_blocks, buf.len())?;
+            let channel = self.channel()?;
+            let offset = offset_blocks * self.block_size as u64;
+            let mut dma = env::dma_zmalloc(buf.len(), self.buf_align);
+            let res = await!(bdev::read(self.desc.clone(), &channel, &mut dma,
+                                        offset, buf.len() as u64));
+            if res.is_ok() {
+                buf.copy_from_slice(dma.read_bytes(buf.len()));
+            }
+            env::dma_free(dma);
+            res.map_err(|_| FsError::EIO)
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, offset_blocks: u64, buf: &'a [u8]) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            check_range(self, offset_blocks, buf.len())?;
+            let channel = self.channel()?;
+            let offset = offset_blocks * self.block_size as u64;
+            let mut dma = env::dma_zmalloc(buf.len(), self.buf_align);
+            dma.fill_bytes(buf);
+            let res = await!(bdev::write(self.desc.clone(), &channel, &dma,
+                                         offset, buf.len() as u64));
+            env::dma_free(dma);
+            res.map_err(|_| FsError::EIO)
+        })
+    }
+
+    fn flush<'a>(&'a self) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            // Completed writes are already durable without a write cache
+            if !self.write_cache { return Ok(()); }
+            let channel = self.channel()?;
+            let res = await!(bdev::flush(self.desc.clone(), &channel,
+                                         0, self.num_blocks * self.block_size as u64));
+            res.map_err(|_| FsError::EIO)
+        })
+    }
+
+    fn unmap_blocks<'a>(&'a self, offset_blocks: u64, num_blocks: u64) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            check_blocks(self, offset_blocks, num_blocks)?;
+            let channel = self.channel()?;
+            let res = await!(bdev::unmap_blocks(self.desc.clone(), &channel,
+                                                offset_blocks, num_blocks));
+            res.map_err(|_| FsError::EIO)
+        })
+    }
+
+    fn write_zeroes_blocks<'a>(&'a self, offset_blocks: u64, num_blocks: u64) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            check_blocks(self, offset_blocks, num_blocks)?;
+            let channel = self.channel()?;
+            let res = await!(bdev::write_zeroes_blocks(self.desc.clone(), &channel,
+                                                       offset_blocks, num_blocks));
+            res.map_err(|_| FsError::EIO)
+        })
+    }
+
+    fn page_memory(&self) -> PageMemory {
+        PageMemory::Dma(self.buf_align)
+    }
+
+    fn writev_blocks<'a>(&'a self, offset_blocks: u64, bufs: &'a [&'a IoBuf]) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            // Buffers in ordinary memory take the bounce buffer
+            if bufs.iter().any(|buf| buf.env_buf().is_none()) {
+                return await!(self.write_blocks(offset_blocks, &gather(bufs)));
+            }
+            let len = bufs.iter().map(|buf| buf.len()).sum();
+            check_range(self, offset_blocks, len)?;
+            let channel = self.channel()?;
+            let mut iovs = env::IoVecs::new();
+            for buf in bufs {
+                iovs.push(buf.env_buf().unwrap(), buf.len());
+            }
+            let res = await!(bdev::writev(self.desc.clone(), &channel, &mut iovs,
+                                          offset_blocks * self.block_size as u64, len as u64));
+            res.map_err(|_| FsError::EIO)
+        })
+    }
+
+    fn readv_blocks<'a>(&'a self, offset_blocks: u64, bufs: &'a mut [IoBuf]) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            if bufs.iter().any(|buf| buf.env_buf().is_none()) {
+                let mut data = vec![0u8; bufs.iter().map(|buf| buf.len()).sum()];
+                await!(self.read_blocks(offset_blocks, &mut data))?;
+                scatter(&data, bufs);
+                return Ok(());
+            }
+            let len = bufs.iter().map(|buf| buf.len()).sum();
+            check_range(self, offset_blocks, len)?;
+            let channel = self.channel()?;
+            let mut iovs = env::IoVecs::new();
+            for buf in bufs.iter() {
+                iovs.push(buf.env_buf().unwrap(), buf.len());
+            }
+            let res = await!(bdev::readv(self.desc.clone(), &channel, &mut iovs,
+                                         offset_blocks * self.block_size as u64, len as u64));
+            res.map_err(|_| FsError::EIO)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockDevice, FileDevice, IoBuf, MemDevice, PageMemory};
+    use crate::error::FsError;
+    use futures_new::executor::block_on;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn exercise<D: BlockDevice>(dev: &D) {
+        let bs = dev.block_size();
+        let data: Vec<u8> = (0..(3 * bs)).map(|i| (i % 251) as u8).collect();
+        let mut buf = vec![0u8; 3 * bs];
+
+        block_on(dev.write_blocks(2, &data)).unwrap();
+        block_on(dev.flush()).unwrap();
+        block_on(dev.read_blocks(2, &mut buf)).unwrap();
+        assert_eq!(data, buf);
+
+        block_on(dev.unmap_blocks(3, 1)).unwrap();
+        block_on(dev.read_blocks(3, &mut buf[..bs])).unwrap();
+        assert!(buf[..bs].iter().all(|&b| b == 0));
+        block_on(dev.write_zeroes_blocks(4, 1)).unwrap();
+        block_on(dev.read_blocks(2, &mut buf)).unwrap();
+        assert_eq!(buf[..bs], data[..bs]);
+        assert!(buf[bs..].iter().all(|&b| b == 0));
+
+        // Vectored transfers are one transfer of the buffers put together
+        let mut bufs = vec![IoBuf::zeroed(bs, dev.page_memory()); 2];
+        bufs[0].copy_from_slice(&data[bs..(2 * bs)]);
+        bufs[1].copy_from_slice(&data[(2 * bs)..]);
+        block_on(dev.writev_blocks(5, &[&bufs[0], &bufs[1]])).unwrap();
+        block_on(dev.read_blocks(5, &mut buf[..(2 * bs)])).unwrap();
+        assert_eq!(buf[..(2 * bs)], data[bs..]);
+        let mut back = vec![IoBuf::zeroed(bs, PageMemory::Heap); 2];
+        block_on(dev.readv_blocks(4, &mut back)).unwrap();
+        assert!(back[0].iter().all(|&b| b == 0));
+        assert_eq!(back[1][..], data[bs..(2 * bs)]);
+
+        // Partial blocks and ranges past the end are rejected
+        assert_eq!(block_on(dev.read_blocks(0, &mut buf[..bs - 1])), Err(FsError::EINVAL));
+        let last = dev.num_blocks() - 1;
+        assert_eq!(block_on(dev.write_blocks(last, &data)), Err(FsError::EINVAL));
+        assert_eq!(block_on(dev.unmap_blocks(last, 2)), Err(FsError::EINVAL));
+        assert_eq!(block_on(dev.write_zeroes_blocks(last, 2)), Err(FsError::EINVAL));
+    }
+
+    #[test]
+    fn test_mem_device() {
+        let dev = MemDevice::new(512, 16);
+        assert_eq!(dev.num_blocks(), 16);
+        exercise(&dev);
+    }
+
+    #[test]
+    fn test_file_device() {
+        // Per process, so that concurrent test runs do not share it
+        let path = env::temp_dir().join(format!("rustfs_test_file_device.{}.img", process::id()));
+        {
+            let dev = FileDevice::create(&path, 4096, 8).unwrap();
+            exercise(&dev);
+        }
+
+        // The content is still there when the file is opened again
+        let dev = FileDevice::open(&path, 4096).unwrap();
+        assert_eq!(dev.num_blocks(), 8);
+        let mut buf = vec![0u8; 4096];
+        block_on(dev.read_blocks(2, &mut buf)).unwrap();
+        assert_eq!(buf[1], 1);
+        fs::remove_file(&path).unwrap();
+
+        // Errors from the host keep their errno
+        assert_eq!(FileDevice::open(&path, 4096).err(), Some(FsError::ENOENT));
+        assert_eq!(FileDevice::open(env::temp_dir(), 4096).err(), Some(FsError::EISDIR));
+    }
+}
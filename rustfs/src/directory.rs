@@ -8,17 +8,27 @@
     Fill in the purpose of this source file here.
  ************************************************************************/
 
-use file::File;
-use file::File::Directory;
+use std::sync::Arc;
+use parking_lot::RwLockWriteGuard;
+use crate::error::{FsError, FsResult};
+use crate::file::{DirectoryContent, File, RcDirContent};
+use crate::file::File::Directory;
+use crate::inode::Attr;
 
-pub trait DirectoryHandle<'r>: Sized {
+pub trait DirectoryHandle: Sized {
     fn is_dir(&self) -> bool;
-    fn insert(&mut self, name: &'r str, file: Self);
-    fn remove(&mut self, name: &'r str);
-    fn get(&self, name: &'r str) -> Option<Self>;
+    fn is_empty(&self) -> FsResult<bool>;
+    fn insert(&mut self, name: &[u8], file: Self) -> FsResult<()>;
+    fn remove_if<F>(&mut self, name: &[u8], check: F) -> FsResult<Self>
+        where F: FnOnce(&Attr, &Self) -> FsResult<()>;
+    fn get(&self, name: &[u8]) -> FsResult<Self>;
+    fn parent(&self) -> FsResult<Self>;
+    fn set_parent(&self, parent: &Self) -> FsResult<()>;
+    fn is_ancestor_of(&self, other: &Self) -> FsResult<bool>;
+    fn name_of(&self, child: &Self) -> FsResult<Vec<u8>>;
 }
 
-impl<'r> DirectoryHandle<'r> for File<'r> {
+impl DirectoryHandle for File {
     fn is_dir(&self) -> bool {
         match self {
             &Directory(_) => true,
@@ -26,24 +36,114 @@ impl<'r> DirectoryHandle<'r> for File<'r> {
         }
     }
 
-    fn insert(&mut self, name: &'r str, file: File<'r>) {
-        let rc = self.get_dir_rc();
-        let mut content = rc.borrow_mut();
-        content.entries.insert(name, file);
+    fn is_empty(&self) -> FsResult<bool> {
+        let rc = self.get_dir_rc()?;
+        let content = rc.read();
+        Ok(content.entries.is_empty())
     }
 
-    fn remove(&mut self, name: &'r str) {
-        let rc = self.get_dir_rc();
-        let mut content = rc.borrow_mut();
-        content.entries.remove(&name);
+    fn insert(&mut self, name: &[u8], file: File) -> FsResult<()> {
+        let rc = self.get_dir_rc()?;
+        let mut content = rc.write();
+        content.add(name.to_vec(), file)?;
+        content.attr.modified();
+        Ok(())
     }
 
-    fn get(&self, name: &'r str) -> Option<File<'r>> {
-        let rc = self.get_dir_rc();
-        let content = rc.borrow();
-        match content.entries.get(&name) {
-            None => None,
-            Some(ref file) => Some((*file).clone()) // It's RC
+    /// Removes `name` if `check`, given the attributes of this directory
+    /// and the file found under `name`, allows it. Both stay locked from
+    /// the check to the removal.
+    fn remove_if<F>(&mut self, name: &[u8], check: F) -> FsResult<File>
+        where F: FnOnce(&Attr, &File) -> FsResult<()>
+    {
+        let rc = self.get_dir_rc()?;
+        let mut content = rc.write();
+        {
+            let entry = content.entries.get(name).ok_or(FsError::ENOENT)?;
+            check(&content.attr, &entry.file)?;
         }
+        let file = content.take(name)?;
+        content.attr.modified();
+        Ok(file)
     }
-}
\ No newline at end of file
+
+    fn get(&self, name: &[u8]) -> FsResult<File> {
+        let rc = self.get_dir_rc()?;
+        let content = rc.read();
+        match content.entries.get(name) {
+            None => Err(FsError::ENOENT),
+            Some(ref entry) => Ok(entry.file.clone()) // It's an Arc
+        }
+    }
+
+    fn parent(&self) -> FsResult<File> {
+        let rc = self.get_dir_rc()?;
+        let content = rc.read();
+        let parent = content.parent.as_ref().and_then(|weak| weak.upgrade());
+        match parent {
+            None => Ok(self.clone()), // The root is its own parent
+            Some(rc) => Ok(Directory(rc))
+        }
+    }
+
+    /// Points ".." at `parent`, for directories that moved.
+    fn set_parent(&self, parent: &File) -> FsResult<()> {
+        let weak = Arc::downgrade(parent.get_dir_rc()?);
+        self.get_dir_rc()?.write().parent = Some(weak);
+        Ok(())
+    }
+
+    /// Whether `other` is this directory or lies somewhere below it.
+    fn is_ancestor_of(&self, other: &File) -> FsResult<bool> {
+        let mut dir = other.clone();
+        loop {
+            if dir.same_as(self) { return Ok(true); }
+            let parent = dir.parent()?;
+            if parent.same_as(&dir) { return Ok(false); }
+            dir = parent;
+        }
+    }
+
+    fn name_of(&self, child: &File) -> FsResult<Vec<u8>> {
+        let rc = self.get_dir_rc()?;
+        let content = rc.read();
+        content.entries.iter()
+            .find(|&(_, entry)| entry.file.same_as(child))
+            .map(|(name, _)| name.clone())
+            .ok_or(FsError::ENOENT)
+    }
+}
+/// Write locks on the two parent directories of a rename, which may be one
+/// and the same.
+pub struct DirPair<'a> {
+    old: RwLockWriteGuard<'a, Box<DirectoryContent>>,
+    new: Option<RwLockWriteGuard<'a, Box<DirectoryContent>>>
+}
+
+impl<'a> DirPair<'a> {
+    /// Locks `old` and `new`, the one that is an ancestor of the other
+    /// first. The caller holds FileSystem::rename_lock, so unrelated
+    /// directories can be locked in any order.
+    pub fn lock(old: &'a RcDirContent, new: &'a RcDirContent, new_first: bool) -> DirPair<'a> {
+        if Arc::ptr_eq(old, new) {
+            DirPair { old: old.write(), new: None }
+        } else if new_first {
+            let new = new.write();
+            DirPair { old: old.write(), new: Some(new) }
+        } else {
+            let old = old.write();
+            DirPair { old: old, new: Some(new.write()) }
+        }
+    }
+
+    pub fn old(&mut self) -> &mut DirectoryContent {
+        &mut self.old
+    }
+
+    pub fn new(&mut self) -> &mut DirectoryContent {
+        match self.new {
+            Some(ref mut new) => new,
+            None => &mut self.old
+        }
+    }
+}
@@ -0,0 +1,589 @@
+/*************************************************************************
+  > File Name:       disk.rs
+  > Author:          Zeyuan Hu
+  > Mail:            iamzeyuanhu@utexas.edu
+  > Created Time:    10/17/26
+  > Description:
+
+    Moves the file system tree between memory and a BlockDevice using the
+    format described in layout.rs:
+
+    - mkfs writes an empty file system (just the root directory).
+    - load rebuilds the directory tree and every inode from the device.
+      Directories and symbolic links are read whole, but not the data of
+      files: read_pages reads a page in the first time it is needed.
+    - store writes the whole tree back: the pages of every live inode that
+      changed since they were last written, in place (zeroing new blocks
+      of holes), directories, extent lists and spilled extended attributes
+      to new blocks, the inode table and the block bitmap to the copy that
+      is not live, then the superblock that makes them live. The blocks
+      of holes punched since the last sync are unmapped after that.
+
+    The bitmap on disk is not trusted at mount time: load rebuilds it from
+    the extents of the inodes it finds, which also gets back blocks leaked
+    by a crash between two syncs.
+ ************************************************************************/
+
+use crate::alloc::{BlockAllocator, Blocks, RcAllocator};
+use crate::device::{BlockDevice, IoBuf, IoFuture};
+use crate::directory::DirectoryHandle;
+use crate::error::{FsError, FsResult};
+use crate::extent::ExtentMap;
+use crate::file::{File, RcInode};
+use crate::file::File::{DataFile, Directory, Symlink};
+use crate::inode::{Attr, Inode, InodeNumbers};
+use crate::layout::*;
+use crate::path;
+use crate::xattr::{Xattrs, XATTR_LIST_MAX};
+use parking_lot::RwLock;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+/// Number of device blocks making up one file system block.
+fn dev_blocks_per_block(dev: &dyn BlockDevice) -> FsResult<u64> {
+    let bs = dev.block_size();
+    if bs == 0 || bs > BLOCK_SIZE || BLOCK_SIZE % bs != 0 {
+        return Err(FsError::EINVAL);
+    }
+    Ok((BLOCK_SIZE / bs) as u64)
+}
+
+/// Reads `buf.len() / BLOCK_SIZE` file system blocks starting at `block`.
+fn read_blocks<'a>(dev: &'a dyn BlockDevice, block: u64, buf: &'a mut [u8]) -> IoFuture<'a, ()> {
+    Box::pin(async move {
+        let per = dev_blocks_per_block(dev)?;
+        await!(dev.read_blocks(block * per, buf))
+    })
+}
+
+/// Writes `buf.len() / BLOCK_SIZE` file system blocks starting at `block`.
+fn write_blocks<'a>(dev: &'a dyn BlockDevice, block: u64, buf: &'a [u8]) -> IoFuture<'a, ()> {
+    Box::pin(async move {
+        let per = dev_blocks_per_block(dev)?;
+        await!(dev.write_blocks(block * per, buf))
+    })
+}
+
+/// Zeroes `count` file system blocks starting at `block`.
+fn write_zeroes<'a>(dev: &'a dyn BlockDevice, block: u64, count: u64) -> IoFuture<'a, ()> {
+    Box::pin(async move {
+        let per = dev_blocks_per_block(dev)?;
+        await!(dev.write_zeroes_blocks(block * per, count * per))
+    })
+}
+
+/// Unmaps `count` file system blocks starting at `block`.
+fn unmap<'a>(dev: &'a dyn BlockDevice, block: u64, count: u64) -> IoFuture<'a, ()> {
+    Box::pin(async move {
+        let per = dev_blocks_per_block(dev)?;
+        await!(dev.unmap_blocks(block * per, count * per))
+    })
+}
+
+fn new_uuid() -> [u8; 16] {
+    let mut uuid: [u8; 16] = rand::random();
+    // Random (version 4) UUID
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    uuid
+}
+
+/// Writes a new, empty file system on `dev`. One inode is reserved for every
+/// four blocks.
+pub fn mkfs<'a>(dev: &'a dyn BlockDevice) -> IoFuture<'a, ()> {
+    Box::pin(async move {
+        let num_blocks = dev.num_blocks() / dev_blocks_per_block(dev)?;
+        let inode_count = cmp::max(num_blocks / 4, 16);
+        let mut sb = Superblock::new(num_blocks, inode_count, new_uuid())?;
+
+        let alloc = BlockAllocator::new(&sb);
+        sb.free_blocks = alloc.free_blocks();
+
+        let mut table = vec![0u8; sb.inode_table_blocks as usize * BLOCK_SIZE];
+        let mut root = InodeRecord::new(KIND_DIR);
+        Attr::new(0o755).fill_record(&mut root);
+        let off = sb.root_ino as usize * INODE_SIZE;
+        root.encode(&mut table[off..(off + INODE_SIZE)]);
+
+        await!(write_blocks(dev, sb.bitmap_at(sb.slot()), alloc.bitmap()))?;
+        await!(write_blocks(dev, sb.inode_table_at(sb.slot()), &table))?;
+        await!(write_blocks(dev, 0, &sb.encode()))?;
+        await!(dev.flush())
+    })
+}
+
+/// What load hands back: the superblock, the root directory, the block
+/// allocator and the inode numbers that are still free.
+pub struct Mounted {
+    pub sb: Superblock,
+    pub root: File,
+    pub alloc: RcAllocator,
+    pub inos: InodeNumbers
+}
+
+/// Decodes the record of `ino` from the inode table, following its extent
+/// overflow chain if needed. Every block the inode uses is claimed from
+/// `alloc`, so a block used twice is caught here. Returns the record and
+/// its overflow blocks.
+fn read_record<'a>(dev: &'a dyn BlockDevice, sb: &'a Superblock, table: &'a [u8],
+                   alloc: &'a RcAllocator, ino: u64) -> IoFuture<'a, (InodeRecord, Vec<u64>)> {
+    Box::pin(async move {
+        if ino == 0 || ino > sb.inode_count { return Err(FsError::EIO); }
+        let off = ino as usize * INODE_SIZE;
+        let (mut record, overflow) = InodeRecord::decode(&table[off..(off + INODE_SIZE)]);
+        if record.kind == KIND_FREE { return Err(FsError::EIO); }
+
+        let mut next = record.extent_block;
+        let mut chain = Vec::new();
+        let mut block = vec![0u8; BLOCK_SIZE];
+        while record.extents.len() < overflow {
+            alloc.lock().claim(next, 1)?;
+            chain.push(next);
+            await!(read_blocks(dev, next, &mut block))?;
+            next = decode_overflow(&block, &mut record.extents)?;
+        }
+
+        let mut alloc = alloc.lock();
+        for e in record.extents.iter() {
+            alloc.claim(e.physical, e.len)?;
+        }
+        Ok((record, chain))
+    })
+}
+
+// Largest number of blocks moved by a single device command
+const MAX_IO_BLOCKS: u64 = 256;
+
+/// Reads every extent of `record`, calling `f` with the first page number
+/// and the content of each piece.
+fn read_extents<'a, F>(dev: &'a dyn BlockDevice, record: &'a InodeRecord,
+                       mut f: F) -> IoFuture<'a, ()>
+    where F: FnMut(u64, &[u8]) -> FsResult<()> + 'a
+{
+    Box::pin(async move {
+        for e in record.extents.iter() {
+            let mut done = 0;
+            while done < e.len {
+                let len = cmp::min(e.len - done, MAX_IO_BLOCKS);
+                let mut buf = vec![0u8; len as usize * BLOCK_SIZE];
+                await!(read_blocks(dev, e.physical + done, &mut buf))?;
+                f(e.logical + done, &buf)?;
+                done += len;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// The extended attributes of `record`, read from its spill blocks if they
+/// are not in the record. The spill blocks are claimed from `alloc`.
+fn read_xattrs<'a>(dev: &'a dyn BlockDevice, record: &'a InodeRecord,
+                   alloc: &'a RcAllocator) -> IoFuture<'a, Xattrs> {
+    Box::pin(async move {
+        let mut data = record.xattrs.clone();
+        let mut next = record.xattr_block;
+        let mut chain = Vec::new();
+        let mut block = vec![0u8; BLOCK_SIZE];
+        while next != 0 {
+            // Long enough for the largest list: the chain loops
+            if chain.len() * XATTR_BYTES_PER_BLOCK > XATTR_LIST_MAX { return Err(FsError::EIO); }
+            alloc.lock().claim(next, 1)?;
+            chain.push(next);
+            await!(read_blocks(dev, next, &mut block))?;
+            next = decode_spill(&block, &mut data)?;
+        }
+        let spill = Blocks::from_disk(alloc.clone(), ExtentMap::new(), chain);
+        Ok(Xattrs::from_disk(decode_xattrs(&data)?, spill))
+    })
+}
+
+/// Rebuilds the whole tree from `dev`.
+pub fn load<'a>(dev: &'a dyn BlockDevice) -> IoFuture<'a, Mounted> {
+    Box::pin(async move {
+        let per = dev_blocks_per_block(dev)?;
+        let mut block = vec![0u8; BLOCK_SIZE];
+        await!(read_blocks(dev, 0, &mut block))?;
+        let sb = Superblock::decode(&block)?;
+        if sb.num_blocks > dev.num_blocks() / per { return Err(FsError::EINVAL); }
+
+        let mut table = vec![0u8; sb.inode_table_blocks as usize * BLOCK_SIZE];
+        await!(read_blocks(dev, sb.inode_table_at(sb.slot()), &mut table))?;
+
+        let alloc = BlockAllocator::new(&sb).into_rc();
+        let root = File::new_dir(sb.root_ino, None, Some(alloc.clone()))?;
+        let mut used = HashSet::new();
+        // Files seen so far with the number of names found for each
+        let mut files: HashMap<u64, (File, u32)> = HashMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(root.clone());
+
+        // Walk the tree breadth first, one directory at a time
+        while let Some(mut dir) = queue.pop_front() {
+            if !used.insert(dir.ino()) { return Err(FsError::EIO); }
+            let (record, chain) = await!(read_record(dev, &sb, &table, &alloc, dir.ino()))?;
+            if record.kind != KIND_DIR { return Err(FsError::EIO); }
+            let attr = Attr::from_record(&record);
+
+            let mut data = vec![0u8; record.size as usize];
+            await!(read_extents(dev, &record, |logical, buf| {
+                let start = logical as usize * BLOCK_SIZE;
+                if start >= data.len() { return Ok(()); }
+                let len = cmp::min(buf.len(), data.len() - start);
+                data[start..(start + len)].copy_from_slice(&buf[..len]);
+                Ok(())
+            }))?;
+            let xattrs = await!(read_xattrs(dev, &record, &alloc))?;
+            let extents = ExtentMap::from_extents(record.extents)?;
+            {
+                let mut content = dir.get_dir_rc()?.write();
+                content.blocks = Blocks::from_disk(alloc.clone(), extents, chain);
+                content.xattrs = xattrs;
+            }
+
+            for entry in decode_dir_entries(&data)? {
+                if path::check_name(&entry.name).is_err() { return Err(FsError::EIO); }
+                let file = match entry.kind {
+                    KIND_DIR => {
+                        let child = File::new_dir(entry.ino, Some(dir.clone()), Some(alloc.clone()))?;
+                        queue.push_back(child.clone());
+                        child
+                    }
+                    KIND_FILE | KIND_SYMLINK if files.contains_key(&entry.ino) => {
+                        // Another name for a file we already have
+                        let seen = files.get_mut(&entry.ino).unwrap();
+                        if seen.0.is_symlink() != (entry.kind == KIND_SYMLINK) {
+                            return Err(FsError::EIO);
+                        }
+                        seen.1 += 1;
+                        seen.0.clone()
+                    }
+                    KIND_FILE | KIND_SYMLINK => {
+                        if !used.insert(entry.ino) { return Err(FsError::EIO); }
+                        let (record, chain) = await!(read_record(dev, &sb, &table, &alloc, entry.ino))?;
+                        if record.kind != entry.kind { return Err(FsError::EIO); }
+
+                        let extents = ExtentMap::from_extents(record.extents.clone())?;
+                        let blocks = Blocks::from_disk(alloc.clone(), extents, chain);
+                        let mut inode = Inode::from_record(entry.ino, &record, blocks, dev.page_memory());
+                        *inode.xattrs_mut() = await!(read_xattrs(dev, &record, &alloc))?;
+                        // Link targets are needed to walk paths; file data
+                        // waits until it is used
+                        if record.kind == KIND_SYMLINK {
+                            await!(read_extents(dev, &record, |logical, buf| {
+                                for (i, page) in buf.chunks(BLOCK_SIZE).enumerate() {
+                                    inode.load_page(logical + i as u64, page);
+                                }
+                                Ok(())
+                            }))?;
+                        }
+                        let rc = Arc::new(RwLock::new(Box::new(inode)));
+                        let file = if record.kind == KIND_SYMLINK { Symlink(rc) } else { DataFile(rc) };
+                        files.insert(entry.ino, (file.clone(), 1));
+                        file
+                    }
+                    _ => return Err(FsError::EIO)
+                };
+                dir.insert(&entry.name, file)?;
+            }
+            // After the inserts, which count as modifications
+            dir.get_dir_rc()?.write().attr = attr;
+        }
+
+        // The link counts must match the names found
+        for (file, names) in files.values() {
+            if file.get_inode_rc()?.read().nlink() != *names { return Err(FsError::EIO); }
+        }
+
+        let mut inos = InodeNumbers::new(ROOT_INO + 1, sb.inode_count);
+        inos.rebuild(ROOT_INO + 1, &used);
+        Ok(Mounted { sb: sb, root: root, alloc: alloc, inos: inos })
+    })
+}
+
+/// Reads in the pages listed by `missing` (see Inode::missing), as few
+/// device commands as the blocks allow, straight into new pages. The inode
+/// is only locked to fill the pages in, not while waiting on the device.
+pub fn read_pages<'a>(dev: &'a dyn BlockDevice, inode_rc: &'a RcInode,
+                      missing: &'a [(u64, u64)]) -> IoFuture<'a, ()> {
+    Box::pin(async move {
+        let mut rest = missing;
+        while !rest.is_empty() {
+            // Pages in a row stored in blocks in a row
+            let (first, block) = rest[0];
+            let len = rest.iter().enumerate().take(MAX_IO_BLOCKS as usize)
+                .take_while(|&(i, &(num, b))| num == first + i as u64 && b == block + i as u64)
+                .count();
+            let per = dev_blocks_per_block(dev)?;
+            let mut pages: Vec<IoBuf> = (0..len).map(|_| IoBuf::zeroed(BLOCK_SIZE, dev.page_memory())).collect();
+            await!(dev.readv_blocks(block * per, &mut pages))?;
+            {
+                let mut inode = inode_rc.write();
+                for (&(num, b), page) in rest[..len].iter().zip(pages) {
+                    inode.fill_page(num, b, Arc::new(page));
+                }
+            }
+            rest = &rest[len..];
+        }
+        Ok(())
+    })
+}
+
+/// Consecutive blocks that go to the device in one command: pages with
+/// data, or holes, which are zeroed without sending a buffer.
+struct Run {
+    first: u64,
+    len: u64,
+    data: Vec<u8> // empty for a run of holes
+}
+
+impl Run {
+    fn new() -> Run {
+        Run { first: 0, len: 0, data: Vec::new() }
+    }
+
+    /// Adds the page mapped to `block` (None for a hole), writing out what
+    /// the run holds first if the page cannot join it.
+    fn push<'a>(&'a mut self, dev: &'a dyn BlockDevice, block: u64,
+                page: Option<&'a [u8]>) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            let joins = block == self.first + self.len && page.is_none() == self.data.is_empty();
+            if self.len > 0 && (!joins || self.len == MAX_IO_BLOCKS) {
+                await!(self.write(dev))?;
+            }
+            if self.len == 0 { self.first = block; }
+            self.len += 1;
+            if let Some(data) = page {
+                let start = self.data.len();
+                self.data.resize(start + BLOCK_SIZE, 0);
+                self.data[start..(start + data.len())].copy_from_slice(data);
+            }
+            Ok(())
+        })
+    }
+
+    fn write<'a>(&'a mut self, dev: &'a dyn BlockDevice) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            if self.len == 0 { return Ok(()); }
+            if self.data.is_empty() {
+                await!(write_zeroes(dev, self.first, self.len))?;
+            } else {
+                await!(write_blocks(dev, self.first, &self.data))?;
+            }
+            self.len = 0;
+            self.data.clear();
+            Ok(())
+        })
+    }
+}
+
+/// Writes the pages of `blocks` to the device, one command per run of
+/// blocks. `page` gives the content of a page; None is a hole, which is
+/// zeroed.
+fn write_extents<'a, F>(dev: &'a dyn BlockDevice, blocks: &'a Blocks, page: F) -> IoFuture<'a, ()>
+    where F: Fn(u64) -> Option<&'a [u8]> + 'a
+{
+    Box::pin(async move {
+        let mut run = Run::new();
+        for e in blocks.extents().iter() {
+            for i in 0..e.len {
+                await!(run.push(dev, e.physical + i, page(e.logical + i)))?;
+            }
+        }
+        await!(run.write(dev))
+    })
+}
+
+/// Fills in the extents of `record` from `blocks` and writes the ones that
+/// do not fit in the inode to its chain of overflow blocks.
+fn store_extents<'a>(dev: &'a dyn BlockDevice, blocks: &'a mut Blocks,
+                     record: &'a mut InodeRecord) -> IoFuture<'a, ()> {
+    Box::pin(async move {
+        record.extents = blocks.extents().to_vec();
+        record.extent_block = 0;
+        let count = if record.has_overflow() {
+            (record.extents.len() + EXTENTS_PER_BLOCK - 1) / EXTENTS_PER_BLOCK
+        } else {
+            0
+        };
+        blocks.replace_overflow(count)?;
+
+        let chain = blocks.overflow();
+        for (i, chunk) in record.extents.chunks(EXTENTS_PER_BLOCK).enumerate().take(count) {
+            let next = if i + 1 == count { 0 } else { chain[i + 1] };
+            await!(write_blocks(dev, chain[i], &encode_overflow(chunk, next)))?;
+        }
+        record.extent_block = chain.first().cloned().unwrap_or(0);
+        Ok(())
+    })
+}
+
+/// Fills in the extended attributes of `record` from `xattrs` and writes
+/// them to a chain of new spill blocks if they do not fit in the record.
+fn store_xattrs<'a>(dev: &'a dyn BlockDevice, xattrs: &'a mut Xattrs,
+                    record: &'a mut InodeRecord) -> IoFuture<'a, ()> {
+    Box::pin(async move {
+        record.xattrs = xattrs.encode();
+        record.xattr_block = 0;
+        let count = if record.has_xattr_spill() {
+            (record.xattrs.len() + XATTR_BYTES_PER_BLOCK - 1) / XATTR_BYTES_PER_BLOCK
+        } else {
+            0
+        };
+        xattrs.spill_mut().replace_overflow(count)?;
+
+        let chain = xattrs.spill().overflow();
+        for (i, chunk) in record.xattrs.chunks(XATTR_BYTES_PER_BLOCK).enumerate().take(count) {
+            let next = if i + 1 == count { 0 } else { chain[i + 1] };
+            await!(write_blocks(dev, chain[i], &encode_spill(chunk, next)))?;
+        }
+        record.xattr_block = chain.first().cloned().unwrap_or(0);
+        Ok(())
+    })
+}
+
+/// Writes the dirty pages of `inode` in place, to the blocks they are
+/// mapped to, one command per run of blocks. The pages go to the device as
+/// they are (see BlockDevice::writev_blocks). Pages without a block are
+/// skipped; dirty holes are zeroed.
+pub fn write_dirty<'a>(dev: &'a dyn BlockDevice, inode: &'a Inode) -> IoFuture<'a, ()> {
+    Box::pin(async move {
+        let per = dev_blocks_per_block(dev)?;
+        let mut holes = Run::new();
+        let mut first = 0;
+        let mut pages: Vec<&IoBuf> = Vec::new(); // a run of pages with data from `first`
+        for &num in inode.dirty_pages().iter() {
+            let block = match inode.blocks().lookup(num) {
+                Some(block) => block,
+                None => continue
+            };
+            let page = match inode.get_page(num) {
+                Some(page) => page,
+                None => {
+                    await!(holes.push(dev, block, None))?;
+                    continue;
+                }
+            };
+            let joins = block == first + pages.len() as u64 && (pages.len() as u64) < MAX_IO_BLOCKS;
+            if !pages.is_empty() && !joins {
+                await!(dev.writev_blocks(first * per, &pages))?;
+                pages.clear();
+            }
+            if pages.is_empty() { first = block; }
+            pages.push(&**page);
+        }
+        if !pages.is_empty() {
+            await!(dev.writev_blocks(first * per, &pages))?;
+        }
+        await!(holes.write(dev))
+    })
+}
+
+/// Writes the whole tree under `root` to `dev` and returns the inode numbers
+/// in use. Files in `open` are unlinked but still open: they are not written
+/// but their numbers (and blocks) stay in use.
+pub fn store<'a>(dev: &'a dyn BlockDevice, sb: &'a mut Superblock, alloc: &'a RcAllocator,
+                 root: &'a File, open: &'a [File]) -> IoFuture<'a, HashSet<u64>> {
+    Box::pin(async move {
+        let mut table = vec![0u8; sb.inode_table_blocks as usize * BLOCK_SIZE];
+        let mut used = HashSet::new();
+        let mut stack = vec![root.clone()];
+        let mut written = Vec::new(); // inodes and the layout they were written with
+        let mut punched = Vec::new(); // blocks of holes punched in them
+
+        while let Some(file) = stack.pop() {
+            let ino = file.ino();
+            if ino == 0 || ino > sb.inode_count { return Err(FsError::ENOSPC); }
+            if !used.insert(ino) { continue; }
+
+            let record = match file {
+                Directory(ref rc) => {
+                    let mut content = rc.write();
+                    let mut entries = Vec::new();
+                    for (name, entry) in content.entries.iter() {
+                        let child = &entry.file;
+                        let kind = match child {
+                            &Directory(_) => KIND_DIR,
+                            &DataFile(_) => KIND_FILE,
+                            &Symlink(_) => KIND_SYMLINK
+                        };
+                        entries.push(DirEntryRecord { ino: child.ino(), kind: kind, name: name.clone() });
+                        stack.push(child.clone());
+                    }
+
+                    // Always to new blocks: the live tree on disk still
+                    // points at the old ones
+                    let data = encode_dir_entries(&entries);
+                    let pages = ((data.len() + BLOCK_SIZE - 1) / BLOCK_SIZE) as u64;
+                    content.blocks.release(0, u64::max_value());
+                    content.blocks.reserve(0, pages)?;
+                    await!(write_extents(dev, &content.blocks, |num| {
+                        let start = num as usize * BLOCK_SIZE;
+                        Some(&data[start..cmp::min(start + BLOCK_SIZE, data.len())])
+                    }))?;
+
+                    let mut record = InodeRecord::new(KIND_DIR);
+                    record.size = data.len() as u64;
+                    content.attr.fill_record(&mut record);
+                    await!(store_extents(dev, &mut content.blocks, &mut record))?;
+                    await!(store_xattrs(dev, &mut content.xattrs, &mut record))?;
+                    record
+                }
+                DataFile(ref rc) | Symlink(ref rc) => {
+                    // The other pages are on the device already
+                    let mut inode = rc.write();
+                    await!(write_dirty(dev, &inode))?;
+                    inode.clear_dirty();
+                    written.push((rc.clone(), inode.layout()));
+                    punched.extend(inode.take_punched());
+
+                    let mut record = inode.to_record();
+                    if file.is_symlink() { record.kind = KIND_SYMLINK; }
+                    await!(store_extents(dev, inode.blocks_mut(), &mut record))?;
+                    await!(store_xattrs(dev, inode.xattrs_mut(), &mut record))?;
+                    record
+                }
+            };
+
+            let off = ino as usize * INODE_SIZE;
+            record.encode(&mut table[off..(off + INODE_SIZE)]);
+        }
+
+        for file in open {
+            used.insert(file.ino());
+        }
+
+        // Everything so far went to blocks the live tree does not use and to
+        // the copy of the table and the bitmap it does not point at. Writing
+        // the superblock (which fits in one sector) switches to the new tree
+        // in one step.
+        let mut next = sb.clone();
+        next.generation += 1;
+        next.features |= FEATURE_XATTR;
+        next.free_blocks = alloc.lock().free_blocks();
+        let bitmap = alloc.lock().committed_bitmap();
+        await!(write_blocks(dev, next.inode_table_at(next.slot()), &table))?;
+        await!(write_blocks(dev, next.bitmap_at(next.slot()), &bitmap))?;
+        await!(dev.flush())?;
+        await!(write_blocks(dev, 0, &next.encode()))?;
+        await!(dev.flush())?;
+
+        // Nothing on disk points at the blocks freed since the last sync
+        // any more
+        alloc.lock().commit();
+        *sb = next;
+        for (rc, layout) in written {
+            rc.write().set_synced(layout);
+        }
+
+        // Nor at the blocks of punched holes, so the device can drop them.
+        // They may have been given out again already, but only a sync
+        // writes to the device, and the next one waits for this one.
+        // Unmapping is only a hint: the holes read back as zeros either way,
+        // so a device that cannot do it is not an error.
+        for (first, len) in punched {
+            let _ = await!(unmap(dev, first, len));
+        }
+        Ok(used)
+    })
+}
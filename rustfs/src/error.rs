@@ -0,0 +1,164 @@
+/*************************************************************************
+  > File Name:       error.rs
+  > Author:          Zeyuan Hu
+  > Mail:            iamzeyuanhu@utexas.edu
+  > Created Time:    10/17/26
+  > Description:
+
+    The error type returned by every file system operation. The variants
+    follow the POSIX errno names so that callers porting POSIX code can map
+    them one to one (see FsError::errno).
+ ************************************************************************/
+
+extern crate libc;
+
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// No such file or directory
+    ENOENT,
+    /// Bad file descriptor
+    EBADF,
+    /// Is a directory
+    EISDIR,
+    /// File too large
+    EFBIG,
+    /// No space left on device
+    ENOSPC,
+    /// Too many open files
+    EMFILE,
+    /// File exists
+    EEXIST,
+    /// Not a directory
+    ENOTDIR,
+    /// Directory not empty
+    ENOTEMPTY,
+    /// Invalid argument
+    EINVAL,
+    /// Device or resource busy
+    EBUSY,
+    /// Input/output error
+    EIO,
+    /// No such device or address (no data/hole past the given offset)
+    ENXIO,
+    /// Operation not permitted
+    EPERM,
+    /// Too many levels of symbolic links
+    ELOOP,
+    /// File name too long
+    ENAMETOOLONG,
+    /// Permission denied
+    EACCES,
+    /// No data available (no such extended attribute)
+    ENODATA,
+    /// Result too large (extended attribute name too long)
+    ERANGE,
+    /// Argument list too long (extended attribute value too large)
+    E2BIG,
+    /// Operation not supported (unknown extended attribute namespace)
+    EOPNOTSUPP,
+}
+
+pub type FsResult<T> = Result<T, FsError>;
+
+impl FsError {
+    /// The raw errno value for this error.
+    pub fn errno(&self) -> i32 {
+        match *self {
+            FsError::ENOENT => libc::ENOENT,
+            FsError::EBADF => libc::EBADF,
+            FsError::EISDIR => libc::EISDIR,
+            FsError::EFBIG => libc::EFBIG,
+            FsError::ENOSPC => libc::ENOSPC,
+            FsError::EMFILE => libc::EMFILE,
+            FsError::EEXIST => libc::EEXIST,
+            FsError::ENOTDIR => libc::ENOTDIR,
+            FsError::ENOTEMPTY => libc::ENOTEMPTY,
+            FsError::EINVAL => libc::EINVAL,
+            FsError::EBUSY => libc::EBUSY,
+            FsError::EIO => libc::EIO,
+            FsError::ENXIO => libc::ENXIO,
+            FsError::EPERM => libc::EPERM,
+            FsError::ELOOP => libc::ELOOP,
+            FsError::ENAMETOOLONG => libc::ENAMETOOLONG,
+            FsError::EACCES => libc::EACCES,
+            FsError::ENODATA => libc::ENODATA,
+            FsError::ERANGE => libc::ERANGE,
+            FsError::E2BIG => libc::E2BIG,
+            FsError::EOPNOTSUPP => libc::EOPNOTSUPP,
+        }
+    }
+}
+
+impl From<::std::io::Error> for FsError {
+    /// Keeps the errno of errors that have one of ours, EIO otherwise.
+    fn from(err: ::std::io::Error) -> FsError {
+        match err.raw_os_error().unwrap_or(libc::EIO) {
+            libc::ENOENT => FsError::ENOENT,
+            libc::EBADF => FsError::EBADF,
+            libc::EISDIR => FsError::EISDIR,
+            libc::EFBIG => FsError::EFBIG,
+            libc::ENOSPC => FsError::ENOSPC,
+            libc::EMFILE => FsError::EMFILE,
+            libc::EEXIST => FsError::EEXIST,
+            libc::ENOTDIR => FsError::ENOTDIR,
+            libc::ENOTEMPTY => FsError::ENOTEMPTY,
+            libc::EINVAL => FsError::EINVAL,
+            libc::EBUSY => FsError::EBUSY,
+            libc::EIO => FsError::EIO,
+            libc::ENXIO => FsError::ENXIO,
+            libc::EPERM => FsError::EPERM,
+            libc::ELOOP => FsError::ELOOP,
+            libc::ENAMETOOLONG => FsError::ENAMETOOLONG,
+            libc::EACCES => FsError::EACCES,
+            libc::ENODATA => FsError::ENODATA,
+            libc::ERANGE => FsError::ERANGE,
+            libc::E2BIG => FsError::E2BIG,
+            libc::EOPNOTSUPP => FsError::EOPNOTSUPP,
+            _ => FsError::EIO
+        }
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match *self {
+            FsError::ENOENT => "No such file or directory",
+            FsError::EBADF => "Bad file descriptor",
+            FsError::EISDIR => "Is a directory",
+            FsError::EFBIG => "File too large",
+            FsError::ENOSPC => "No space left on device",
+            FsError::EMFILE => "Too many open files",
+            FsError::EEXIST => "File exists",
+            FsError::ENOTDIR => "Not a directory",
+            FsError::ENOTEMPTY => "Directory not empty",
+            FsError::EINVAL => "Invalid argument",
+            FsError::EBUSY => "Device or resource busy",
+            FsError::EIO => "Input/output error",
+            FsError::ENXIO => "No such device or address",
+            FsError::EPERM => "Operation not permitted",
+            FsError::ELOOP => "Too many levels of symbolic links",
+            FsError::ENAMETOOLONG => "File name too long",
+            FsError::EACCES => "Permission denied",
+            FsError::ENODATA => "No data available",
+            FsError::ERANGE => "Numerical result out of range",
+            FsError::E2BIG => "Argument list too long",
+            FsError::EOPNOTSUPP => "Operation not supported",
+        };
+        write!(f, "{} (errno {})", msg, self.errno())
+    }
+}
+
+impl Error for FsError {
+    fn description(&self) -> &str {
+        "file system error"
+    }
+}
+
+impl From<FsError> for ::std::io::Error {
+    fn from(err: FsError) -> ::std::io::Error {
+        ::std::io::Error::from_raw_os_error(err.errno())
+    }
+}
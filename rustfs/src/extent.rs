@@ -0,0 +1,191 @@
+/*************************************************************************
+  > File Name:       extent.rs
+  > Author:          Zeyuan Hu
+  > Mail:            iamzeyuanhu@utexas.edu
+  > Created Time:    10/17/26
+  > Description:
+
+    The map from the pages of a file to the device blocks holding them.
+    Each entry is an extent (a run of pages stored in a run of blocks),
+    kept in a B-tree keyed by the first page, so a file written
+    sequentially costs one entry no matter how large it is and lookups
+    stay logarithmic in the number of runs.
+ ************************************************************************/
+
+use crate::error::{FsError, FsResult};
+use crate::layout::Extent;
+use std::cmp;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Default, Clone)]
+pub struct ExtentMap {
+    map: BTreeMap<u64, Extent>
+}
+
+impl ExtentMap {
+    pub fn new() -> ExtentMap {
+        ExtentMap { map: BTreeMap::new() }
+    }
+
+    /// Builds the map from extents read back from disk. Fails with EIO if
+    /// they are empty or overlap.
+    pub fn from_extents(extents: Vec<Extent>) -> FsResult<ExtentMap> {
+        let mut map = ExtentMap::new();
+        for e in extents {
+            let end = e.logical.checked_add(e.len).ok_or(FsError::EIO)?;
+            if e.len == 0 || map.map.range(e.logical..end).next().is_some() || map.lookup(e.logical).is_some() {
+                return Err(FsError::EIO);
+            }
+            map.insert(e);
+        }
+        Ok(map)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Extents in increasing page order.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = &'a Extent> + 'a {
+        self.map.values()
+    }
+
+    pub fn to_vec(&self) -> Vec<Extent> {
+        self.iter().cloned().collect()
+    }
+
+    pub fn last(&self) -> Option<&Extent> {
+        self.map.values().next_back()
+    }
+
+    // The extent holding `page`, if any
+    fn find(&self, page: u64) -> Option<&Extent> {
+        match self.map.range(..=page).next_back() {
+            Some((_, e)) if page < e.logical + e.len => Some(e),
+            _ => None
+        }
+    }
+
+    /// The block `page` is stored in, if any.
+    pub fn lookup(&self, page: u64) -> Option<u64> {
+        self.find(page).map(|e| e.physical + page - e.logical)
+    }
+
+    /// The first mapped page at or after `page`, if any.
+    pub fn next_mapped(&self, page: u64) -> Option<u64> {
+        if self.find(page).is_some() { return Some(page); }
+        self.map.range(page..).next().map(|(&logical, _)| logical)
+    }
+
+    /// The end of the extent holding `page`, if it is mapped.
+    pub fn extent_end(&self, page: u64) -> Option<u64> {
+        self.find(page).map(|e| e.logical + e.len)
+    }
+
+    /// Adds an extent for pages that are not mapped yet, merging it with the
+    /// extents around it when both the pages and the blocks line up.
+    pub fn insert(&mut self, mut new: Extent) {
+        let before = self.map.range(..new.logical).next_back().map(|(_, e)| *e);
+        if let Some(e) = before {
+            if e.logical + e.len == new.logical && e.physical + e.len == new.physical {
+                self.map.remove(&e.logical);
+                new = Extent { logical: e.logical, physical: e.physical, len: e.len + new.len };
+            }
+        }
+
+        let after = self.map.get(&(new.logical + new.len)).cloned();
+        if let Some(e) = after {
+            if new.physical + new.len == e.physical {
+                self.map.remove(&e.logical);
+                new.len += e.len;
+            }
+        }
+        self.map.insert(new.logical, new);
+    }
+
+    /// Unmaps the pages in [from, to) and returns the runs of blocks they
+    /// were stored in.
+    pub fn remove(&mut self, from: u64, to: u64) -> Vec<(u64, u64)> {
+        let mut freed = Vec::new();
+        if from >= to { return freed; }
+
+        // The extent starting before `from` may reach into the range
+        let first = match self.find(from) {
+            Some(e) => e.logical,
+            None => from
+        };
+        let hit: Vec<Extent> = self.map.range(first..to).map(|(_, e)| *e).collect();
+
+        for e in hit {
+            self.map.remove(&e.logical);
+            let (start, end) = (cmp::max(e.logical, from), cmp::min(e.logical + e.len, to));
+            freed.push((e.physical + start - e.logical, end - start));
+            if e.logical < start {
+                self.map.insert(e.logical, Extent { logical: e.logical, physical: e.physical,
+                                                    len: start - e.logical });
+            }
+            if end < e.logical + e.len {
+                self.map.insert(end, Extent { logical: end, physical: e.physical + end - e.logical,
+                                              len: e.logical + e.len - end });
+            }
+        }
+        freed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ExtentMap;
+    use crate::error::FsError;
+    use crate::layout::Extent;
+
+    fn extent(logical: u64, physical: u64, len: u64) -> Extent {
+        Extent { logical: logical, physical: physical, len: len }
+    }
+
+    #[test]
+    fn test_extent_map() {
+        let mut map = ExtentMap::new();
+        map.insert(extent(0, 100, 4));
+        map.insert(extent(8, 108, 2));
+        // Fills the gap and joins both neighbours
+        map.insert(extent(4, 104, 4));
+        assert_eq!(map.to_vec(), vec![extent(0, 100, 10)]);
+        assert_eq!(map.lookup(9), Some(109));
+        assert_eq!(map.lookup(10), None);
+        assert_eq!(map.next_mapped(3), Some(3));
+        assert_eq!(map.next_mapped(10), None);
+        assert_eq!(map.extent_end(3), Some(10));
+
+        // Lines up with the pages but not the blocks
+        map.insert(extent(10, 500, 1));
+        assert_eq!(map.len(), 2);
+
+        // Huge offsets cost one entry
+        map.insert(extent(1 << 40, 7, 1));
+        assert_eq!(map.lookup(1 << 40), Some(7));
+        assert_eq!(map.next_mapped(11), Some(1 << 40));
+
+        assert_eq!(map.remove(2, 4), vec![(102, 2)]);
+        assert_eq!(map.to_vec(), vec![extent(0, 100, 2), extent(4, 104, 6),
+                                      extent(10, 500, 1), extent(1 << 40, 7, 1)]);
+        assert_eq!(map.remove(5, u64::max_value()), vec![(105, 5), (500, 1), (7, 1)]);
+        assert_eq!(map.to_vec(), vec![extent(0, 100, 2), extent(4, 104, 1)]);
+    }
+
+    #[test]
+    fn test_extent_map_from_disk() {
+        let map = ExtentMap::from_extents(vec![extent(0, 10, 2), extent(2, 12, 3)]).unwrap();
+        assert_eq!(map.to_vec(), vec![extent(0, 10, 5)]);
+
+        let overlap = ExtentMap::from_extents(vec![extent(0, 10, 4), extent(2, 50, 1)]);
+        assert_eq!(overlap.err(), Some(FsError::EIO));
+        let overlap = ExtentMap::from_extents(vec![extent(2, 10, 4), extent(0, 50, 3)]);
+        assert_eq!(overlap.err(), Some(FsError::EIO));
+        assert_eq!(ExtentMap::from_extents(vec![extent(0, 10, 0)]).err(), Some(FsError::EIO));
+    }
+}
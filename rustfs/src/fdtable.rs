@@ -0,0 +1,164 @@
+/*************************************************************************
+  > File Name:       fdtable.rs
+  > Author:          Zeyuan Hu
+  > Mail:            iamzeyuanhu@utexas.edu
+  > Created Time:    10/17/26
+  > Description:
+
+    The file descriptor table of a Proc. A descriptor is an index into the
+    table; each slot points at an open file description (a FileHandle, which
+    holds the offset and the open flags). dup and friends make several
+    descriptors point at the same description, so a read through one moves
+    the offset seen by the others, as in POSIX. New descriptors are always
+    the lowest free ones.
+ ************************************************************************/
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use crate::error::{FsError, FsResult};
+use crate::file::FileHandle;
+use crate::FileDescriptor;
+
+/// How many descriptors a Proc may have open unless told otherwise.
+pub const DEFAULT_FD_LIMIT: usize = 1024;
+
+pub struct FdTable {
+    slots: Vec<Option<Arc<FileHandle>>>,
+    free: BTreeSet<usize>, // empty slots below slots.len()
+    limit: usize
+}
+
+impl FdTable {
+    pub fn new(limit: usize) -> FdTable {
+        FdTable {
+            slots: Vec::new(),
+            free: BTreeSet::new(),
+            limit: limit
+        }
+    }
+
+    /// The number of descriptors that may be open at once. Descriptors at
+    /// or above it can no longer be handed out.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Changes the limit. Descriptors already open at or above the new
+    /// limit stay open.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    /// Whether insert would find a free descriptor.
+    pub fn has_free(&self) -> bool {
+        self.lowest_free(0).is_some()
+    }
+
+    pub fn get(&self, fd: FileDescriptor) -> FsResult<&Arc<FileHandle>> {
+        if fd < 0 { return Err(FsError::EBADF); }
+        match self.slots.get(fd as usize) {
+            Some(&Some(ref handle)) => Ok(handle),
+            _ => Err(FsError::EBADF)
+        }
+    }
+
+    /// Puts `handle` at the lowest free descriptor. Fails with EMFILE if
+    /// every descriptor below the limit is in use.
+    pub fn insert(&mut self, handle: Arc<FileHandle>) -> FsResult<FileDescriptor> {
+        self.insert_from(0, handle)
+    }
+
+    /// Same as insert, but only descriptors from `min` up are considered,
+    /// as for fcntl(F_DUPFD).
+    pub fn insert_from(&mut self, min: usize, handle: Arc<FileHandle>) -> FsResult<FileDescriptor> {
+        let fd = self.lowest_free(min).ok_or(FsError::EMFILE)?;
+        self.install(fd, handle);
+        Ok(fd as FileDescriptor)
+    }
+
+    /// Puts `handle` at `fd`, which must be below the limit, and returns
+    /// what was there before.
+    pub fn replace(&mut self, fd: FileDescriptor, handle: Arc<FileHandle>) -> FsResult<Option<Arc<FileHandle>>> {
+        if fd < 0 || fd as usize >= self.limit { return Err(FsError::EBADF); }
+        Ok(self.install(fd as usize, handle))
+    }
+
+    /// Frees `fd` and returns the description it pointed at; the
+    /// description itself lives on as long as other descriptors share it.
+    pub fn remove(&mut self, fd: FileDescriptor) -> FsResult<Arc<FileHandle>> {
+        self.get(fd)?;
+        let fd = fd as usize;
+        let handle = self.slots[fd].take().expect("checked by get");
+        self.free.insert(fd);
+        // Keep the table (and the free set) no longer than the highest open
+        // descriptor
+        while let Some(&None) = self.slots.last() {
+            self.slots.pop();
+            self.free.remove(&self.slots.len());
+        }
+        Ok(handle)
+    }
+
+    fn lowest_free(&self, min: usize) -> Option<usize> {
+        let fd = match self.free.range(min..).next() {
+            Some(&fd) => fd,
+            None => ::std::cmp::max(min, self.slots.len())
+        };
+        if fd < self.limit { Some(fd) } else { None }
+    }
+
+    fn install(&mut self, fd: usize, handle: Arc<FileHandle>) -> Option<Arc<FileHandle>> {
+        while self.slots.len() <= fd {
+            self.free.insert(self.slots.len());
+            self.slots.push(None);
+        }
+        self.free.remove(&fd);
+        self.slots[fd].replace(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FdTable;
+    use std::sync::Arc;
+    use crate::error::FsError;
+    use crate::file::{File, FileHandle};
+    use crate::O_RDONLY;
+
+    fn handle() -> Arc<FileHandle> {
+        let dir = File::new_dir(1, None, None).unwrap();
+        Arc::new(FileHandle::new(dir, O_RDONLY))
+    }
+
+    #[test]
+    fn test_lowest_free() {
+        let mut table = FdTable::new(4);
+        for fd in 0..4 {
+            assert_eq!(table.insert(handle()), Ok(fd));
+        }
+        assert_eq!(table.insert(handle()).err(), Some(FsError::EMFILE));
+        assert!(!table.has_free());
+
+        table.remove(2).unwrap();
+        table.remove(1).unwrap();
+        assert_eq!(table.remove(1).err(), Some(FsError::EBADF));
+        assert_eq!(table.insert(handle()), Ok(1));
+        assert_eq!(table.insert_from(3, handle()).err(), Some(FsError::EMFILE));
+        assert_eq!(table.insert_from(2, handle()), Ok(2));
+
+        // Closing the top descriptors shrinks the table
+        table.remove(3).unwrap();
+        table.remove(2).unwrap();
+        assert_eq!(table.slots.len(), 2);
+        assert!(table.free.is_empty());
+        assert_eq!(table.insert_from(3, handle()), Ok(3));
+        assert_eq!(table.insert(handle()), Ok(2));
+
+        // A lower limit leaves open descriptors alone
+        table.set_limit(2);
+        assert!(table.get(3).is_ok());
+        assert_eq!(table.insert(handle()).err(), Some(FsError::EMFILE));
+        assert_eq!(table.replace(2, handle()).err(), Some(FsError::EBADF));
+        assert_eq!(table.get(-1).err(), Some(FsError::EBADF));
+    }
+}
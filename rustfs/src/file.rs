@@ -10,118 +10,626 @@
 
 extern crate time;
 
-use std::collections::HashMap;
-use std::rc::Rc;
-use std::cell::{Cell, RefCell};
-use inode::{Inode};
-use self::File::{DataFile, Directory};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Weak};
+use parking_lot::{Mutex, RwLock};
+use crate::alloc::{Blocks, RcAllocator};
+use crate::device::IoFuture;
+use crate::error::{FsError, FsResult};
+use crate::fs::FileSystem;
+use crate::inode::{AtimePolicy, Attr, Inode, PAGE_SIZE};
+use crate::view::{PageView, PageViewMut};
+use crate::xattr::Xattrs;
+use crate::{DirEntry, FileType, Stat, O_APPEND, O_NONBLOCK, O_RDONLY, O_RDWR, O_WRONLY};
+use crate::{FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE};
+use self::File::{DataFile, Directory, Symlink};
 
-pub type RcDirContent<'r> = Rc<RefCell<Box<DirectoryContent<'r>>>>;
-pub type RcInode = Rc<RefCell<Box<Inode>>>;
+pub type RcDirContent = Arc<RwLock<Box<DirectoryContent>>>;
+pub type WeakDirContent = Weak<RwLock<Box<DirectoryContent>>>;
+pub type RcInode = Arc<RwLock<Box<Inode>>>;
 
 // File is a thing wrapper around Inodes and Directories. The whole point is to
 // provide a layer of indirection. FileHandle's and Directory entries, then,
 // point to these guys instead of directly to Inodes/Directories
+//
+// A Symlink keeps its target path as the content of an Inode, so it is
+// stored (and hard linked) exactly like a data file.
+//
+// Every Inode and every directory has its own lock. No code path holds two
+// of them unless they are a directory and something in it, and then the
+// directory is locked first (Proc::rename, which locks two directories,
+// takes FileSystem's rename lock before). Handles lock their offset before
+// the file. No lock is held while pages of a file are read in from the
+// device: what needs them reads them in first, then locks and checks that
+// it is still about the same pages.
 #[derive(Clone)]
-pub enum File<'r> {
+pub enum File {
     DataFile(RcInode),
-    Directory(RcDirContent<'r>),
-    EmptyFile
+    Directory(RcDirContent),
+    Symlink(RcInode)
 }
 
-#[derive(Clone)]
-pub struct FileHandle<'r> {
-    file: File<'r>,
-    seek: Cell<usize>
+// An open file description. Proc's descriptor table shares one between
+// every fd dup'ed from the same open, so they all see the same offset and
+// flags.
+pub struct FileHandle {
+    file: File,
+    seek: Mutex<u64>, // for a directory, the cookie of the last entry read
+    flags: Mutex<u32>, // the open flags
+    sorted: Option<Arc<Vec<Vec<u8>>>> // names of a directory opened sorted
 }
 
-#[derive(Clone)]
-pub struct DirectoryContent<'r> {
-    pub entries: HashMap<&'r str, File<'r>>
+// ".." is kept as a weak link to the parent rather than as an entry: a strong
+// reference would form a cycle and the directory (and every Inode below it)
+// would never be dropped. The root has no parent; its ".." is itself.
+//
+// Names are byte strings owned by the directory; path::check_name says
+// which ones are valid.
+//
+// Every entry gets a cookie when it is inserted, larger than any cookie
+// handed out before in this directory. Listing the directory in cookie
+// order (see next_entry) therefore survives entries coming and going in
+// the meantime: each entry that stays is seen exactly once, wherever the
+// listing stands. "." and ".." have the cookies DOT_COOKIE and
+// DOTDOT_COOKIE; 0 is the start of the directory.
+pub struct DirectoryContent {
+    pub ino: u64,
+    pub entries: HashMap<Vec<u8>, Entry>,
+    order: BTreeMap<u64, Vec<u8>>, // names by cookie
+    next_cookie: u64,
+    pub parent: Option<WeakDirContent>,
+    pub blocks: Blocks,
+    pub attr: Attr,
+    pub xattrs: Xattrs,
+    pub removed: bool // by rmdir or rename; nothing can be added any more
+}
+
+pub struct Entry {
+    pub file: File,
+    pub cookie: u64
 }
 
+pub const DOT_COOKIE: u64 = 1;
+pub const DOTDOT_COOKIE: u64 = 2;
+
 pub enum Whence {
     SeekSet,
     SeekCur,
-    SeekEnd
+    SeekEnd,
+    /// Next offset at or after the given one that holds data
+    SeekData,
+    /// Next offset at or after the given one that is in a hole
+    SeekHole
 }
 
-impl<'r> File<'r> {
-    pub fn new_dir(_parent: Option<File<'r>>) -> File<'r> {
-        let content = Box::new(DirectoryContent { entries: HashMap::new() });
-        let rc = Rc::new(RefCell::new(content));
-        let dir = Directory(rc);
+impl File {
+    pub fn new_dir(ino: u64, parent: Option<File>,
+                   alloc: Option<RcAllocator>) -> FsResult<File> {
+        // "." is resolved by the path walker and ".." through the weak
+        // parent link, see DirectoryContent.
+        let parent = match parent {
+            Some(f) => Some(Arc::downgrade(f.get_dir_rc()?)),
+            None => None
+        };
+        let content = Box::new(DirectoryContent {
+            ino: ino,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_cookie: DOTDOT_COOKIE + 1,
+            parent: parent,
+            blocks: Blocks::new(alloc.clone()),
+            attr: Attr::new(0o755),
+            xattrs: Xattrs::new(alloc),
+            removed: false
+        });
+        let rc = Arc::new(RwLock::new(content));
+        Ok(Directory(rc))
+    }
 
-        // Note that dir is RCd, so this is cheap
-        // Used to borrow dir and mut_dir at "same time"
-        // RefCell makes sure we're not doing anything wrong
-        // let mut mut_dir = dir.clone();
+    pub fn new_data_file(inode: RcInode) -> File {
+        DataFile(inode)
+    }
 
-        // // Setting up "." and ".."
-        // mut_dir.insert(".", dir.clone());
-        // match parent {
-        //   None => mut_dir.insert("..", dir.clone()),
-        //   Some(f) => mut_dir.insert("..", f)
-        // }
+    /// A symbolic link pointing at `target`, which is written to `inode`.
+    pub fn new_symlink(inode: RcInode, target: &[u8]) -> FsResult<File> {
+        {
+            let mut inode = inode.write();
+            inode.write(0, target)?;
+            inode.attr_mut().mode = 0o777;
+        }
+        Ok(Symlink(inode))
+    }
 
-        dir
+    pub fn is_symlink(&self) -> bool {
+        match self {
+            &Symlink(_) => true,
+            _ => false
+        }
     }
 
-    pub fn new_data_file(inode: RcInode) -> File<'r> {
-        DataFile(inode)
+    /// The path a symbolic link points at. EINVAL for any other file.
+    pub fn link_target(&self) -> FsResult<Vec<u8>> {
+        let rc = match self {
+            &Symlink(ref rc) => rc,
+            _ => return Err(FsError::EINVAL)
+        };
+        let inode = rc.read();
+        let mut target = vec![0u8; inode.size() as usize];
+        inode.read(0, &mut target)?;
+        Ok(target)
+    }
+
+    pub fn get_dir_rc<'a>(&'a self) -> FsResult<&'a RcDirContent> {
+        match self {
+            &Directory(ref rc) => Ok(rc),
+            _ => Err(FsError::ENOTDIR)
+        }
+    }
+
+    pub fn ino(&self) -> u64 {
+        match self {
+            &DataFile(ref rc) | &Symlink(ref rc) => rc.read().ino(),
+            &Directory(ref rc) => rc.read().ino
+        }
     }
 
-    pub fn get_dir_rc<'a>(&'a self) -> &'a RcDirContent<'r> {
+    /// Whether both files refer to the same underlying inode or directory.
+    pub fn same_as(&self, other: &File) -> bool {
+        match (self, other) {
+            (&DataFile(ref a), &DataFile(ref b)) => Arc::ptr_eq(a, b),
+            (&Directory(ref a), &Directory(ref b)) => Arc::ptr_eq(a, b),
+            (&Symlink(ref a), &Symlink(ref b)) => Arc::ptr_eq(a, b),
+            _ => false
+        }
+    }
+
+    pub fn file_type(&self) -> FileType {
         match self {
-            &Directory(ref rc) => rc,
-            _ => panic!("not a directory")
+            &DataFile(_) => FileType::Regular,
+            &Directory(_) => FileType::Directory,
+            &Symlink(_) => FileType::Symlink
         }
     }
 
-    pub fn get_inode_rc<'a>(&'a self) -> &'a RcInode {
+    pub fn attr(&self) -> Attr {
         match self {
-            &DataFile(ref rc) => rc,
-            _ => panic!("not a directory")
+            &DataFile(ref rc) | &Symlink(ref rc) => *rc.read().attr(),
+            &Directory(ref rc) => rc.read().attr
         }
     }
+
+    pub fn update_attr<F: FnOnce(&mut Attr)>(&self, f: F) {
+        match self {
+            &DataFile(ref rc) | &Symlink(ref rc) => f(rc.write().attr_mut()),
+            &Directory(ref rc) => f(&mut rc.write().attr)
+        }
+    }
+
+    /// Runs `f` on the extended attributes of the file.
+    pub fn xattrs<R, F: FnOnce(&Xattrs) -> R>(&self, f: F) -> R {
+        match self {
+            &DataFile(ref rc) | &Symlink(ref rc) => f(rc.read().xattrs()),
+            &Directory(ref rc) => f(&rc.read().xattrs)
+        }
+    }
+
+    /// Runs `f` on the extended attributes of the file and, if it succeeds,
+    /// updates the change time.
+    pub fn update_xattrs<F: FnOnce(&mut Xattrs) -> FsResult<()>>(&self, f: F) -> FsResult<()> {
+        match self {
+            &DataFile(ref rc) | &Symlink(ref rc) => {
+                let mut inode = rc.write();
+                f(inode.xattrs_mut())?;
+                inode.attr_mut().changed();
+            }
+            &Directory(ref rc) => {
+                let mut content = rc.write();
+                f(&mut content.xattrs)?;
+                content.attr.changed();
+            }
+        }
+        Ok(())
+    }
+
+    /// The metadata of the file. A directory links to itself and to each of
+    /// its subdirectories (through their ".."), on top of its own name.
+    pub fn stat(&self) -> Stat {
+        match self {
+            &DataFile(ref rc) | &Symlink(ref rc) => {
+                let inode = rc.read();
+                make_stat(inode.ino(), self.file_type(), inode.attr(), inode.nlink(), inode.size(),
+                          inode.allocated_pages())
+            }
+            &Directory(ref rc) => {
+                let content = rc.read();
+                let subdirs = content.entries.values().filter(|e| e.file.get_dir_rc().is_ok()).count();
+                let pages = content.blocks.extents().iter().map(|e| e.len).sum::<u64>();
+                make_stat(content.ino, FileType::Directory, &content.attr, 2 + subdirs as u32,
+                          pages * PAGE_SIZE as u64, pages)
+            }
+        }
+    }
+
+    /// The inode behind a data file or a symbolic link.
+    pub fn get_inode_rc<'a>(&'a self) -> FsResult<&'a RcInode> {
+        match self {
+            &DataFile(ref rc) | &Symlink(ref rc) => Ok(rc),
+            &Directory(_) => Err(FsError::EISDIR)
+        }
+    }
+}
+
+impl DirectoryContent {
+    /// Adds `file` under `name` with a new cookie. Fails with EEXIST if the
+    /// name is taken and with ENOENT if the directory has been removed.
+    pub fn add(&mut self, name: Vec<u8>, file: File) -> FsResult<()> {
+        if self.removed { return Err(FsError::ENOENT); }
+        if self.entries.contains_key(&name) {
+            return Err(FsError::EEXIST);
+        }
+        let cookie = self.next_cookie;
+        self.next_cookie += 1;
+        self.order.insert(cookie, name.clone());
+        self.entries.insert(name, Entry { file: file, cookie: cookie });
+        Ok(())
+    }
+
+    pub fn take(&mut self, name: &[u8]) -> FsResult<File> {
+        let entry = self.entries.remove(name).ok_or(FsError::ENOENT)?;
+        self.order.remove(&entry.cookie);
+        Ok(entry.file)
+    }
+
+    /// The first entry (not counting "." and "..") whose cookie is larger
+    /// than `cookie`, with its cookie.
+    pub fn next_entry(&self, cookie: u64) -> Option<(u64, &[u8], &File)> {
+        let (&next, name) = self.order.range((cookie + 1)..).next()?;
+        Some((next, name, &self.entries[name].file))
+    }
+}
+
+fn make_stat(ino: u64, kind: FileType, attr: &Attr, nlink: u32, size: u64, pages: u64) -> Stat {
+    Stat {
+        ino: ino,
+        file_type: kind,
+        mode: kind.mode_bits() | attr.mode as u32,
+        nlink: nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        size: size,
+        blocks: pages * (PAGE_SIZE / 512) as u64,
+        block_size: PAGE_SIZE,
+        atime: attr.access_time,
+        mtime: attr.mod_time,
+        ctime: attr.change_time,
+        crtime: attr.create_time
+    }
+}
+
+// Total length of buffers, saturated rather than wrapped
+fn total_len<I: Iterator<Item = usize>>(lens: I) -> u64 {
+    lens.fold(0u64, |total, len| total.saturating_add(len as u64))
+}
+
+// Reads in the pages that [offset, end) only partly covers, for a change of
+// the range to keep the rest of them. The pages it wholly covers are
+// replaced without being read.
+fn load_edges<'a>(fs: &'a FileSystem, inode_rc: &'a RcInode, offset: u64, end: u64) -> IoFuture<'a, ()> {
+    Box::pin(async move {
+        if offset >= end { return Ok(()); }
+        let page = PAGE_SIZE as u64;
+        if offset % page != 0 {
+            await!(fs.load(inode_rc, offset - offset % page, offset))?;
+        }
+        if end % page != 0 {
+            await!(fs.load(inode_rc, end, (end - end % page).saturating_add(page)))?;
+        }
+        Ok(())
+    })
+}
+
+/// Sets the size of the file of `inode_rc` to `len` (see Inode::truncate),
+/// reading in first the page that keeps part of its data.
+pub fn truncate<'a>(fs: &'a FileSystem, inode_rc: &'a RcInode, len: u64) -> IoFuture<'a, ()> {
+    Box::pin(async move {
+        let page = PAGE_SIZE as u64;
+        await!(fs.load(inode_rc, len - len % page, len))?;
+        inode_rc.write().truncate(len)
+    })
 }
 
-impl<'r> FileHandle<'r> {
+impl FileHandle {
     // Probably not the right type.
-    pub fn new(file: File<'r>) -> FileHandle<'r> {
+    pub fn new(file: File, flags: u32) -> FileHandle {
         FileHandle {
             file: file,
-            seek: Cell::new(0)
+            seek: Mutex::new(0),
+            flags: Mutex::new(flags),
+            sorted: None
         }
     }
 
-    pub fn read(&self, dst: &mut [u8]) -> usize {
-        let offset = self.seek.get();
-        let inode_rc = self.file.get_inode_rc();
-        let changed = inode_rc.borrow().read(offset, dst);
-        self.seek.set(offset + changed);
-        changed
+    /// A handle listing the directory `dir`. If `sorted` is set, entries
+    /// come in name order; the names are taken when the handle is made, so
+    /// entries added later are not listed.
+    pub fn new_dir(dir: File, sorted: bool) -> FsResult<FileHandle> {
+        let names = if sorted {
+            let mut names: Vec<Vec<u8>> = dir.get_dir_rc()?.read().entries.keys().cloned().collect();
+            names.sort();
+            Some(Arc::new(names))
+        } else {
+            None
+        };
+        let mut handle = FileHandle::new(dir, O_RDONLY);
+        handle.sorted = names;
+        Ok(handle)
+    }
+
+    /// The next entry of a directory handle, None at the end. Every entry
+    /// comes with a cookie that seekdir takes to continue right after it.
+    pub fn readdir(&self) -> FsResult<Option<DirEntry>> {
+        let rc = self.file.get_dir_rc()?;
+        let mut seek = self.seek.lock();
+        let pos = *seek;
+        let (cookie, name, file) = match pos {
+            0 => (DOT_COOKIE, b".".to_vec(), self.file.clone()),
+            DOT_COOKIE => {
+                let content = rc.read();
+                let parent = match content.parent.as_ref().and_then(|weak| weak.upgrade()) {
+                    Some(parent) => Directory(parent),
+                    None => self.file.clone()
+                };
+                (DOTDOT_COOKIE, b"..".to_vec(), parent)
+            }
+            _ => {
+                let content = rc.read();
+                let found = match self.sorted {
+                    // Cookies count positions in the list of names; names
+                    // removed since the handle was made are skipped
+                    Some(ref names) => {
+                        let first = (pos - DOTDOT_COOKIE) as usize;
+                        names.iter().enumerate().skip(first)
+                            .filter_map(|(i, name)| {
+                                content.entries.get(name)
+                                    .map(|e| (i as u64 + DOTDOT_COOKIE + 1, name.clone(), e.file.clone()))
+                            })
+                            .next()
+                    }
+                    None => content.next_entry(pos)
+                        .map(|(cookie, name, file)| (cookie, name.to_vec(), file.clone()))
+                };
+                match found {
+                    Some(entry) => entry,
+                    None => return Ok(None)
+                }
+            }
+        };
+
+        *seek = cookie;
+        Ok(Some(DirEntry { name: name, ino: file.ino(), file_type: file.file_type(), cookie: cookie }))
+    }
+
+    /// Where a directory listing stands, see readdir.
+    pub fn telldir(&self) -> FsResult<u64> {
+        self.file.get_dir_rc()?;
+        Ok(*self.seek.lock())
+    }
+
+    /// Continues a directory listing after the entry with the given cookie;
+    /// 0 starts over.
+    pub fn seekdir(&self, cookie: u64) -> FsResult<()> {
+        self.file.get_dir_rc()?;
+        *self.seek.lock() = cookie;
+        Ok(())
+    }
+
+    pub fn readable(&self) -> bool {
+        (*self.flags.lock() & (O_RDONLY | O_RDWR)) != 0
     }
 
-    pub fn write(&mut self, src: &[u8]) -> usize {
-        let offset = self.seek.get();
-        let inode_rc = self.file.get_inode_rc();
-        let changed = inode_rc.borrow_mut().write(offset, src);
-        self.seek.set(offset + changed);
-        changed
+    pub fn writable(&self) -> bool {
+        (*self.flags.lock() & (O_WRONLY | O_RDWR)) != 0
     }
 
-    pub fn seek(&mut self, offset: isize, whence: Whence) -> usize {
-        let inode_rc = self.file.get_inode_rc();
+    pub fn flags(&self) -> u32 {
+        *self.flags.lock()
+    }
+
+    /// Replaces the status flags that can change after open (O_APPEND and
+    /// O_NONBLOCK); the rest of `flags` is ignored.
+    pub fn set_flags(&self, flags: u32) {
+        let mut current = self.flags.lock();
+        *current = (*current & !(O_APPEND | O_NONBLOCK)) | (flags & (O_APPEND | O_NONBLOCK));
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn read<'a>(&'a self, fs: &'a FileSystem, dst: &'a mut [u8], atime: AtimePolicy) -> IoFuture<'a, usize> {
+        Box::pin(async move { await!(self.readv(fs, &mut [dst], atime)) })
+    }
 
-        let seek = self.seek.get();
-        let new_seek = match whence {
-            Whence::SeekSet => offset as usize,
-            Whence::SeekCur => (seek as isize + offset) as usize,
-            Whence::SeekEnd => (inode_rc.borrow().size() as isize + offset) as usize
+    /// Reads at `offset` without using or moving the offset of the handle,
+    /// like pread(2). Several of these can run on the same file at once.
+    pub fn read_at<'a>(&'a self, fs: &'a FileSystem, offset: u64, dst: &'a mut [u8],
+                       atime: AtimePolicy) -> IoFuture<'a, usize> {
+        Box::pin(async move { await!(self.readv_at(fs, offset, &mut [dst], atime)) })
+    }
+
+    /// Same as read, but scatters the data over `dsts` in order.
+    pub fn readv<'a, 'b: 'a>(&'a self, fs: &'a FileSystem, dsts: &'a mut [&'b mut [u8]],
+                             atime: AtimePolicy) -> IoFuture<'a, usize> {
+        Box::pin(async move {
+            if !self.readable() { return Err(FsError::EBADF); }
+            let inode_rc = self.file.get_inode_rc()?;
+            let len = total_len(dsts.iter().map(|dst| dst.len()));
+            loop {
+                let offset = *self.seek.lock();
+                await!(fs.load(inode_rc, offset, offset.saturating_add(len)))?;
+                // Another read of this handle may have moved the offset to
+                // pages not read in yet meanwhile
+                let mut seek = self.seek.lock();
+                if *seek != offset { continue; }
+                let changed = self.read_loaded(inode_rc, offset, dsts, atime)?;
+                *seek += changed as u64;
+                return Ok(changed);
+            }
+        })
+    }
+
+    pub fn readv_at<'a, 'b: 'a>(&'a self, fs: &'a FileSystem, offset: u64, dsts: &'a mut [&'b mut [u8]],
+                                atime: AtimePolicy) -> IoFuture<'a, usize> {
+        Box::pin(async move {
+            if !self.readable() { return Err(FsError::EBADF); }
+            let inode_rc = self.file.get_inode_rc()?;
+            let len = total_len(dsts.iter().map(|dst| dst.len()));
+            await!(fs.load(inode_rc, offset, offset.saturating_add(len)))?;
+            self.read_loaded(inode_rc, offset, dsts, atime)
+        })
+    }
+
+    // Reads once the pages of the range are in memory
+    fn read_loaded(&self, inode_rc: &RcInode, offset: u64, dsts: &mut [&mut [u8]],
+                   atime: AtimePolicy) -> FsResult<usize> {
+        let changed = inode_rc.read().readv(offset, dsts)?;
+        if atime != AtimePolicy::NoAtime {
+            inode_rc.write().attr_mut().accessed(atime);
+        }
+        Ok(changed)
+    }
+
+    pub fn write<'a>(&'a self, fs: &'a FileSystem, src: &'a [u8]) -> IoFuture<'a, usize> {
+        Box::pin(async move { await!(self.writev(fs, &[src])) })
+    }
+
+    /// Writes at `offset` without using or moving the offset of the handle,
+    /// like pwrite(2). Unlike Linux, O_APPEND does not move the write to the
+    /// end of the file.
+    pub fn write_at<'a>(&'a self, fs: &'a FileSystem, offset: u64, src: &'a [u8]) -> IoFuture<'a, usize> {
+        Box::pin(async move { await!(self.writev_at(fs, offset, &[src])) })
+    }
+
+    /// Same as write, but gathers the data from `srcs` in order. Nothing
+    /// else written to the file can land between the buffers.
+    pub fn writev<'a, 'b: 'a>(&'a self, fs: &'a FileSystem, srcs: &'a [&'b [u8]]) -> IoFuture<'a, usize> {
+        Box::pin(async move {
+            if !self.writable() { return Err(FsError::EBADF); }
+            let append = (self.flags() & O_APPEND) != 0;
+            let inode_rc = self.file.get_inode_rc()?;
+            let len = total_len(srcs.iter().map(|src| src.len()));
+            loop {
+                let offset = if append { inode_rc.read().size() } else { *self.seek.lock() };
+                await!(load_edges(fs, inode_rc, offset, offset.saturating_add(len)))?;
+                let mut seek = self.seek.lock();
+                let mut inode = inode_rc.write();
+                // With O_APPEND the end of file is looked up under the same
+                // lock as the write, so nothing can slip in between. If it
+                // moved since the pages were read in, they may be others
+                let now = if append { inode.size() } else { *seek };
+                if now != offset { continue; }
+                let changed = inode.writev(offset, srcs)?;
+                *seek = offset + changed as u64;
+                return Ok(changed);
+            }
+        })
+    }
+
+    pub fn writev_at<'a, 'b: 'a>(&'a self, fs: &'a FileSystem, offset: u64,
+                                 srcs: &'a [&'b [u8]]) -> IoFuture<'a, usize> {
+        Box::pin(async move {
+            if !self.writable() { return Err(FsError::EBADF); }
+            let inode_rc = self.file.get_inode_rc()?;
+            let len = total_len(srcs.iter().map(|src| src.len()));
+            await!(load_edges(fs, inode_rc, offset, offset.saturating_add(len)))?;
+            inode_rc.write().writev(offset, srcs)
+        })
+    }
+
+    pub fn truncate<'a>(&'a self, fs: &'a FileSystem, len: u64) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            if !self.writable() { return Err(FsError::EINVAL); }
+            await!(truncate(fs, self.file.get_inode_rc()?, len))
+        })
+    }
+
+    /// See Proc::map.
+    pub fn map<'a>(&'a self, fs: &'a FileSystem, offset: u64, len: u64,
+                   atime: AtimePolicy) -> IoFuture<'a, PageView> {
+        Box::pin(async move {
+            if !self.readable() { return Err(FsError::EBADF); }
+            if len == 0 { return Err(FsError::EINVAL); }
+            let inode_rc = self.file.get_inode_rc()?;
+            await!(fs.load(inode_rc, offset, offset.saturating_add(len)))?;
+            // The access time is set under the same lock the pages are taken
+            // under, so that it covers what the view shows
+            if atime == AtimePolicy::NoAtime {
+                return Ok(PageView::new(&inode_rc.read(), offset, len));
+            }
+            let mut inode = inode_rc.write();
+            inode.attr_mut().accessed(atime);
+            Ok(PageView::new(&inode, offset, len))
+        })
+    }
+
+    /// See Proc::map_mut.
+    pub fn map_mut<'a>(&'a self, fs: &'a FileSystem, offset: u64, len: u64) -> IoFuture<'a, PageViewMut> {
+        Box::pin(async move {
+            if !self.writable() { return Err(FsError::EBADF); }
+            if len == 0 { return Err(FsError::EINVAL); }
+            let inode_rc = self.file.get_inode_rc()?;
+            await!(fs.load(inode_rc, offset, offset.saturating_add(len)))?;
+            PageViewMut::new(inode_rc.clone(), offset, len)
+        })
+    }
+
+    /// See Proc::fallocate.
+    pub fn fallocate<'a>(&'a self, fs: &'a FileSystem, mode: u32, offset: u64, len: u64) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            if !self.writable() { return Err(FsError::EBADF); }
+            if len == 0 { return Err(FsError::EINVAL); }
+            let keep_size = (mode & FALLOC_FL_KEEP_SIZE) != 0;
+            let inode_rc = self.file.get_inode_rc()?;
+            if (mode & (FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE)) != 0 {
+                await!(load_edges(fs, inode_rc, offset, offset.saturating_add(len)))?;
+            }
+            let mut inode = inode_rc.write();
+            match mode & !FALLOC_FL_KEEP_SIZE {
+                0 => inode.allocate(offset, len, keep_size),
+                // Punching a hole never changes the size, so Linux wants that
+                // spelled out
+                FALLOC_FL_PUNCH_HOLE if keep_size => inode.punch_hole(offset, len),
+                FALLOC_FL_ZERO_RANGE => inode.zero_range(offset, len, keep_size),
+                _ => Err(FsError::EOPNOTSUPP)
+            }
+        })
+    }
+
+    pub fn seek(&self, offset: i64, whence: Whence) -> FsResult<u64> {
+        let inode_rc = self.file.get_inode_rc()?;
+
+        let mut seek = self.seek.lock();
+        let base = match whence {
+            Whence::SeekSet => 0,
+            Whence::SeekCur => *seek as i64,
+            Whence::SeekEnd => inode_rc.read().size() as i64,
+            Whence::SeekData | Whence::SeekHole => {
+                if offset < 0 { return Err(FsError::ENXIO); }
+                let inode = inode_rc.read();
+                let found = match whence {
+                    Whence::SeekData => inode.seek_data(offset as u64)?,
+                    _ => inode.seek_hole(offset as u64)?
+                };
+                *seek = found;
+                return Ok(found);
+            }
         };
 
-        self.seek.set(new_seek);
-        new_seek
+        // Seeking before the start of the file is an error, like lseek(2)
+        let new_seek = base.checked_add(offset).ok_or(FsError::EINVAL)?;
+        if new_seek < 0 { return Err(FsError::EINVAL); }
+
+        *seek = new_seek as u64;
+        Ok(new_seek as u64)
     }
 }
\ No newline at end of file
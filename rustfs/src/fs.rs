@@ -0,0 +1,260 @@
+/*************************************************************************
+  > File Name:       fs.rs
+  > Author:          Zeyuan Hu
+  > Mail:            iamzeyuanhu@utexas.edu
+  > Created Time:    10/17/26
+  > Description:
+
+    A mounted file system: the directory tree, the inode numbers, the block
+    allocator and the device, shared by every Proc working on it. What is
+    private to a process (working directory, descriptor table, credentials)
+    lives in Proc.
+
+    A FileSystem is Send and Sync: Procs on different threads can work on
+    it at the same time. See file.rs for the order locks are taken in.
+ ************************************************************************/
+
+use futures_new::channel::oneshot;
+use parking_lot::{Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::sync::{Arc, Weak};
+use crate::alloc::RcAllocator;
+use crate::device::{BlockDevice, IoFuture, PageMemory};
+use crate::disk;
+use crate::error::{FsError, FsResult};
+use crate::file::{File, FileHandle, RcInode};
+use crate::file::File::DataFile;
+use crate::inode::{Inode, InodeNumbers};
+use crate::layout::{Superblock, ROOT_INO};
+use crate::StatFs;
+
+struct Disk {
+    device: Box<dyn BlockDevice>,
+    // Only the superblock is locked: pages of files are read in from the
+    // device while a sync runs
+    sb: Mutex<Superblock>
+}
+
+pub struct FileSystem {
+    root: File,
+    inos: Mutex<InodeNumbers>,
+    alloc: Option<RcAllocator>,
+    disk: Option<Disk>, // None if the tree only lives in memory
+    // Every file opened by any Proc. sync keeps the inode numbers of those
+    // still open in use, whether or not they still have a name.
+    open: Mutex<Vec<Weak<FileHandle>>>,
+    // Those waiting for the running sync to end, or None if none runs
+    syncing: Mutex<Option<Vec<oneshot::Sender<()>>>>,
+    names: RwLock<()>,
+    rename: Mutex<()>
+}
+
+/// Lets the next sync start when dropped (see FileSystem::begin_sync).
+struct SyncGuard<'a> {
+    fs: &'a FileSystem
+}
+
+impl<'a> Drop for SyncGuard<'a> {
+    fn drop(&mut self) {
+        if let Some(waiters) = self.fs.syncing.lock().take() {
+            for waiter in waiters {
+                // Gone if the one waiting was dropped meanwhile
+                let _ = waiter.send(());
+            }
+        }
+    }
+}
+
+impl FileSystem {
+    /// A file system with an empty root directory, held in memory only.
+    pub fn new() -> Arc<FileSystem> {
+        let root = File::new_dir(ROOT_INO, None, None).expect("root has no parent to check");
+        Arc::new(FileSystem::with_root(root, InodeNumbers::new(ROOT_INO + 1, u64::max_value()), None, None))
+    }
+
+    fn with_root(root: File, inos: InodeNumbers, alloc: Option<RcAllocator>, disk: Option<Disk>) -> FileSystem {
+        FileSystem {
+            root: root,
+            inos: Mutex::new(inos),
+            alloc: alloc,
+            disk: disk,
+            open: Mutex::new(Vec::new()),
+            syncing: Mutex::new(None),
+            names: RwLock::new(()),
+            rename: Mutex::new(())
+        }
+    }
+
+    /// Mounts the file system on `device` (see mkfs). The directory tree
+    /// and the metadata of every file are read into memory, but not the
+    /// data of files: a page is read from the device the first time it is
+    /// used, and then stays in memory. Changes are written back by sync and
+    /// unmount.
+    pub fn mount(device: Box<dyn BlockDevice>) -> IoFuture<'static, Arc<FileSystem>> {
+        Box::pin(async move {
+            let mounted = await!(disk::load(&*device))?;
+            let state = Disk { device: device, sb: Mutex::new(mounted.sb) };
+            Ok(Arc::new(FileSystem::with_root(mounted.root, mounted.inos, Some(mounted.alloc), Some(state))))
+        })
+    }
+
+    pub fn root(&self) -> &File {
+        &self.root
+    }
+
+    /// An empty, unlinked inode with a fresh number.
+    pub fn new_inode(&self) -> FsResult<RcInode> {
+        let memory = self.disk.as_ref().map_or(PageMemory::Heap, |state| state.device.page_memory());
+        let inode = Inode::new(self.inos.lock().alloc()?, self.alloc.clone(), memory);
+        Ok(Arc::new(RwLock::new(Box::new(inode))))
+    }
+
+    /// An empty directory with a fresh number, to be inserted into `parent`.
+    pub fn new_dir(&self, parent: &File) -> FsResult<File> {
+        let ino = self.inos.lock().alloc()?;
+        File::new_dir(ino, Some(parent.clone()), self.alloc.clone())
+    }
+
+    /// Reads in the pages of [offset, end) of `inode_rc` still only on the
+    /// device (see Inode::missing). Does nothing for a file system that only
+    /// lives in memory.
+    pub fn load<'a>(&'a self, inode_rc: &'a RcInode, offset: u64, end: u64) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            let state = match self.disk {
+                Some(ref state) => state,
+                None => return Ok(())
+            };
+            let missing = inode_rc.read().missing(offset, end);
+            await!(disk::read_pages(&*state.device, inode_rc, &missing))
+        })
+    }
+
+    /// Makes sync aware of a newly opened file.
+    pub fn track(&self, handle: &Arc<FileHandle>) {
+        let mut open = self.open.lock();
+        // Forget closed files before the list would grow, so it stays
+        // within twice the number of open ones
+        if open.len() == open.capacity() {
+            open.retain(|weak| weak.upgrade().is_some());
+        }
+        open.push(Arc::downgrade(handle));
+    }
+
+    /// Held by a Proc while it adds or removes names or changes link
+    /// counts, so that sync never sees such a change half done.
+    pub fn names_lock(&self) -> RwLockReadGuard<()> {
+        self.names.read()
+    }
+
+    /// Taken by Proc::rename for the whole move, so that no other rename
+    /// changes the shape of the tree meanwhile.
+    pub fn rename_lock(&self) -> MutexGuard<()> {
+        self.rename.lock()
+    }
+
+    /// Waits for the running sync, if any, to end, and keeps the next one
+    /// from starting until the guard is dropped. A sync waits on the device
+    /// with the tree locked, and the thread blocked on that lock could be
+    /// the one to complete the I/O, so the wait is a future instead.
+    fn begin_sync<'a>(&'a self) -> IoFuture<'a, SyncGuard<'a>> {
+        Box::pin(async move {
+            loop {
+                let receiver = {
+                    let mut syncing = self.syncing.lock();
+                    match *syncing {
+                        Some(ref mut waiters) => {
+                            let (sender, receiver) = oneshot::channel();
+                            waiters.push(sender);
+                            receiver
+                        }
+                        None => {
+                            *syncing = Some(Vec::new());
+                            return Ok(SyncGuard { fs: self });
+                        }
+                    }
+                };
+                // Another waiter may get there first: check again
+                let _ = await!(receiver);
+            }
+        })
+    }
+
+    /// Writes the file system back to its device. Does nothing for a file
+    /// system that only lives in memory. If a sync is already running, waits
+    /// for it to end and then runs, since it may have missed changes made
+    /// meanwhile.
+    pub fn sync<'a>(&'a self) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            let _sync = await!(self.begin_sync())?;
+            let (state, alloc) = match (&self.disk, &self.alloc) {
+                (&Some(ref state), &Some(ref alloc)) => (state, alloc),
+                _ => return Ok(())
+            };
+            // Syncs run one at a time, so nothing else changes it meanwhile
+            let mut sb = state.sb.lock().clone();
+            let _names = self.names.write();
+            let open: Vec<File> = {
+                let mut open = self.open.lock();
+                open.retain(|weak| weak.upgrade().is_some());
+                open.iter().filter_map(|weak| weak.upgrade()).map(|h| h.file().clone()).collect()
+            };
+            let stored = await!(disk::store(&*state.device, &mut sb, alloc, &self.root, &open));
+            *state.sb.lock() = sb;
+            let used = stored?;
+            self.inos.lock().rebuild(ROOT_INO + 1, &used);
+            Ok(())
+        })
+    }
+
+    /// Makes the data of `file` durable and, unless `data_only` is set, its
+    /// metadata too (fsync and fdatasync). Metadata only reaches the device
+    /// with the whole tree, so that takes a sync. Data alone is written in
+    /// place, as long as the size and the blocks of the file on the device
+    /// are still the current ones.
+    pub fn sync_file<'a>(&'a self, file: &'a File, data_only: bool) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            let inode_rc = match file {
+                &DataFile(ref rc) if data_only => rc,
+                _ => return await!(self.sync())
+            };
+            {
+                let _sync = await!(self.begin_sync())?;
+                let state = match self.disk {
+                    Some(ref state) => state,
+                    None => return Ok(())
+                };
+                let mut inode = inode_rc.write();
+                if inode.layout_synced() {
+                    await!(disk::write_dirty(&*state.device, &inode))?;
+                    inode.clear_dirty();
+                    return await!(state.device.flush());
+                }
+            }
+            await!(self.sync())
+        })
+    }
+
+    /// Block usage of the device, or None for a file system that only lives
+    /// in memory.
+    pub fn statfs(&self) -> Option<StatFs> {
+        let (sb, alloc) = match (&self.disk, &self.alloc) {
+            (&Some(ref state), &Some(ref alloc)) => (state.sb.lock(), alloc.lock()),
+            _ => return None
+        };
+        Some(StatFs {
+            block_size: sb.block_size as usize,
+            blocks: sb.num_blocks,
+            free_blocks: alloc.free_blocks(),
+            used_blocks: alloc.used_blocks()
+        })
+    }
+
+    /// Syncs and hands the device back. Fails with EBUSY if a Proc still
+    /// works on the file system, and with EINVAL if it only lives in memory.
+    pub fn unmount(fs: Arc<FileSystem>) -> IoFuture<'static, Box<dyn BlockDevice>> {
+        Box::pin(async move {
+            await!(fs.sync())?;
+            let fs = Arc::try_unwrap(fs).map_err(|_| FsError::EBUSY)?;
+            fs.disk.map(|state| state.device).ok_or(FsError::EINVAL)
+        })
+    }
+}
@@ -8,122 +8,385 @@
     This file contains the implementation of the inode.
  ************************************************************************/
 
-extern crate spdk_rs;
-
-use self::spdk_rs::raw;
+use crate::alloc::{Blocks, RcAllocator};
+use crate::device::{IoBuf, PageMemory};
+use crate::error::{FsError, FsResult};
+use crate::extent::ExtentMap;
+use crate::layout::{InodeRecord, KIND_FILE};
+use crate::xattr::Xattrs;
 use time;
 use time::Timespec;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::mem;
-use std::ptr;
 use std::ptr::copy_nonoverlapping;
+use std::sync::Arc;
+
+pub const PAGE_SIZE: usize = 4096;
+/// Largest file size; offsets past it cannot be reached with lseek.
+pub const MAX_FILE_SIZE: u64 = i64::max_value() as u64;
+
+/// PAGE_SIZE bytes in the memory the device asks for (see IoBuf), shared
+/// with the views that lend it out (see view.rs); copied before a change
+/// while one still holds it.
+pub type Page = Arc<IoBuf>;
+
+/// What holes read back as when lent out rather than copied.
+pub static ZERO_PAGE: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+
+/// Reads do not update the access time of a file more than once in this
+/// many seconds under AtimePolicy::Relatime.
+pub const RELATIME_INTERVAL: i64 = 24 * 60 * 60;
+
+/// When reading a file updates its access time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtimePolicy {
+    /// On every read
+    Strict,
+    /// Only if the access time is not after the last modification or
+    /// change, or is more than RELATIME_INTERVAL old (like Linux relatime)
+    Relatime,
+    /// Never
+    NoAtime
+}
 
-const PAGE_SIZE: usize = 4096;
-const LIST_SIZE: usize = 256;
+/// The metadata every kind of file has, whatever holds its content.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attr {
+    pub mode: u16, // permission bits
+    pub uid: u32,
+    pub gid: u32,
+    pub access_time: Timespec,
+    pub mod_time: Timespec,
+    pub change_time: Timespec,
+    pub create_time: Timespec
+}
 
-type Page = Box<([u8; PAGE_SIZE])>;
-type Entry = Page;
-type EntryList = TList<Entry>; // TODO: Option<TList> for lazy loading
-type DoubleEntryList = TList<EntryList>;
-pub type TList<T> = Box<([Option<T>; LIST_SIZE])>;
+impl Attr {
+    pub fn new(mode: u16) -> Attr {
+        let time_now = time::get_time();
+        Attr {
+            mode: mode,
+            uid: 0,
+            gid: 0,
+            access_time: time_now,
+            mod_time: time_now,
+            change_time: time_now,
+            create_time: time_now
+        }
+    }
 
-#[inline(always)]
-fn ceil_div(x: usize, y: usize) -> usize {
-    return (x + y - 1) / y;
+    pub fn from_record(record: &InodeRecord) -> Attr {
+        Attr {
+            mode: record.mode,
+            uid: record.uid,
+            gid: record.gid,
+            access_time: record.access_time,
+            mod_time: record.mod_time,
+            change_time: record.change_time,
+            create_time: record.create_time
+        }
+    }
+
+    pub fn fill_record(&self, record: &mut InodeRecord) {
+        record.mode = self.mode;
+        record.uid = self.uid;
+        record.gid = self.gid;
+        record.access_time = self.access_time;
+        record.mod_time = self.mod_time;
+        record.change_time = self.change_time;
+        record.create_time = self.create_time;
+    }
+
+    /// The content changed, which changes the metadata too.
+    pub fn modified(&mut self) {
+        let time_now = time::get_time();
+        self.mod_time = time_now;
+        self.change_time = time_now;
+    }
+
+    /// Only the metadata changed (link count, mode, owner, ...).
+    pub fn changed(&mut self) {
+        self.change_time = time::get_time();
+    }
+
+    /// The content was read.
+    pub fn accessed(&mut self, policy: AtimePolicy) {
+        let time_now = time::get_time();
+        let update = match policy {
+            AtimePolicy::Strict => true,
+            AtimePolicy::Relatime => {
+                self.access_time <= self.mod_time || self.access_time <= self.change_time
+                    || time_now.sec - self.access_time.sec >= RELATIME_INTERVAL
+            }
+            AtimePolicy::NoAtime => false
+        };
+        if update { self.access_time = time_now; }
+    }
 }
 
 #[inline(always)]
-pub fn create_tlist<T>() -> TList<T> {
-    let mut list: TList<T> = Box::new(unsafe { mem::uninitialized() });
-    for x in list.iter_mut() { unsafe { ptr::write(x, None); } };
-    list
+fn ceil_div(x: u64, y: u64) -> u64 {
+    return (x + y - 1) / y;
 }
 
 pub struct Inode {
-    single: EntryList, // Box<([Option<Page>, ..256])>
-    double: DoubleEntryList, // Box<[Option<Box<([Option<Page>>, ..256])>, ..256]
-    size: usize,
-
-    mod_time: Timespec,
-    access_time: Timespec,
-    create_time: Timespec,
+    ino: u64,
+    nlink: u32, // names in directories pointing at this inode
+    pages: BTreeMap<u64, Page>, // only the pages that hold data
+    // Pages that hold data the device has but memory does not yet, with
+    // their blocks. Mount reads no file data: a page is read in the first
+    // time it is needed (see missing and fill_page)
+    on_disk: ExtentMap,
+    size: u64,
+    blocks: Blocks, // where the pages go on the device
+    dirty: BTreeSet<u64>, // pages changed since they were last written out
+    pins: u32, // writable views of the file, whose blocks must stay
+    memory: PageMemory, // where new pages are allocated
+    // Blocks freed by punch_hole, as (first block, count), for the next
+    // sync to unmap once the tree on disk no longer uses them
+    punched: Vec<(u64, u64)>,
+    // The size and the blocks version the device has, None if the inode was
+    // never written there
+    synced: Option<(u64, u64)>,
+    attr: Attr,
+    xattrs: Xattrs
 }
 
 impl Inode {
-    pub fn new() -> Inode {
-        // NOTE: here we show how to use spdk_rs
-        let opts : raw::spdk_app_opts;
-        let time_now = time::get_time();
+    pub fn new(ino: u64, alloc: Option<RcAllocator>, memory: PageMemory) -> Inode {
+        Inode::with_blocks(ino, Blocks::new(alloc), memory)
+    }
 
+    fn with_blocks(ino: u64, blocks: Blocks, memory: PageMemory) -> Inode {
+        let xattrs = Xattrs::new(blocks.allocator().cloned());
         Inode {
-            single: create_tlist(),
-            double: create_tlist(),
+            ino: ino,
+            nlink: 1,
+            pages: BTreeMap::new(),
+            on_disk: ExtentMap::new(),
             size: 0,
-
-            mod_time: time_now,
-            access_time: time_now,
-            create_time: time_now
+            blocks: blocks,
+            dirty: BTreeSet::new(),
+            pins: 0,
+            memory: memory,
+            punched: Vec::new(),
+            synced: None,
+            attr: Attr::new(0o644),
+            xattrs: xattrs
         }
     }
 
-    fn get_or_alloc_page<'a>(&'a mut self, num: usize) -> &'a mut Page {
-        if num >= LIST_SIZE + LIST_SIZE * LIST_SIZE {
-            panic!("Maximum file size exceeded!")
-        };
+    /// Rebuilds an inode from its on-disk record and the blocks it maps. The
+    /// pages stay on the device until filled in with fill_page or load_page.
+    pub fn from_record(ino: u64, record: &InodeRecord, blocks: Blocks, memory: PageMemory) -> Inode {
+        let on_disk = blocks.extents().clone();
+        let mut inode = Inode::with_blocks(ino, blocks, memory);
+        inode.on_disk = on_disk;
+        inode.nlink = record.nlink;
+        inode.size = record.size;
+        inode.attr = Attr::from_record(record);
+        inode.synced = Some(inode.layout());
+        inode
+    }
 
-        // Getting a pointer to the page
-        let page = if num < LIST_SIZE {
-            // if the page num is in the singly-indirect list
-            &mut self.single[num]
-        } else {
-            // if the page num is in the doubly-indirect list. We allocate a new
-            // entry list where necessary (*entry_list = ...)
-            let double_entry = num - LIST_SIZE;
-            let slot = double_entry / LIST_SIZE;
-            let entry_list = &mut self.double[slot];
-
-            match *entry_list {
-                None => *entry_list = Some(create_tlist()),
-                _ => { /* Do nothing */ }
-            }
+    /// The on-disk record for this inode, without its extents.
+    pub fn to_record(&self) -> InodeRecord {
+        let mut record = InodeRecord::new(KIND_FILE);
+        record.nlink = self.nlink;
+        record.size = self.size;
+        self.attr.fill_record(&mut record);
+        record
+    }
 
-            let entry_offset = double_entry % LIST_SIZE;
-            &mut entry_list.as_mut().unwrap()[entry_offset]
-        };
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// Number of directory entries naming this inode. A new inode starts
+    /// with one, the name it is created under. The inode (and its storage)
+    /// goes away once it has no names left and no fd holds it.
+    pub fn nlink(&self) -> u32 {
+        self.nlink
+    }
+
+    pub fn inc_nlink(&mut self) {
+        self.nlink += 1;
+        self.attr.changed();
+    }
+
+    pub fn dec_nlink(&mut self) {
+        self.nlink -= 1;
+        self.attr.changed();
+    }
+
+    pub fn attr(&self) -> &Attr {
+        &self.attr
+    }
+
+    pub fn attr_mut(&mut self) -> &mut Attr {
+        &mut self.attr
+    }
+
+    pub fn xattrs(&self) -> &Xattrs {
+        &self.xattrs
+    }
+
+    pub fn xattrs_mut(&mut self) -> &mut Xattrs {
+        &mut self.xattrs
+    }
+
+    pub fn blocks(&self) -> &Blocks {
+        &self.blocks
+    }
+
+    pub fn blocks_mut(&mut self) -> &mut Blocks {
+        &mut self.blocks
+    }
+
+    /// Pages changed since they were last written to the device.
+    pub fn dirty_pages(&self) -> &BTreeSet<u64> {
+        &self.dirty
+    }
+
+    /// Hands the blocks freed by punch_hole since the last call to a sync,
+    /// which unmaps them (see disk::store).
+    pub fn take_punched(&mut self) -> Vec<(u64, u64)> {
+        mem::replace(&mut self.punched, Vec::new())
+    }
+
+    /// Called once the dirty pages are on the device.
+    pub fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+
+    /// What a sync has to record for set_synced: the size and the version of
+    /// the block mapping.
+    pub fn layout(&self) -> (u64, u64) {
+        (self.size, self.blocks.version())
+    }
+
+    /// Called once the inode record written with `layout` is live on disk.
+    pub fn set_synced(&mut self, layout: (u64, u64)) {
+        self.synced = Some(layout);
+    }
 
-        match *page {
-            None => *page = Some(Box::new([0u8; 4096])),
-            _ => { /* Do Nothing */ }
+    /// Whether the size and the block mapping on disk are still current, so
+    /// that writing the dirty pages in place is enough to make the data
+    /// durable.
+    pub fn layout_synced(&self) -> bool {
+        self.synced == Some(self.layout())
+    }
+
+    // Gives the pages of [first, first + count) that have none a device
+    // block (see Blocks::reserve). A new block still holds whatever was
+    // there, so its page is marked dirty for sync to write or zero it.
+    fn reserve(&mut self, first: u64, count: u64) -> FsResult<()> {
+        for e in self.blocks.reserve(first, count)? {
+            self.dirty.extend(e.logical..(e.logical + e.len));
         }
+        Ok(())
+    }
 
-        page.as_mut().unwrap()
+    // The page to change, copied first if a view still holds it. A page made
+    // here takes over from what the device has for it.
+    fn get_or_alloc_page<'a>(&'a mut self, num: u64) -> &'a mut [u8] {
+        if !self.pages.contains_key(&num) {
+            self.on_disk.remove(num, num + 1);
+            let page = self.new_page();
+            self.pages.insert(num, page);
+        }
+        &mut Arc::make_mut(self.pages.get_mut(&num).unwrap())[..]
     }
 
-    fn get_page<'a>(&'a self, num: usize) -> &'a Option<Page> {
-        if num >= LIST_SIZE + LIST_SIZE * LIST_SIZE {
-            panic!("Page does not exist.")
-        };
+    /// A page of zeros, not part of the file.
+    pub fn new_page(&self) -> Page {
+        Arc::new(IoBuf::zeroed(PAGE_SIZE, self.memory))
+    }
 
-        if num < LIST_SIZE {
-            &self.single[num]
-        } else {
-            let double_entry = num - LIST_SIZE;
-            let slot = double_entry / LIST_SIZE;
-            let entry_offset = double_entry % LIST_SIZE;
-            let entry_list = &self.double[slot];
-
-            match *entry_list {
-                None => panic!("Page does not exist."),
-                _ => &entry_list.as_ref().unwrap()[entry_offset]
+    /// Returns None for pages that were never written (holes)
+    pub fn get_page<'a>(&'a self, num: u64) -> Option<&'a Page> {
+        self.pages.get(&num)
+    }
+
+    // Finds the first page at or after `num` (and before `end`) whose
+    // allocation state is `allocated`. Pages not read in yet hold data too.
+    fn find_page(&self, num: u64, end: u64, allocated: bool) -> Option<u64> {
+        if num >= end { return None; }
+        if allocated {
+            let in_memory = self.pages.range(num..end).next().map(|(&n, _)| n);
+            let on_disk = self.on_disk.next_mapped(num).filter(|&n| n < end);
+            return match (in_memory, on_disk) {
+                (Some(a), Some(b)) => Some(cmp::min(a, b)),
+                (a, b) => a.or(b)
+            };
+        }
+
+        // The first gap in the run of allocated pages starting at `num`
+        let mut expect = num;
+        while expect < end {
+            if self.pages.contains_key(&expect) {
+                expect += 1;
+            } else if let Some(next) = self.on_disk.extent_end(expect) {
+                expect = next;
+            } else {
+                return Some(expect);
             }
         }
+        None
+    }
+
+    // The pages in [first, last) not read in yet, with their blocks
+    fn unread_pages(&self, first: u64, last: u64) -> Vec<(u64, u64)> {
+        let mut unread = Vec::new();
+        let mut num = first;
+        while let Some(next) = self.on_disk.next_mapped(num) {
+            if next >= last { break; }
+            num = cmp::min(self.on_disk.extent_end(next).unwrap(), last);
+            unread.extend((next..num).map(|n| (n, self.on_disk.lookup(n).unwrap())));
+        }
+        unread
+    }
+
+    /// The pages of [offset, end) still only on the device, as (page number,
+    /// block), for the caller to read in with fill_page before it reads or
+    /// partly overwrites them. Pages past the end of the file are left out:
+    /// their blocks only hold zeros, which is what a missing page reads as.
+    pub fn missing(&self, offset: u64, end: u64) -> Vec<(u64, u64)> {
+        let end = cmp::min(end, self.size);
+        if offset >= end { return Vec::new(); }
+        self.unread_pages(offset / PAGE_SIZE as u64, ceil_div(end, PAGE_SIZE as u64))
+    }
+
+    /// Makes `page`, read from `block` where missing said page `num` was,
+    /// that page. Nothing happens if the page changed in memory since.
+    pub fn fill_page(&mut self, num: u64, block: u64, page: Page) {
+        if self.on_disk.lookup(num) == Some(block) {
+            self.on_disk.remove(num, num + 1);
+            self.pages.insert(num, page);
+        }
     }
 
-    pub fn write(&mut self, offset: usize, data: &[u8]) -> usize {
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> FsResult<usize> {
+        // Nothing to write: the file must neither grow nor get a page
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        // Refuse the whole write up front rather than leaving it half done
+        let end = offset.checked_add(data.len() as u64).ok_or(FsError::EFBIG)?;
+        if end > MAX_FILE_SIZE {
+            return Err(FsError::EFBIG);
+        }
+
         let mut written = 0;
-        let mut block_offset = offset % PAGE_SIZE; // offset from first block
+        let mut block_offset = (offset % PAGE_SIZE as u64) as usize; // offset from first block
 
-        let start = offset / PAGE_SIZE; // first block to act on
-        let blocks_to_act_on = ceil_div(block_offset + data.len(), PAGE_SIZE);
+        let start = offset / PAGE_SIZE as u64; // first block to act on
+        let blocks_to_act_on = ceil_div((block_offset + data.len()) as u64, PAGE_SIZE as u64);
+
+        // Device space for the new pages, as one run where possible
+        self.reserve(start, blocks_to_act_on)?;
 
         for i in 0..blocks_to_act_on {
             // Resetting the block offset after first pass since we want to read from
@@ -138,7 +401,8 @@ impl Inode {
             };
 
             // Finding our block, writing to it
-            let mut page = self.get_or_alloc_page(start + i);
+            self.dirty.insert(start + i);
+            let page = self.get_or_alloc_page(start + i);
             let slice = &mut page[block_offset..(block_offset + num_bytes)];
             // written += slice.copy_from(data.slice(written, written + num_bytes));
             unsafe {
@@ -150,21 +414,25 @@ impl Inode {
             written += num_bytes;
         }
 
-        let last_byte = offset + written;
+        let last_byte = offset + written as u64;
         if self.size < last_byte { self.size = last_byte; }
+        self.attr.modified();
 
-        let time_now = time::get_time();
-        self.mod_time = time_now;
-        self.access_time = time_now;
-
-        written
+        Ok(written)
     }
 
-    pub fn read(&self, offset: usize, data: &mut [u8]) -> usize {
+    /// Reads up to `data.len()` bytes starting at `offset`. Like read(2) this
+    /// stops at the end of the file and returns a short count, or 0 if
+    /// `offset` is at or past the end. Holes read back as zeros.
+    pub fn read(&self, offset: u64, data: &mut [u8]) -> FsResult<usize> {
+        if offset >= self.size { return Ok(0); }
+        let len = cmp::min(data.len() as u64, self.size - offset) as usize;
+        let data = &mut data[..len];
+
         let mut read = 0;
-        let mut block_offset = offset % PAGE_SIZE; // offset from first block
-        let start = offset / PAGE_SIZE; // first block to act on
-        let blocks_to_act_on = ceil_div(block_offset + data.len(), PAGE_SIZE);
+        let mut block_offset = (offset % PAGE_SIZE as u64) as usize; // offset from first block
+        let start = offset / PAGE_SIZE as u64; // first block to act on
+        let blocks_to_act_on = ceil_div((block_offset + data.len()) as u64, PAGE_SIZE as u64);
 
         for i in 0..blocks_to_act_on {
             // Resetting the block offset after first pass since we want to read from
@@ -179,12 +447,17 @@ impl Inode {
             };
 
             // Finding our block, reading from it
+            let slice = &mut data[read..(read + num_bytes)];
             let page = match self.get_page(start + i) {
-                &None => panic!("Empty data."),
-                &Some(ref pg) => pg
+                None => {
+                    // A hole inside the file
+                    for byte in slice.iter_mut() { *byte = 0; }
+                    read += num_bytes;
+                    continue;
+                }
+                Some(pg) => pg
             };
 
-            let slice = &mut data[read..(read + num_bytes)];
             // read += slice.copy_from(page.slice(block_offset,
             // block_offset + num_bytes));
             unsafe {
@@ -196,28 +469,323 @@ impl Inode {
             read += num_bytes;
         }
 
-        read
+        Ok(read)
+    }
+
+    /// Writes the buffers in `srcs` one after the other, starting at
+    /// `offset`, like writev(2). Like write, either all of it is written or,
+    /// on EFBIG or ENOSPC, none of it.
+    pub fn writev(&mut self, offset: u64, srcs: &[&[u8]]) -> FsResult<usize> {
+        let len = srcs.iter().fold(0u64, |len, src| len.saturating_add(src.len() as u64));
+        if len == 0 {
+            return Ok(0);
+        }
+        let end = offset.checked_add(len).ok_or(FsError::EFBIG)?;
+        if end > MAX_FILE_SIZE {
+            return Err(FsError::EFBIG);
+        }
+
+        // Map the whole range at once so it goes to one run on the device
+        // rather than one per buffer
+        let start = offset / PAGE_SIZE as u64;
+        self.reserve(start, ceil_div(end, PAGE_SIZE as u64) - start)?;
+
+        let mut written = 0;
+        for src in srcs {
+            written += self.write(offset + written as u64, src)?;
+        }
+        Ok(written)
+    }
+
+    /// Fills the buffers in `dsts` one after the other with the data at
+    /// `offset`, like readv(2). Stops at the end of the file.
+    pub fn readv(&self, offset: u64, dsts: &mut [&mut [u8]]) -> FsResult<usize> {
+        let mut read = 0;
+        for dst in dsts.iter_mut() {
+            let len = self.read(offset + read as u64, dst)?;
+            read += len;
+            if len < dst.len() { break; }
+        }
+        Ok(read)
+    }
+
+    /// The part of [offset, end) in every page it touches, as (page number,
+    /// start, end) within the page.
+    pub fn page_parts(offset: u64, end: u64) -> impl Iterator<Item = (u64, usize, usize)> {
+        let first = offset / PAGE_SIZE as u64;
+        let last = if offset < end { ceil_div(end, PAGE_SIZE as u64) } else { first };
+        (first..last).map(move |num| {
+            let base = num * PAGE_SIZE as u64;
+            let from = cmp::max(offset, base) - base;
+            let to = cmp::min(end, base + PAGE_SIZE as u64) - base;
+            (num, from as usize, to as usize)
+        })
+    }
+
+    /// The pages of [offset, offset + len) as they are now, None for holes,
+    /// for a view to hold on to. The file copies a page it changes while a
+    /// view holds it, so the view keeps seeing what it got. The pages must
+    /// have been read in (see missing).
+    pub fn share_pages(&self, offset: u64, len: u64) -> Vec<Option<Page>> {
+        Inode::page_parts(offset, offset + len).map(|(num, _, _)| self.pages.get(&num).cloned()).collect()
+    }
+
+    /// Gives every page of [offset, offset + len) a device block and grows
+    /// the file to cover the range, for a writable view to fill. Like
+    /// write, either all of it is done or, on EFBIG or ENOSPC, none of it.
+    pub fn prepare(&mut self, offset: u64, len: u64) -> FsResult<()> {
+        let end = Inode::range_end(offset, len)?;
+        let start = offset / PAGE_SIZE as u64;
+        self.reserve(start, ceil_div(end, PAGE_SIZE as u64) - start)?;
+        if self.size < end { self.size = end; }
+        Ok(())
+    }
+
+    /// Stores what a writable view changed in [from, to) of page `num`:
+    /// the bytes of `page` that differ from `old`, the page the view started
+    /// from (None for a hole). What was written to the rest of the page
+    /// meanwhile stays. If nothing was, a whole page is taken as it is,
+    /// without a copy. Returns whether the view changed anything.
+    pub fn put_page(&mut self, num: u64, old: Option<&Page>, page: &Page, from: usize, to: usize) -> bool {
+        let old_data = old.map_or(&ZERO_PAGE[..], |old| &old[..]);
+        if old.map_or(false, |old| Arc::ptr_eq(old, page)) || old_data[from..to] == page[from..to] {
+            return false;
+        }
+
+        let untouched = match (self.pages.get(&num), old) {
+            (Some(current), Some(old)) => Arc::ptr_eq(current, old),
+            (None, None) => true,
+            _ => false
+        };
+        if untouched && from == 0 && to == PAGE_SIZE {
+            self.pages.insert(num, page.clone());
+        } else {
+            let current = self.get_or_alloc_page(num);
+            for i in from..to {
+                if page[i] != old_data[i] { current[i] = page[i]; }
+            }
+        }
+        self.dirty.insert(num);
+        true
+    }
+
+    /// Called when a writable view of the file is made, and unpin when it
+    /// goes away. The view has blocks for its pages, so until then the file
+    /// cannot shrink or have holes punched in it.
+    pub fn pin(&mut self) {
+        self.pins += 1;
+    }
+
+    pub fn unpin(&mut self) {
+        self.pins -= 1;
+    }
+
+    /// Returns the first offset at or after `offset` that lies in an
+    /// allocated page (SEEK_DATA). Fails with ENXIO if there is none before
+    /// the end of the file.
+    pub fn seek_data(&self, offset: u64) -> FsResult<u64> {
+        if offset >= self.size { return Err(FsError::ENXIO); }
+
+        let end = ceil_div(self.size, PAGE_SIZE as u64);
+        match self.find_page(offset / PAGE_SIZE as u64, end, true) {
+            Some(num) => Ok(cmp::max(offset, num * PAGE_SIZE as u64)),
+            None => Err(FsError::ENXIO)
+        }
+    }
+
+    /// Returns the first offset at or after `offset` that lies in a hole
+    /// (SEEK_HOLE). The end of the file counts as a hole.
+    pub fn seek_hole(&self, offset: u64) -> FsResult<u64> {
+        if offset >= self.size { return Err(FsError::ENXIO); }
+
+        let end = ceil_div(self.size, PAGE_SIZE as u64);
+        match self.find_page(offset / PAGE_SIZE as u64, end, false) {
+            Some(num) => Ok(cmp::max(offset, num * PAGE_SIZE as u64)),
+            None => Ok(self.size)
+        }
+    }
+
+    /// Sets the size of the file to `len`. Pages past the new end are freed
+    /// (with their device blocks) and the tail of the last page is zeroed so
+    /// that growing the file again reads back zeros. Growing leaves a hole.
+    /// Shrinking fails with EBUSY while a writable view is pinning the file.
+    /// The page holding `len` must have been read in (see missing).
+    pub fn truncate(&mut self, len: u64) -> FsResult<()> {
+        if len > MAX_FILE_SIZE { return Err(FsError::EFBIG); }
+
+        if len < self.size {
+            if self.pins > 0 { return Err(FsError::EBUSY); }
+            let keep = ceil_div(len, PAGE_SIZE as u64);
+            self.pages.split_off(&keep);
+            self.on_disk.remove(keep, u64::max_value());
+            self.dirty.split_off(&keep);
+            self.blocks.release(keep, u64::max_value());
+
+            let tail = (len % PAGE_SIZE as u64) as usize;
+            if tail != 0 {
+                if let Some(page) = self.pages.get_mut(&(keep - 1)) {
+                    for byte in Arc::make_mut(page)[tail..].iter_mut() { *byte = 0; }
+                    self.dirty.insert(keep - 1);
+                }
+            }
+        }
+        self.size = len;
+        self.attr.modified();
+        Ok(())
+    }
+
+    // The end of the range of `len` bytes at `offset`, which must lie within
+    // MAX_FILE_SIZE.
+    fn range_end(offset: u64, len: u64) -> FsResult<u64> {
+        match offset.checked_add(len) {
+            Some(end) if end <= MAX_FILE_SIZE => Ok(end),
+            _ => Err(FsError::EFBIG)
+        }
+    }
+
+    // Zeroes [offset, end) in the pages held in memory and marks them dirty.
+    // Pages wholly inside the range are dropped instead, read in or not;
+    // they stay dirty (so that their block gets zeroed) only if
+    // `keep_blocks` is set. Pages partly inside it must have been read in.
+    fn clear_range(&mut self, offset: u64, end: u64, keep_blocks: bool) {
+        let (whole_first, whole_last) = (ceil_div(offset, PAGE_SIZE as u64), end / PAGE_SIZE as u64);
+        if keep_blocks {
+            let unread = self.unread_pages(whole_first, whole_last);
+            self.dirty.extend(unread.into_iter().map(|(num, _)| num));
+        }
+        self.on_disk.remove(whole_first, whole_last);
+
+        let first = offset / PAGE_SIZE as u64;
+        let nums: Vec<u64> = self.pages.range(first..ceil_div(end, PAGE_SIZE as u64))
+            .map(|(&num, _)| num).collect();
+        for num in nums {
+            let base = num * PAGE_SIZE as u64;
+            let from = (cmp::max(offset, base) - base) as usize;
+            let to = (cmp::min(end, base + PAGE_SIZE as u64) - base) as usize;
+            if from == 0 && to == PAGE_SIZE {
+                self.pages.remove(&num);
+                if !keep_blocks {
+                    self.dirty.remove(&num);
+                    continue;
+                }
+            } else {
+                let page = Arc::make_mut(self.pages.get_mut(&num).unwrap());
+                for byte in page[from..to].iter_mut() { *byte = 0; }
+            }
+            self.dirty.insert(num);
+        }
+    }
+
+    /// Gives [offset, offset + len) device blocks without changing what the
+    /// file reads back (fallocate). The pages stay holes until written, but
+    /// writing them can no longer fail with ENOSPC. Unless `keep_size` is
+    /// set, the file grows to cover the range.
+    pub fn allocate(&mut self, offset: u64, len: u64, keep_size: bool) -> FsResult<()> {
+        let end = Inode::range_end(offset, len)?;
+        let start = offset / PAGE_SIZE as u64;
+        self.reserve(start, ceil_div(end, PAGE_SIZE as u64) - start)?;
+        if !keep_size && end > self.size {
+            self.size = end;
+            self.attr.modified();
+        }
+        Ok(())
+    }
+
+    /// Makes [offset, offset + len) read back as zeros and frees the pages
+    /// wholly inside it, with their device blocks (FALLOC_FL_PUNCH_HOLE).
+    /// The size does not change. Fails with EBUSY while a writable view is
+    /// pinning the file.
+    pub fn punch_hole(&mut self, offset: u64, len: u64) -> FsResult<()> {
+        let end = Inode::range_end(offset, len)?;
+        if self.pins > 0 { return Err(FsError::EBUSY); }
+        self.clear_range(offset, end, false);
+        let (first, last) = (ceil_div(offset, PAGE_SIZE as u64), end / PAGE_SIZE as u64);
+        if first < last {
+            let freed = self.blocks.release(first, last);
+            self.punched.extend(freed);
+        }
+        self.attr.modified();
+        Ok(())
+    }
+
+    /// Makes [offset, offset + len) read back as zeros, keeping (or giving)
+    /// the range device blocks like allocate (FALLOC_FL_ZERO_RANGE). Unless
+    /// `keep_size` is set, the file grows to cover the range. On ENOSPC
+    /// nothing changes.
+    pub fn zero_range(&mut self, offset: u64, len: u64, keep_size: bool) -> FsResult<()> {
+        let end = Inode::range_end(offset, len)?;
+        let start = offset / PAGE_SIZE as u64;
+        self.reserve(start, ceil_div(end, PAGE_SIZE as u64) - start)?;
+        self.clear_range(offset, end, true);
+        if !keep_size && end > self.size { self.size = end; }
+        self.attr.modified();
+        Ok(())
+    }
+
+    /// Fills page `num` with `data` (at most PAGE_SIZE bytes) without
+    /// touching the size or the timestamps. Used when loading from disk.
+    pub fn load_page(&mut self, num: u64, data: &[u8]) {
+        let page = self.get_or_alloc_page(num);
+        page[..data.len()].copy_from_slice(data);
     }
 
-    pub fn size(&self) -> usize {
+    pub fn size(&self) -> u64 {
         self.size
     }
 
-    pub fn stat(&self) -> (Timespec, Timespec, Timespec) {
-        (self.create_time, self.access_time, self.mod_time)
+    /// Number of pages holding data, read in or not; holes do not count.
+    pub fn allocated_pages(&self) -> u64 {
+        self.pages.len() as u64 + self.on_disk.iter().map(|e| e.len).sum::<u64>()
+    }
+}
+
+/// Hands out inode numbers. Numbers are never handed out twice while the
+/// file system is mounted; the free list is rebuilt from the numbers still
+/// in use every time the file system is written to disk.
+pub struct InodeNumbers {
+    next: u64,
+    limit: u64,
+    free: Vec<u64>
+}
+
+impl InodeNumbers {
+    /// Numbers go from `first` up to and including `limit`.
+    pub fn new(first: u64, limit: u64) -> InodeNumbers {
+        InodeNumbers { next: first, limit: limit, free: Vec::new() }
+    }
+
+    pub fn alloc(&mut self) -> FsResult<u64> {
+        if let Some(ino) = self.free.pop() {
+            return Ok(ino);
+        }
+        if self.next > self.limit {
+            return Err(FsError::ENOSPC);
+        }
+        self.next += 1;
+        Ok(self.next - 1)
+    }
+
+    /// Rebuilds the free list given every number in use. Numbers at or above
+    /// `first` that are not in `used` become free.
+    pub fn rebuild(&mut self, first: u64, used: &HashSet<u64>) {
+        let top = used.iter().cloned().filter(|&ino| ino >= first).max()
+            .map_or(first, |ino| ino + 1);
+        self.next = top;
+        // Reversed so that pop() hands out the lowest number first
+        self.free = (first..top).rev().filter(|ino| !used.contains(ino)).collect();
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::mem;
+    use std::mem;
 
     extern crate libc;
 
     use std::ffi::{CString, CStr};
     use std::ptr;
     use std::os::raw::{c_void, c_char, c_int};
-    use inode::spdk_rs::raw;
+    use spdk_rs::raw;
 
 
     #[derive(Debug)]
@@ -0,0 +1,617 @@
+/*************************************************************************
+  > File Name:       layout.rs
+  > Author:          Zeyuan Hu
+  > Mail:            iamzeyuanhu@utexas.edu
+  > Created Time:    10/17/26
+  > Description:
+
+    The on-disk format. The device is divided into file system blocks of
+    BLOCK_SIZE bytes (the same size as an in-memory page):
+
+    | superblock | bitmap 0 | bitmap 1 | inode table 0 | inode table 1 | data blocks ... |
+      block 0      bitmap_start           inode_table_start               data_start
+
+    - The superblock identifies the file system (magic, version, UUID) and
+      records where every other region starts.
+    - The block bitmap has one bit per file system block, set when in use.
+    - The inode table is an array of INODE_SIZE byte records; an inode's
+      number is its index in the table. Slot 0 is never used.
+    - There are two copies of the bitmap and the inode table. The
+      superblock's generation picks the live one (generation % 2); a sync
+      writes the other one and then flips the generation, so a crash leaves
+      either the old or the new tree, never a mix of both.
+    - Data blocks hold file content, directory entries and extent overflow
+      blocks. A file's blocks are described by extents (runs of contiguous
+      blocks); up to INLINE_EXTENTS live in the inode record, larger lists
+      live in a chain of overflow blocks.
+    - The extended attributes of an inode are encoded as one list. Up to
+      INLINE_XATTR_SIZE bytes of it live in the inode record; a longer list
+      lives in a chain of spill blocks (FEATURE_XATTR).
+
+    All integers are little endian.
+ ************************************************************************/
+
+use crate::error::{FsError, FsResult};
+use std::cmp;
+use time::Timespec;
+
+pub const BLOCK_SIZE: usize = 4096;
+pub const MAGIC: u64 = 0x4253_7366_7473_7572; // "rustfsSB"
+pub const VERSION: u32 = 1;
+
+/// Inode records may carry extended attributes. Older versions would drop
+/// them, so they must not mount such a file system.
+pub const FEATURE_XATTR: u64 = 1;
+
+/// Feature flags understood by this version. Mounting a file system with
+/// any other flag set fails.
+pub const FEATURES_SUPPORTED: u64 = FEATURE_XATTR;
+
+pub const INODE_SIZE: usize = 256;
+pub const INODES_PER_BLOCK: usize = BLOCK_SIZE / INODE_SIZE;
+pub const ROOT_INO: u64 = 1;
+
+pub const EXTENT_SIZE: usize = 24;
+pub const INLINE_EXTENTS: usize = 5;
+const INLINE_EXTENTS_OFFSET: usize = 128;
+const OVERFLOW_HEADER: usize = 16;
+pub const EXTENTS_PER_BLOCK: usize = (BLOCK_SIZE - OVERFLOW_HEADER) / EXTENT_SIZE;
+
+pub const INLINE_XATTR_SIZE: usize = 26;
+const INLINE_XATTR_OFFSET: usize = 102;
+const XATTR_BLOCK_OFFSET: usize = 248;
+const SPILL_HEADER: usize = 16;
+pub const XATTR_BYTES_PER_BLOCK: usize = BLOCK_SIZE - SPILL_HEADER;
+const XATTR_HEADER: usize = 5;
+
+pub const KIND_FREE: u8 = 0;
+pub const KIND_FILE: u8 = 1;
+pub const KIND_DIR: u8 = 2;
+pub const KIND_SYMLINK: u8 = 3;
+
+#[inline(always)]
+pub fn put_u16(buf: &mut [u8], off: usize, v: u16) {
+    buf[off..(off + 2)].copy_from_slice(&v.to_le_bytes());
+}
+
+#[inline(always)]
+pub fn put_u32(buf: &mut [u8], off: usize, v: u32) {
+    buf[off..(off + 4)].copy_from_slice(&v.to_le_bytes());
+}
+
+#[inline(always)]
+pub fn put_u64(buf: &mut [u8], off: usize, v: u64) {
+    buf[off..(off + 8)].copy_from_slice(&v.to_le_bytes());
+}
+
+#[inline(always)]
+pub fn get_u16(buf: &[u8], off: usize) -> u16 {
+    let mut b = [0u8; 2];
+    b.copy_from_slice(&buf[off..(off + 2)]);
+    u16::from_le_bytes(b)
+}
+
+#[inline(always)]
+pub fn get_u32(buf: &[u8], off: usize) -> u32 {
+    let mut b = [0u8; 4];
+    b.copy_from_slice(&buf[off..(off + 4)]);
+    u32::from_le_bytes(b)
+}
+
+#[inline(always)]
+pub fn get_u64(buf: &[u8], off: usize) -> u64 {
+    let mut b = [0u8; 8];
+    b.copy_from_slice(&buf[off..(off + 8)]);
+    u64::from_le_bytes(b)
+}
+
+fn put_time(buf: &mut [u8], off: usize, t: Timespec) {
+    put_u64(buf, off, t.sec as u64);
+    put_u32(buf, off + 8, t.nsec as u32);
+}
+
+fn get_time(buf: &[u8], off: usize) -> Timespec {
+    Timespec::new(get_u64(buf, off) as i64, get_u32(buf, off + 8) as i32)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Superblock {
+    pub magic: u64,
+    pub version: u32,
+    pub block_size: u32,
+    pub features: u64,
+    pub uuid: [u8; 16],
+    pub num_blocks: u64,
+    pub inode_count: u64,
+    pub bitmap_start: u64,
+    pub bitmap_blocks: u64,
+    pub inode_table_start: u64,
+    pub inode_table_blocks: u64,
+    pub data_start: u64,
+    pub root_ino: u64,
+    pub free_blocks: u64,
+    pub generation: u64,
+}
+
+impl Superblock {
+    /// Lays out a new file system of `num_blocks` blocks with room for
+    /// `inode_count` inodes.
+    pub fn new(num_blocks: u64, inode_count: u64, uuid: [u8; 16]) -> FsResult<Superblock> {
+        let bits_per_block = (BLOCK_SIZE * 8) as u64;
+        let bitmap_blocks = (num_blocks + bits_per_block - 1) / bits_per_block;
+        // Slot 0 is reserved, so the table has inode_count + 1 slots
+        let inode_table_blocks = (inode_count + 1 + INODES_PER_BLOCK as u64 - 1)
+            / INODES_PER_BLOCK as u64;
+        let data_start = 1 + 2 * (bitmap_blocks + inode_table_blocks);
+        if data_start >= num_blocks { return Err(FsError::ENOSPC); }
+
+        Ok(Superblock {
+            magic: MAGIC,
+            version: VERSION,
+            block_size: BLOCK_SIZE as u32,
+            features: FEATURE_XATTR,
+            uuid: uuid,
+            num_blocks: num_blocks,
+            inode_count: inode_count,
+            bitmap_start: 1,
+            bitmap_blocks: bitmap_blocks,
+            inode_table_start: 1 + 2 * bitmap_blocks,
+            inode_table_blocks: inode_table_blocks,
+            data_start: data_start,
+            root_ino: ROOT_INO,
+            free_blocks: num_blocks - data_start,
+            generation: 0,
+        })
+    }
+
+    /// The copy of the bitmap and the inode table the superblock points at.
+    pub fn slot(&self) -> u64 {
+        self.generation % 2
+    }
+
+    pub fn bitmap_at(&self, slot: u64) -> u64 {
+        self.bitmap_start + slot * self.bitmap_blocks
+    }
+
+    pub fn inode_table_at(&self, slot: u64) -> u64 {
+        self.inode_table_start + slot * self.inode_table_blocks
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        put_u64(&mut buf, 0, self.magic);
+        put_u32(&mut buf, 8, self.version);
+        put_u32(&mut buf, 12, self.block_size);
+        put_u64(&mut buf, 16, self.features);
+        buf[24..40].copy_from_slice(&self.uuid);
+        put_u64(&mut buf, 40, self.num_blocks);
+        put_u64(&mut buf, 48, self.inode_count);
+        put_u64(&mut buf, 56, self.bitmap_start);
+        put_u64(&mut buf, 64, self.bitmap_blocks);
+        put_u64(&mut buf, 72, self.inode_table_start);
+        put_u64(&mut buf, 80, self.inode_table_blocks);
+        put_u64(&mut buf, 88, self.data_start);
+        put_u64(&mut buf, 96, self.root_ino);
+        put_u64(&mut buf, 104, self.free_blocks);
+        put_u64(&mut buf, 112, self.generation);
+        buf
+    }
+
+    /// Decodes and validates a superblock. Fails with EINVAL if `buf` does
+    /// not hold a file system this version can mount.
+    pub fn decode(buf: &[u8]) -> FsResult<Superblock> {
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&buf[24..40]);
+        let sb = Superblock {
+            magic: get_u64(buf, 0),
+            version: get_u32(buf, 8),
+            block_size: get_u32(buf, 12),
+            features: get_u64(buf, 16),
+            uuid: uuid,
+            num_blocks: get_u64(buf, 40),
+            inode_count: get_u64(buf, 48),
+            bitmap_start: get_u64(buf, 56),
+            bitmap_blocks: get_u64(buf, 64),
+            inode_table_start: get_u64(buf, 72),
+            inode_table_blocks: get_u64(buf, 80),
+            data_start: get_u64(buf, 88),
+            root_ino: get_u64(buf, 96),
+            free_blocks: get_u64(buf, 104),
+            generation: get_u64(buf, 112),
+        };
+
+        if sb.magic != MAGIC || sb.version != VERSION || sb.block_size as usize != BLOCK_SIZE {
+            return Err(FsError::EINVAL);
+        }
+        if sb.features & !FEATURES_SUPPORTED != 0 {
+            return Err(FsError::EINVAL);
+        }
+        if sb.data_start >= sb.num_blocks || sb.root_ino == 0 || sb.root_ino > sb.inode_count {
+            return Err(FsError::EINVAL);
+        }
+        // The regions must be large enough and must not overlap
+        let bits = sb.bitmap_blocks.checked_mul((BLOCK_SIZE * 8) as u64);
+        let slots = sb.inode_table_blocks.checked_mul(INODES_PER_BLOCK as u64);
+        if sb.bitmap_start == 0 || bits.map_or(true, |b| b < sb.num_blocks)
+            || slots.map_or(true, |n| n <= sb.inode_count)
+            || sb.inode_table_start < sb.bitmap_start.saturating_add(2 * sb.bitmap_blocks)
+            || sb.data_start < sb.inode_table_start.saturating_add(2 * sb.inode_table_blocks) {
+            return Err(FsError::EINVAL);
+        }
+        Ok(sb)
+    }
+}
+
+/// A run of `len` contiguous blocks starting at block `physical` holding
+/// the file pages starting at page `logical`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub logical: u64,
+    pub physical: u64,
+    pub len: u64,
+}
+
+impl Extent {
+    fn encode(&self, buf: &mut [u8], off: usize) {
+        put_u64(buf, off, self.logical);
+        put_u64(buf, off + 8, self.physical);
+        put_u64(buf, off + 16, self.len);
+    }
+
+    fn decode(buf: &[u8], off: usize) -> Extent {
+        Extent {
+            logical: get_u64(buf, off),
+            physical: get_u64(buf, off + 8),
+            len: get_u64(buf, off + 16),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InodeRecord {
+    pub kind: u8,
+    pub mode: u16, // permission bits
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub access_time: Timespec,
+    pub mod_time: Timespec,
+    pub change_time: Timespec,
+    pub create_time: Timespec,
+    /// Every extent of the inode. Only the first INLINE_EXTENTS are stored
+    /// in the record if they all fit; otherwise all of them go to the chain
+    /// of overflow blocks starting at `extent_block`.
+    pub extents: Vec<Extent>,
+    pub extent_block: u64,
+    /// The extended attributes (see encode_xattrs). Stored in the record if
+    /// they fit in INLINE_XATTR_SIZE bytes; otherwise they go to the chain
+    /// of spill blocks starting at `xattr_block`, and decode leaves this
+    /// empty.
+    pub xattrs: Vec<u8>,
+    pub xattr_block: u64,
+}
+
+impl InodeRecord {
+    pub fn new(kind: u8) -> InodeRecord {
+        let zero = Timespec::new(0, 0);
+        InodeRecord {
+            kind: kind,
+            mode: 0,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            access_time: zero,
+            mod_time: zero,
+            change_time: zero,
+            create_time: zero,
+            extents: Vec::new(),
+            extent_block: 0,
+            xattrs: Vec::new(),
+            xattr_block: 0,
+        }
+    }
+
+    pub fn has_overflow(&self) -> bool {
+        self.extents.len() > INLINE_EXTENTS
+    }
+
+    pub fn has_xattr_spill(&self) -> bool {
+        self.xattrs.len() > INLINE_XATTR_SIZE
+    }
+
+    /// Encodes the record into `buf`, which must be INODE_SIZE bytes. The
+    /// caller is responsible for writing the overflow and spill blocks.
+    pub fn encode(&self, buf: &mut [u8]) {
+        for b in buf.iter_mut() { *b = 0; }
+        buf[0] = self.kind;
+        put_u16(buf, 2, self.mode);
+        put_u32(buf, 4, self.extents.len() as u32);
+        put_u64(buf, 8, self.size);
+        put_time(buf, 16, self.access_time);
+        put_time(buf, 32, self.mod_time);
+        put_time(buf, 48, self.create_time);
+        put_u64(buf, 64, self.extent_block);
+        put_u32(buf, 72, self.nlink);
+        put_u32(buf, 76, self.uid);
+        put_u32(buf, 80, self.gid);
+        put_time(buf, 88, self.change_time);
+        if !self.has_overflow() {
+            for (i, e) in self.extents.iter().enumerate() {
+                e.encode(buf, INLINE_EXTENTS_OFFSET + i * EXTENT_SIZE);
+            }
+        }
+        put_u64(buf, XATTR_BLOCK_OFFSET, self.xattr_block);
+        if !self.has_xattr_spill() {
+            put_u16(buf, INLINE_XATTR_OFFSET - 2, self.xattrs.len() as u16);
+            buf[INLINE_XATTR_OFFSET..(INLINE_XATTR_OFFSET + self.xattrs.len())]
+                .copy_from_slice(&self.xattrs);
+        }
+    }
+
+    /// Decodes a record. Returns the number of extents stored in overflow
+    /// blocks that still have to be read (see decode_overflow). The spill
+    /// blocks of the extended attributes are left to the caller too.
+    pub fn decode(buf: &[u8]) -> (InodeRecord, usize) {
+        let count = get_u32(buf, 4) as usize;
+        let mut record = InodeRecord {
+            kind: buf[0],
+            mode: get_u16(buf, 2),
+            nlink: get_u32(buf, 72),
+            uid: get_u32(buf, 76),
+            gid: get_u32(buf, 80),
+            size: get_u64(buf, 8),
+            access_time: get_time(buf, 16),
+            mod_time: get_time(buf, 32),
+            change_time: get_time(buf, 88),
+            create_time: get_time(buf, 48),
+            extents: Vec::new(),
+            extent_block: get_u64(buf, 64),
+            xattrs: Vec::new(),
+            xattr_block: get_u64(buf, XATTR_BLOCK_OFFSET),
+        };
+
+        let inline = cmp::min(get_u16(buf, INLINE_XATTR_OFFSET - 2) as usize, INLINE_XATTR_SIZE);
+        if record.xattr_block == 0 {
+            record.xattrs = buf[INLINE_XATTR_OFFSET..(INLINE_XATTR_OFFSET + inline)].to_vec();
+        }
+
+        if count <= INLINE_EXTENTS {
+            for i in 0..count {
+                record.extents.push(Extent::decode(buf, INLINE_EXTENTS_OFFSET + i * EXTENT_SIZE));
+            }
+            (record, 0)
+        } else {
+            (record, count)
+        }
+    }
+}
+
+/// Encodes one overflow block holding `extents` (at most EXTENTS_PER_BLOCK)
+/// and pointing at the block `next` (0 ends the chain).
+pub fn encode_overflow(extents: &[Extent], next: u64) -> Vec<u8> {
+    let mut buf = vec![0u8; BLOCK_SIZE];
+    put_u64(&mut buf, 0, next);
+    put_u64(&mut buf, 8, extents.len() as u64);
+    for (i, e) in extents.iter().enumerate() {
+        e.encode(&mut buf, OVERFLOW_HEADER + i * EXTENT_SIZE);
+    }
+    buf
+}
+
+/// Decodes an overflow block into `extents`, returning the next block.
+pub fn decode_overflow(buf: &[u8], extents: &mut Vec<Extent>) -> FsResult<u64> {
+    let count = get_u64(buf, 8) as usize;
+    if count > EXTENTS_PER_BLOCK { return Err(FsError::EIO); }
+    for i in 0..count {
+        extents.push(Extent::decode(buf, OVERFLOW_HEADER + i * EXTENT_SIZE));
+    }
+    Ok(get_u64(buf, 0))
+}
+
+/// Encodes one spill block holding `data` (at most XATTR_BYTES_PER_BLOCK
+/// bytes of an encoded attribute list) and pointing at the block `next` (0
+/// ends the chain).
+pub fn encode_spill(data: &[u8], next: u64) -> Vec<u8> {
+    let mut buf = vec![0u8; BLOCK_SIZE];
+    put_u64(&mut buf, 0, next);
+    put_u64(&mut buf, 8, data.len() as u64);
+    buf[SPILL_HEADER..(SPILL_HEADER + data.len())].copy_from_slice(data);
+    buf
+}
+
+/// Decodes a spill block, appending its data to `data` and returning the
+/// next block.
+pub fn decode_spill(buf: &[u8], data: &mut Vec<u8>) -> FsResult<u64> {
+    let len = get_u64(buf, 8) as usize;
+    if len > XATTR_BYTES_PER_BLOCK { return Err(FsError::EIO); }
+    data.extend_from_slice(&buf[SPILL_HEADER..(SPILL_HEADER + len)]);
+    Ok(get_u64(buf, 0))
+}
+
+/// Encodes extended attributes as a list of name length (one byte), value
+/// length (four bytes), name and value.
+pub fn encode_xattrs<'a, I>(xattrs: I) -> Vec<u8>
+    where I: Iterator<Item = (&'a [u8], &'a [u8])>
+{
+    let mut buf = Vec::new();
+    for (name, value) in xattrs {
+        let mut header = [0u8; XATTR_HEADER];
+        header[0] = name.len() as u8;
+        put_u32(&mut header, 1, value.len() as u32);
+        buf.extend_from_slice(&header);
+        buf.extend_from_slice(name);
+        buf.extend_from_slice(value);
+    }
+    buf
+}
+
+/// Space one attribute takes in an encoded list.
+pub fn xattr_size(name: &[u8], value: &[u8]) -> usize {
+    XATTR_HEADER + name.len() + value.len()
+}
+
+pub fn decode_xattrs(buf: &[u8]) -> FsResult<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut xattrs = Vec::new();
+    let mut off = 0;
+    while off < buf.len() {
+        if off + XATTR_HEADER > buf.len() { return Err(FsError::EIO); }
+        let name_len = buf[off] as usize;
+        let value_len = get_u32(buf, off + 1) as usize;
+        off += XATTR_HEADER;
+        if buf.len() - off < name_len + value_len { return Err(FsError::EIO); }
+        let name = buf[off..(off + name_len)].to_vec();
+        let value = buf[(off + name_len)..(off + name_len + value_len)].to_vec();
+        xattrs.push((name, value));
+        off += name_len + value_len;
+    }
+    Ok(xattrs)
+}
+
+/// A directory entry as stored in a directory's data blocks: the inode
+/// number, the kind of the inode and the name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntryRecord {
+    pub ino: u64,
+    pub kind: u8,
+    pub name: Vec<u8>,
+}
+
+const DIR_ENTRY_HEADER: usize = 11;
+
+pub fn encode_dir_entries(entries: &[DirEntryRecord]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for entry in entries {
+        let mut header = [0u8; DIR_ENTRY_HEADER];
+        put_u64(&mut header, 0, entry.ino);
+        header[8] = entry.kind;
+        put_u16(&mut header, 9, entry.name.len() as u16);
+        buf.extend_from_slice(&header);
+        buf.extend_from_slice(&entry.name);
+    }
+    buf
+}
+
+pub fn decode_dir_entries(buf: &[u8]) -> FsResult<Vec<DirEntryRecord>> {
+    let mut entries = Vec::new();
+    let mut off = 0;
+    while off < buf.len() {
+        if off + DIR_ENTRY_HEADER > buf.len() { return Err(FsError::EIO); }
+        let ino = get_u64(buf, off);
+        let kind = buf[off + 8];
+        let len = get_u16(buf, off + 9) as usize;
+        off += DIR_ENTRY_HEADER;
+        if off + len > buf.len() { return Err(FsError::EIO); }
+        let name = buf[off..(off + len)].to_vec();
+        entries.push(DirEntryRecord { ino: ino, kind: kind, name: name });
+        off += len;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_superblock_roundtrip() {
+        let sb = Superblock::new(1 << 20, 1 << 12, [7u8; 16]).unwrap();
+        assert_eq!(sb.bitmap_blocks, 32);
+        assert_eq!(sb.inode_table_start, 65);
+        assert_eq!(sb.inode_table_at(1), 65 + sb.inode_table_blocks);
+        assert_eq!(sb.data_start, 65 + 2 * sb.inode_table_blocks);
+        assert_eq!(Superblock::decode(&sb.encode()), Ok(sb.clone()));
+
+        let mut bad = sb.encode();
+        bad[0] ^= 1;
+        assert_eq!(Superblock::decode(&bad), Err(FsError::EINVAL));
+
+        let mut overlap = sb.clone();
+        overlap.data_start -= 1;
+        assert_eq!(Superblock::decode(&overlap.encode()), Err(FsError::EINVAL));
+
+        let mut future = sb.clone();
+        future.features = 1 << 63;
+        assert_eq!(Superblock::decode(&future.encode()), Err(FsError::EINVAL));
+
+        assert_eq!(Superblock::new(4, 1 << 12, [0u8; 16]), Err(FsError::ENOSPC));
+    }
+
+    #[test]
+    fn test_inode_record_roundtrip() {
+        let mut record = InodeRecord::new(KIND_FILE);
+        record.size = 12345;
+        record.nlink = 3;
+        record.mode = 0o4755;
+        record.uid = 1000;
+        record.gid = 100;
+        record.mod_time = Timespec::new(100, 200);
+        record.change_time = Timespec::new(300, 400);
+        for i in 0..INLINE_EXTENTS as u64 {
+            record.extents.push(Extent { logical: i * 10, physical: 100 + i, len: 1 });
+        }
+
+        let mut buf = [0u8; INODE_SIZE];
+        record.encode(&mut buf);
+        assert_eq!(InodeRecord::decode(&buf), (record.clone(), 0));
+
+        // One more extent and they all move out of the record
+        record.extents.push(Extent { logical: 99, physical: 999, len: 3 });
+        record.extent_block = 42;
+        record.encode(&mut buf);
+        let (decoded, overflow) = InodeRecord::decode(&buf);
+        assert_eq!(overflow, INLINE_EXTENTS + 1);
+        assert_eq!(decoded.extent_block, 42);
+        assert!(decoded.extents.is_empty());
+
+        let block = encode_overflow(&record.extents, 0);
+        let mut extents = Vec::new();
+        assert_eq!(decode_overflow(&block, &mut extents), Ok(0));
+        assert_eq!(extents, record.extents);
+    }
+
+    #[test]
+    fn test_xattrs_roundtrip() {
+        let small: Vec<(&[u8], &[u8])> = vec![(b"user.a", b"1"), (b"trusted.b", b"")];
+        let buf = encode_xattrs(small.iter().cloned());
+        assert_eq!(buf.len(), xattr_size(b"user.a", b"1") + xattr_size(b"trusted.b", b""));
+        let decoded = decode_xattrs(&buf).unwrap();
+        assert_eq!(decoded[0], (b"user.a".to_vec(), b"1".to_vec()));
+        assert_eq!(decoded[1], (b"trusted.b".to_vec(), Vec::new()));
+        assert_eq!(decode_xattrs(&buf[..buf.len() - 1]), Err(FsError::EIO));
+
+        // A short list stays in the record, along with the extents
+        let mut record = InodeRecord::new(KIND_FILE);
+        record.extents.push(Extent { logical: 0, physical: 100, len: 5 });
+        record.xattrs = buf;
+        assert!(!record.has_xattr_spill());
+        let mut inode = [0u8; INODE_SIZE];
+        record.encode(&mut inode);
+        assert_eq!(InodeRecord::decode(&inode), (record.clone(), 0));
+
+        // A long one is left to the spill blocks
+        record.xattrs = encode_xattrs(vec![(&b"user.hash"[..], &[7u8; 32][..])].into_iter());
+        record.xattr_block = 42;
+        assert!(record.has_xattr_spill());
+        record.encode(&mut inode);
+        let (decoded, _) = InodeRecord::decode(&inode);
+        assert_eq!(decoded.xattr_block, 42);
+        assert!(decoded.xattrs.is_empty());
+
+        let mut data = Vec::new();
+        assert_eq!(decode_spill(&encode_spill(&record.xattrs, 43), &mut data), Ok(43));
+        assert_eq!(data, record.xattrs);
+    }
+
+    #[test]
+    fn test_dir_entries_roundtrip() {
+        let entries = vec![
+            DirEntryRecord { ino: 2, kind: KIND_FILE, name: b"a.txt".to_vec() },
+            DirEntryRecord { ino: 3, kind: KIND_DIR, name: b"sub".to_vec() },
+        ];
+        let buf = encode_dir_entries(&entries);
+        assert_eq!(decode_dir_entries(&buf), Ok(entries));
+        assert_eq!(decode_dir_entries(&buf[..5]), Err(FsError::EIO));
+    }
+}
@@ -8,20 +8,47 @@
     Fill in the purpose of this source file here.
  ************************************************************************/
 
+#![feature(nll)]
+#![feature(async_await, await_macro, futures_api)]
+
+extern crate parking_lot;
 extern crate time;
 
+mod alloc;
+mod cred;
+mod device;
 mod directory;
+mod disk;
+mod error;
+mod extent;
+mod fdtable;
 mod file;
+mod fs;
 mod inode;
+mod layout;
+mod path;
+mod view;
+mod xattr;
 
-use file::{File, FileHandle};
-use file::File::{EmptyFile, DataFile, Directory};
-use std::rc::Rc;
-use std::cell::{RefCell};
-use std::collections::HashMap;
-use directory::DirectoryHandle;
-pub use file::Whence;
-pub use inode::Inode;
+use crate::file::{File, FileHandle};
+use crate::file::File::{DataFile, Directory, Symlink};
+use std::sync::Arc;
+use crate::directory::{DirPair, DirectoryHandle};
+use crate::fdtable::FdTable;
+use crate::inode::Attr;
+use crate::xattr::Namespace;
+pub use crate::cred::{Cred, F_OK, R_OK, S_ISGID, S_ISUID, S_ISVTX, W_OK, X_OK};
+pub use crate::device::{BlockDevice, FileDevice, IoFuture, MemDevice, SpdkDevice};
+pub use crate::disk::mkfs;
+pub use crate::error::{FsError, FsResult};
+pub use crate::fdtable::DEFAULT_FD_LIMIT;
+pub use crate::file::Whence;
+pub use crate::fs::FileSystem;
+pub use crate::inode::{AtimePolicy, Inode};
+pub use crate::path::{NAME_MAX, PATH_MAX};
+pub use crate::view::{PageView, PageViewMut};
+pub use crate::xattr::{XATTR_CREATE, XATTR_LIST_MAX, XATTR_NAME_MAX, XATTR_REPLACE, XATTR_SIZE_MAX};
+pub use time::Timespec;
 
 pub type FileDescriptor = isize;
 
@@ -31,81 +58,951 @@ pub const O_RDWR: u32 =     (1 << 2);
 pub const O_NONBLOCK: u32 = (1 << 3);
 pub const O_APPEND: u32 =   (1 << 4);
 pub const O_CREAT: u32 =    (1 << 5);
+pub const O_TRUNC: u32 =    (1 << 6);
+pub const O_EXCL: u32 =     (1 << 7);
+pub const O_NOFOLLOW: u32 = (1 << 8);
+/// The access mode bits of the open flags.
+pub const O_ACCMODE: u32 = O_RDONLY | O_WRONLY | O_RDWR;
+
+/// Commands for Proc::fcntl.
+pub const F_DUPFD: u32 = 0;
+pub const F_GETFL: u32 = 3;
+pub const F_SETFL: u32 = 4;
+
+/// Modes for Proc::fallocate.
+pub const FALLOC_FL_KEEP_SIZE: u32 = 0x01;
+pub const FALLOC_FL_PUNCH_HOLE: u32 = 0x02;
+pub const FALLOC_FL_ZERO_RANGE: u32 = 0x10;
+
+/// Block usage as reported by Proc::statfs. Blocks freed since the last sync
+/// count as free although they are only reused after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatFs {
+    pub block_size: usize,
+    pub blocks: u64,
+    pub free_blocks: u64,
+    pub used_blocks: u64
+}
+
+pub const S_IFMT: u32 =   0o170000;
+pub const S_IFREG: u32 =  0o100000;
+pub const S_IFDIR: u32 =  0o040000;
+pub const S_IFLNK: u32 =  0o120000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink
+}
+
+impl FileType {
+    /// The S_IF* bits for this type, as found in Stat::mode.
+    pub fn mode_bits(&self) -> u32 {
+        match *self {
+            FileType::Regular => S_IFREG,
+            FileType::Directory => S_IFDIR,
+            FileType::Symlink => S_IFLNK
+        }
+    }
+}
+
+/// File metadata as reported by Proc::stat, lstat and fstat.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stat {
+    pub ino: u64,
+    pub file_type: FileType,
+    pub mode: u32, // file type (S_IF*) and permission bits, like st_mode
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub blocks: u64, // 512 byte units holding data
+    pub block_size: usize, // preferred I/O size
+    pub atime: Timespec,
+    pub mtime: Timespec,
+    pub ctime: Timespec,
+    pub crtime: Timespec
+}
+
+/// One entry of a directory listing, see Proc::readdir.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: Vec<u8>,
+    pub ino: u64,
+    pub file_type: FileType,
+    pub cookie: u64 // pass to seekdir to go on after this entry
+}
 
-pub struct Proc<'r> {
-    cwd: File<'r>,
-    fd_table: HashMap<FileDescriptor, FileHandle<'r>>,
-    fds: Vec<FileDescriptor>
+/// A process's view of a FileSystem: its open files, working directory
+/// and credentials. Any number of Procs can work on the same FileSystem,
+/// each with its own working directory and descriptor table, from any
+/// thread. Paths are byte strings and can be passed as anything
+/// that is AsRef<[u8]> (&str, String, &[u8], Vec<u8>); each name in them is
+/// at most NAME_MAX bytes and holds no NUL.
+pub struct Proc {
+    fs: Arc<FileSystem>,
+    root: File,
+    cwd: File,
+    fds: FdTable,
+    atime: AtimePolicy,
+    cred: Cred
 }
 
-impl<'r> Proc<'r> {
-    pub fn new() -> Proc<'r> {
+impl Proc {
+    /// A process on a new, empty FileSystem held in memory only.
+    pub fn new() -> Proc {
+        Proc::with_fs(FileSystem::new())
+    }
+
+    /// A process on `fs`, working in its root directory with no open files.
+    pub fn with_fs(fs: Arc<FileSystem>) -> Proc {
+        let root = fs.root().clone();
         Proc {
-            cwd: File::new_dir(None),
-            fd_table: HashMap::new(),
-            fds: (0..(256 - 2)).map(|i| 256 - i).collect(),
+            fs: fs,
+            cwd: root.clone(),
+            root: root,
+            fds: FdTable::new(DEFAULT_FD_LIMIT),
+            atime: AtimePolicy::Relatime,
+            cred: Cred::root()
         }
     }
 
+    /// The file system the process works on.
+    pub fn fs(&self) -> &Arc<FileSystem> {
+        &self.fs
+    }
+
+    /// The credentials the process acts with. A new process is the
+    /// superuser with a umask of 0o022.
+    pub fn cred(&self) -> &Cred {
+        &self.cred
+    }
+
+    pub fn set_cred(&mut self, cred: Cred) {
+        self.cred = cred;
+    }
+
+    /// Sets the umask and returns the previous one, like umask(2).
+    pub fn umask(&mut self, mask: u16) -> u16 {
+        let old = self.cred.umask;
+        self.cred.umask = mask & 0o777;
+        old
+    }
+
+    /// Sets when reads update the access time of a file. The default is
+    /// AtimePolicy::Relatime.
+    pub fn set_atime_policy(&mut self, policy: AtimePolicy) {
+        self.atime = policy;
+    }
+
+    /// How many descriptors the process may have open at once, like
+    /// RLIMIT_NOFILE. DEFAULT_FD_LIMIT for a new process.
+    pub fn fd_limit(&self) -> usize {
+        self.fds.limit()
+    }
+
+    /// Sets fd_limit. Descriptors already open at or above the new limit
+    /// stay open, but no new ones are handed out there.
+    pub fn set_fd_limit(&mut self, limit: usize) {
+        self.fds.set_limit(limit);
+    }
+
+    /// Mounts the file system on `device` (see FileSystem::mount, which
+    /// leaves the data of files on the device until it is used) and returns
+    /// a process on it. More can join with `Proc::with_fs(p.fs().clone())`.
+    pub fn mount(device: Box<dyn BlockDevice>) -> IoFuture<'static, Proc> {
+        Box::pin(async move {
+            Ok(Proc::with_fs(await!(FileSystem::mount(device))?))
+        })
+    }
+
+    /// Writes the file system back to its device, see FileSystem::sync.
+    pub fn sync<'a>(&'a self) -> IoFuture<'a, ()> {
+        self.fs.sync()
+    }
+
+    /// Makes the data and the metadata of the open file `fd` durable, see
+    /// FileSystem::sync_file. As metadata is written with the whole tree,
+    /// this is as costly as syncfs.
+    pub fn fsync<'a>(&'a self, fd: FileDescriptor) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            let handle = self.handle(fd)?;
+            await!(self.fs.sync_file(handle.file(), false))
+        })
+    }
+
+    /// Makes the data of the open file `fd` durable, along with what is
+    /// needed to read it back (its size and blocks) but not its timestamps.
+    /// Overwriting a file in place and calling this only writes the pages
+    /// that changed.
+    pub fn fdatasync<'a>(&'a self, fd: FileDescriptor) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            let handle = self.handle(fd)?;
+            await!(self.fs.sync_file(handle.file(), true))
+        })
+    }
+
+    /// Writes back the whole file system `fd` is on, like sync.
+    pub fn syncfs<'a>(&'a self, fd: FileDescriptor) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            self.handle(fd)?;
+            await!(self.fs.sync())
+        })
+    }
+
+    /// Block usage of the device, see FileSystem::statfs.
+    pub fn statfs(&self) -> Option<StatFs> {
+        self.fs.statfs()
+    }
+
+    /// Drops the open file descriptors, then unmounts the file system (see
+    /// FileSystem::unmount). Fails with EBUSY if other processes still
+    /// work on it.
+    pub fn unmount(self) -> IoFuture<'static, Box<dyn BlockDevice>> {
+        let fs = self.fs.clone();
+        drop(self);
+        FileSystem::unmount(fs)
+    }
+
+    /// Gives `handle` the lowest free descriptor.
+    fn install(&mut self, handle: FileHandle) -> FsResult<FileDescriptor> {
+        let handle = Arc::new(handle);
+        self.fs.track(&handle);
+        self.fds.insert(handle)
+    }
+
     #[inline(always)]
-    fn extract_fd(fd_opt: &Option<FileDescriptor>) -> FileDescriptor {
-        match fd_opt {
-            &Some(fd) => fd,
-            &None => panic!("Error in FD allocation.")
-        }
-    }
-
-    pub fn open(&mut self, path: &'r str, flags: u32) -> FileDescriptor {
-        let lookup = self.cwd.get(path);
-        let file = match lookup {
-            Some(f) => f,
-            None => {
-                if (flags & O_CREAT) != 0 {
-                    // FIXME: Fetch from allocator
-                    let rcinode = Rc::new(RefCell::new(Box::new(Inode::new())));
-                    let file = File::new_data_file(rcinode);
-                    self.cwd.insert(path, file.clone());
-                    file
-                } else {
-                    EmptyFile
+    fn handle(&self, fd: FileDescriptor) -> FsResult<&FileHandle> {
+        Ok(&**self.fds.get(fd)?)
+    }
+
+    #[inline(always)]
+    fn resolve(&self, path: &[u8]) -> FsResult<File> {
+        path::resolve(&self.root, &self.cwd, path, &self.cred)
+    }
+
+    #[inline(always)]
+    fn resolve_nofollow(&self, path: &[u8]) -> FsResult<File> {
+        path::resolve_nofollow(&self.root, &self.cwd, path, &self.cred)
+    }
+
+    #[inline(always)]
+    fn resolve_parent<'p>(&self, path: &'p [u8]) -> FsResult<(File, &'p [u8])> {
+        path::resolve_parent(&self.root, &self.cwd, path, &self.cred)
+    }
+
+    #[inline(always)]
+    fn resolve_create(&self, path: &[u8]) -> FsResult<(File, Vec<u8>)> {
+        path::resolve_create(&self.root, &self.cwd, path, &self.cred)
+    }
+
+    /// Adding or removing a name in `dir` takes write and search permission
+    /// on it.
+    fn check_dir_write(&self, dir: &File) -> FsResult<()> {
+        self.cred.check(&dir.attr(), true, W_OK | X_OK)
+    }
+
+    /// In a sticky directory (with attributes `dir_attr`) only the owner of
+    /// `victim`, the owner of the directory and the superuser may remove or
+    /// replace `victim`.
+    fn check_sticky(&self, dir_attr: &Attr, victim: &File) -> FsResult<()> {
+        if (dir_attr.mode & S_ISVTX) == 0 || self.cred.owns(dir_attr) || self.cred.owns(&victim.attr()) {
+            Ok(())
+        } else {
+            Err(FsError::EPERM)
+        }
+    }
+
+    /// Sets up the owner and mode of a file about to be created in `dir`:
+    /// the caller owns it and `mode` is masked with the umask. In a set-group-ID
+    /// directory the group comes from the directory instead, and new
+    /// directories inherit the bit.
+    fn init_attr(&self, dir: &File, file: &File, mode: u16) {
+        let parent = dir.attr();
+        let mut mode = mode & !self.cred.umask & 0o7777;
+        let gid = if (parent.mode & S_ISGID) != 0 {
+            if file.is_dir() { mode |= S_ISGID; }
+            parent.gid
+        } else {
+            self.cred.gid
+        };
+        file.update_attr(|attr| {
+            attr.mode = mode;
+            attr.uid = self.cred.uid;
+            attr.gid = gid;
+        });
+    }
+
+    /// Opens the file at `path`. `flags` holds exactly one of O_RDONLY,
+    /// O_WRONLY and O_RDWR, plus:
+    ///
+    /// - O_CREAT: create the file if it does not exist; for a dangling
+    ///   symbolic link, that is its target
+    /// - O_EXCL: with O_CREAT, fail with EEXIST if the file exists
+    /// - O_TRUNC: empty the file; only done if it is opened for writing
+    /// - O_APPEND: every write goes to the end of the file
+    /// - O_NOFOLLOW: fail with ELOOP if `path` names a symbolic link
+    ///
+    /// Symbolic links are otherwise followed. Files are created with mode
+    /// 0o666 (less the umask), see open_mode.
+    pub fn open<P: AsRef<[u8]>>(&mut self, path: P, flags: u32) -> FsResult<FileDescriptor> {
+        self.open_mode(path, flags, 0o666)
+    }
+
+    /// Same as open, giving the permission bits of a file created by
+    /// O_CREAT. The umask is cleared from them. An existing file must allow
+    /// the access asked for (EACCES); a newly created one always does.
+    pub fn open_mode<P: AsRef<[u8]>>(&mut self, path: P, flags: u32, mode: u16) -> FsResult<FileDescriptor> {
+        let path = path.as_ref();
+        let access = flags & (O_RDONLY | O_WRONLY | O_RDWR);
+        if access.count_ones() != 1 { return Err(FsError::EINVAL); }
+        // Nothing gets created or truncated if there is no fd to hand out
+        if !self.fds.has_free() { return Err(FsError::EMFILE); }
+
+        let fs = self.fs.clone();
+        let _names = if (flags & O_CREAT) != 0 { Some(fs.names_lock()) } else { None };
+        let mut raced = false;
+        let file = loop {
+            let lookup = if (flags & O_NOFOLLOW) != 0 {
+                self.resolve_nofollow(path)
+            } else {
+                self.resolve(path)
+            };
+            match lookup {
+                Ok(_) if (flags & O_CREAT) != 0 && (flags & O_EXCL) != 0 => return Err(FsError::EEXIST),
+                Ok(f) => {
+                    let want = match access {
+                        O_RDONLY => R_OK,
+                        O_WRONLY => W_OK,
+                        _ => R_OK | W_OK
+                    };
+                    if !f.is_dir() { self.cred.check(&f.attr(), false, want)?; }
+                    break f;
                 }
+                Err(FsError::ENOENT) if (flags & O_CREAT) != 0 => {
+                    // A dangling link gets its target created, unless
+                    // O_EXCL, which never goes through a link
+                    let (mut dir, name) = if (flags & O_EXCL) != 0 {
+                        self.resolve_parent(path).map(|(dir, name)| (dir, name.to_vec()))?
+                    } else {
+                        self.resolve_create(path)?
+                    };
+                    self.check_dir_write(&dir)?;
+                    let file = File::new_data_file(self.fs.new_inode()?);
+                    self.init_attr(&dir, &file, mode);
+                    match dir.insert(&name, file.clone()) {
+                        // Another process created it in the meantime: open
+                        // that. The lookup finds it now, so once is enough.
+                        Err(FsError::EEXIST) if (flags & O_EXCL) == 0 && !raced => {
+                            raced = true;
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                        Ok(()) => break file
+                    }
+                }
+                Err(e) => return Err(e)
             }
         };
 
         match file {
             DataFile(_) => {
-                let fd = Proc::extract_fd(&self.fds.pop());
-                let handle = FileHandle::new(file);
-                self.fd_table.insert(fd, handle);
-                fd
+                let handle = FileHandle::new(file, flags);
+                if (flags & O_TRUNC) != 0 && handle.writable() {
+                    // Keeps nothing, so there is nothing to read in first
+                    handle.file().get_inode_rc()?.write().truncate(0)?;
+                }
+                self.install(handle)
+            }
+            Directory(_) => Err(FsError::EISDIR),
+            Symlink(_) => Err(FsError::ELOOP)
+        }
+    }
+
+    /// Reads from `fd` at its offset, see read(2). Like every call that
+    /// reads or changes the data of a file, this is a future: pages of a
+    /// mounted file are read from the device the first time they are used.
+    pub fn read<'a>(&'a self, fd: FileDescriptor, dst: &'a mut [u8]) -> IoFuture<'a, usize> {
+        Box::pin(async move { await!(self.handle(fd)?.read(&self.fs, dst, self.atime)) })
+    }
+
+    pub fn write<'a>(&'a mut self, fd: FileDescriptor, src: &'a [u8]) -> IoFuture<'a, usize> {
+        Box::pin(async move { await!(self.handle(fd)?.write(&self.fs, src)) })
+    }
+
+    /// Reads from `fd` at `offset`, see pread(2). The offset of `fd` is
+    /// neither used nor changed.
+    pub fn pread<'a>(&'a self, fd: FileDescriptor, dst: &'a mut [u8], offset: u64) -> IoFuture<'a, usize> {
+        Box::pin(async move { await!(self.handle(fd)?.read_at(&self.fs, offset, dst, self.atime)) })
+    }
+
+    /// Writes to `fd` at `offset`, see pwrite(2). The offset of `fd` is
+    /// neither used nor changed, even with O_APPEND.
+    pub fn pwrite<'a>(&'a self, fd: FileDescriptor, src: &'a [u8], offset: u64) -> IoFuture<'a, usize> {
+        Box::pin(async move { await!(self.handle(fd)?.write_at(&self.fs, offset, src)) })
+    }
+
+    /// Reads from `fd` into the buffers in `dsts`, filling each before
+    /// moving to the next, see readv(2).
+    pub fn readv<'a, 'b: 'a>(&'a self, fd: FileDescriptor, dsts: &'a mut [&'b mut [u8]]) -> IoFuture<'a, usize> {
+        Box::pin(async move { await!(self.handle(fd)?.readv(&self.fs, dsts, self.atime)) })
+    }
+
+    /// Writes the buffers in `srcs` to `fd` back to back, as a single write,
+    /// see writev(2).
+    pub fn writev<'a, 'b: 'a>(&'a mut self, fd: FileDescriptor, srcs: &'a [&'b [u8]]) -> IoFuture<'a, usize> {
+        Box::pin(async move { await!(self.handle(fd)?.writev(&self.fs, srcs)) })
+    }
+
+    /// Same as readv, but at `offset` like pread.
+    pub fn preadv<'a, 'b: 'a>(&'a self, fd: FileDescriptor, dsts: &'a mut [&'b mut [u8]],
+                              offset: u64) -> IoFuture<'a, usize> {
+        Box::pin(async move { await!(self.handle(fd)?.readv_at(&self.fs, offset, dsts, self.atime)) })
+    }
+
+    /// Same as writev, but at `offset` like pwrite.
+    pub fn pwritev<'a, 'b: 'a>(&'a self, fd: FileDescriptor, srcs: &'a [&'b [u8]],
+                               offset: u64) -> IoFuture<'a, usize> {
+        Box::pin(async move { await!(self.handle(fd)?.writev_at(&self.fs, offset, srcs)) })
+    }
+
+    /// Lends out [offset, offset + len) of the open file `fd` to read in
+    /// place, one page at a time, instead of copying it like pread. The view
+    /// stops at the end of the file and shows the file as it was when map
+    /// returned: it shares the pages, and the file copies a page it changes
+    /// meanwhile. Nothing is locked while the view lives. On SPDK the pages
+    /// are DMA buffers the device reads and writes as they are (see
+    /// view.rs). Fails with EBADF if `fd` is not open for reading and with
+    /// EINVAL if `len` is 0.
+    pub fn map<'a>(&'a self, fd: FileDescriptor, offset: u64, len: u64) -> IoFuture<'a, PageView> {
+        Box::pin(async move { await!(self.handle(fd)?.map(&self.fs, offset, len, self.atime)) })
+    }
+
+    /// Same as map, but to write in place, like pwrite without the copy. The
+    /// file grows to cover the range at once, which reads back as zeros
+    /// where nothing was written before. What is written to the view only
+    /// reaches the file when it is dropped, and then only the bytes the view
+    /// changed: what was written to the range meanwhile stays. Until then,
+    /// shrinking the file or punching a hole in it fails with EBUSY. Fails
+    /// with EBADF if `fd` is not open for writing, with EFBIG and ENOSPC
+    /// like write.
+    pub fn map_mut<'a>(&'a self, fd: FileDescriptor, offset: u64, len: u64) -> IoFuture<'a, PageViewMut> {
+        Box::pin(async move { await!(self.handle(fd)?.map_mut(&self.fs, offset, len)) })
+    }
+
+    pub fn seek(&mut self, fd: FileDescriptor, o: i64, whence: Whence) -> FsResult<u64> {
+        self.handle(fd)?.seek(o, whence)
+    }
+
+    /// Sets the size of the file at `path` to `len`, see ftruncate. Takes
+    /// write permission on the file.
+    pub fn truncate<'a, P: AsRef<[u8]> + 'a>(&'a mut self, path: P, len: u64) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            let path = path.as_ref();
+            let file = self.resolve(path)?;
+            let inode_rc = file.get_inode_rc()?;
+            self.cred.check(&file.attr(), false, W_OK)?;
+            await!(file::truncate(&self.fs, inode_rc, len))
+        })
+    }
+
+    /// Sets the size of the open file `fd` to `len`. Data past `len` is
+    /// dropped; growing the file leaves a hole that reads back as zeros.
+    /// Fails with EINVAL if `fd` is not open for writing.
+    pub fn ftruncate<'a>(&'a mut self, fd: FileDescriptor, len: u64) -> IoFuture<'a, ()> {
+        Box::pin(async move { await!(self.handle(fd)?.truncate(&self.fs, len)) })
+    }
+
+    /// Changes the storage of [offset, offset + len) in the open file `fd`,
+    /// see fallocate(2). `mode` is one of
+    ///
+    /// - 0: give the range device blocks, so that writing it cannot fail
+    ///   with ENOSPC. What the file reads back does not change; the file
+    ///   grows to cover the range.
+    /// - FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE: free the pages of the
+    ///   range and their blocks, which the next sync unmaps on the device.
+    ///   The range reads back as zeros.
+    /// - FALLOC_FL_ZERO_RANGE: make the range read back as zeros, keeping
+    ///   its blocks (or giving it some, as with 0).
+    ///
+    /// FALLOC_FL_KEEP_SIZE can be added to 0 and FALLOC_FL_ZERO_RANGE to
+    /// leave the size alone. Fails with EBADF if `fd` is not open for
+    /// writing, with EINVAL if `len` is 0, with EFBIG if the range ends past
+    /// the largest file size and with EOPNOTSUPP for any other mode.
+    pub fn fallocate<'a>(&'a mut self, fd: FileDescriptor, mode: u32, offset: u64, len: u64) -> IoFuture<'a, ()> {
+        Box::pin(async move { await!(self.handle(fd)?.fallocate(&self.fs, mode, offset, len)) })
+    }
+
+    /// The metadata of the file at `path`, following symbolic links.
+    pub fn stat<P: AsRef<[u8]>>(&self, path: P) -> FsResult<Stat> {
+        let path = path.as_ref();
+        Ok(self.resolve(path)?.stat())
+    }
+
+    /// Same as stat, but describes a symbolic link itself rather than the
+    /// file it points at.
+    pub fn lstat<P: AsRef<[u8]>>(&self, path: P) -> FsResult<Stat> {
+        let path = path.as_ref();
+        Ok(self.resolve_nofollow(path)?.stat())
+    }
+
+    /// The metadata of the open file `fd`.
+    pub fn fstat(&self, fd: FileDescriptor) -> FsResult<Stat> {
+        Ok(self.handle(fd)?.file().stat())
+    }
+
+    /// Sets the permission bits (and the set-ID and sticky bits) of the file
+    /// at `path`. Only its owner and the superuser may (EPERM). The
+    /// set-group-ID bit of a file is dropped if the caller is not in its
+    /// group.
+    pub fn chmod<P: AsRef<[u8]>>(&mut self, path: P, mode: u16) -> FsResult<()> {
+        let path = path.as_ref();
+        let file = self.resolve(path)?;
+        let attr = file.attr();
+        if !self.cred.owns(&attr) { return Err(FsError::EPERM); }
+
+        let mut mode = mode & 0o7777;
+        if !self.cred.is_root() && !file.is_dir() && !self.cred.in_group(attr.gid) {
+            mode &= !S_ISGID;
+        }
+        file.update_attr(|attr| {
+            attr.mode = mode;
+            attr.changed();
+        });
+        Ok(())
+    }
+
+    /// Changes the owner and/or the group of the file at `path`; None leaves
+    /// one as it is. Only the superuser may give a file away; its owner may
+    /// only move it to one of their own groups (EPERM otherwise). The
+    /// set-ID bits of a file are dropped.
+    pub fn chown<P: AsRef<[u8]>>(&mut self, path: P, uid: Option<u32>, gid: Option<u32>) -> FsResult<()> {
+        let path = path.as_ref();
+        let file = self.resolve(path)?;
+        let attr = file.attr();
+        if !self.cred.is_root() {
+            let uid_ok = uid.map_or(true, |uid| uid == attr.uid);
+            let gid_ok = gid.map_or(true, |gid| gid == attr.gid || self.cred.in_group(gid));
+            if self.cred.uid != attr.uid || !uid_ok || !gid_ok { return Err(FsError::EPERM); }
+        }
+
+        let is_dir = file.is_dir();
+        file.update_attr(|attr| {
+            attr.uid = uid.unwrap_or(attr.uid);
+            attr.gid = gid.unwrap_or(attr.gid);
+            if !is_dir { attr.mode &= !(S_ISUID | S_ISGID); }
+            attr.changed();
+        });
+        Ok(())
+    }
+
+    /// Sets the extended attribute `name` of the file at `path` to `value`,
+    /// creating it if needed. With XATTR_CREATE in `flags` it must not exist
+    /// yet (EEXIST), with XATTR_REPLACE it must (ENODATA). user. attributes
+    /// take write permission on the file, trusted. ones the superuser
+    /// (EPERM). Names are at most XATTR_NAME_MAX bytes (ERANGE), values
+    /// XATTR_SIZE_MAX (E2BIG), and all the attributes of a file together
+    /// XATTR_LIST_MAX once encoded (ENOSPC).
+    pub fn setxattr<P: AsRef<[u8]>>(&mut self, path: P, name: &[u8], value: &[u8],
+                                    flags: u32) -> FsResult<()> {
+        let path = path.as_ref();
+        let file = self.resolve(path)?;
+        self.set_xattr(&file, name, value, flags)
+    }
+
+    /// Same as setxattr, on the open file `fd`.
+    pub fn fsetxattr(&mut self, fd: FileDescriptor, name: &[u8], value: &[u8], flags: u32) -> FsResult<()> {
+        self.set_xattr(self.handle(fd)?.file(), name, value, flags)
+    }
+
+    /// The value of the extended attribute `name` of the file at `path`.
+    /// user. attributes take read permission on the file. Fails with
+    /// ENODATA if there is no such attribute, or if it is a trusted. one
+    /// and the caller is not the superuser.
+    pub fn getxattr<P: AsRef<[u8]>>(&self, path: P, name: &[u8]) -> FsResult<Vec<u8>> {
+        let path = path.as_ref();
+        let file = self.resolve(path)?;
+        self.get_xattr(&file, name)
+    }
+
+    /// Same as getxattr, on the open file `fd`.
+    pub fn fgetxattr(&self, fd: FileDescriptor, name: &[u8]) -> FsResult<Vec<u8>> {
+        self.get_xattr(self.handle(fd)?.file(), name)
+    }
+
+    /// The names of the extended attributes of the file at `path`, in
+    /// order. trusted. ones are only listed for the superuser.
+    pub fn listxattr<P: AsRef<[u8]>>(&self, path: P) -> FsResult<Vec<Vec<u8>>> {
+        let path = path.as_ref();
+        let file = self.resolve(path)?;
+        Ok(self.list_xattr(&file))
+    }
+
+    /// Same as listxattr, on the open file `fd`.
+    pub fn flistxattr(&self, fd: FileDescriptor) -> FsResult<Vec<Vec<u8>>> {
+        Ok(self.list_xattr(self.handle(fd)?.file()))
+    }
+
+    /// Removes the extended attribute `name` of the file at `path`, with
+    /// the same permissions as setxattr. Fails with ENODATA if there is no
+    /// such attribute.
+    pub fn removexattr<P: AsRef<[u8]>>(&mut self, path: P, name: &[u8]) -> FsResult<()> {
+        let path = path.as_ref();
+        let file = self.resolve(path)?;
+        self.remove_xattr(&file, name)
+    }
+
+    /// Same as removexattr, on the open file `fd`.
+    pub fn fremovexattr(&mut self, fd: FileDescriptor, name: &[u8]) -> FsResult<()> {
+        self.remove_xattr(self.handle(fd)?.file(), name)
+    }
+
+    // Whether the caller may read (or with `write`, change) the attribute
+    // `name` of `file`. Like Linux, reading a trusted. attribute without
+    // being allowed to looks as if it was not there.
+    fn check_xattr(&self, file: &File, name: &[u8], write: bool) -> FsResult<()> {
+        match xattr::namespace(name)? {
+            Namespace::Trusted if self.cred.is_root() => Ok(()),
+            Namespace::Trusted if write => Err(FsError::EPERM),
+            Namespace::Trusted => Err(FsError::ENODATA),
+            Namespace::User => {
+                let want = if write { W_OK } else { R_OK };
+                self.cred.check(&file.attr(), file.is_dir(), want)
+            }
+        }
+    }
+
+    fn set_xattr(&self, file: &File, name: &[u8], value: &[u8], flags: u32) -> FsResult<()> {
+        self.check_xattr(file, name, true)?;
+        file.update_xattrs(|xattrs| xattrs.set(name, value, flags))
+    }
+
+    fn get_xattr(&self, file: &File, name: &[u8]) -> FsResult<Vec<u8>> {
+        self.check_xattr(file, name, false)?;
+        file.xattrs(|xattrs| xattrs.get(name).map(|value| value.to_vec())).ok_or(FsError::ENODATA)
+    }
+
+    fn list_xattr(&self, file: &File) -> Vec<Vec<u8>> {
+        file.xattrs(|xattrs| {
+            xattrs.names().into_iter()
+                .filter(|name| self.cred.is_root() || xattr::namespace(name) != Ok(Namespace::Trusted))
+                .map(|name| name.to_vec())
+                .collect()
+        })
+    }
+
+    fn remove_xattr(&self, file: &File, name: &[u8]) -> FsResult<()> {
+        self.check_xattr(file, name, true)?;
+        file.update_xattrs(|xattrs| xattrs.remove(name))
+    }
+
+    /// Checks whether the caller may access the file at `path` as asked by
+    /// `mode`: F_OK for existence only, or a mix of R_OK, W_OK and X_OK.
+    /// Fails with EACCES if not.
+    pub fn access<P: AsRef<[u8]>>(&self, path: P, mode: u16) -> FsResult<()> {
+        let path = path.as_ref();
+        if (mode & !(R_OK | W_OK | X_OK)) != 0 { return Err(FsError::EINVAL); }
+        let file = self.resolve(path)?;
+        self.cred.check(&file.attr(), file.is_dir(), mode)
+    }
+
+    /// Opens the directory at `path` for listing with readdir. Takes read
+    /// permission on it.
+    pub fn opendir<P: AsRef<[u8]>>(&mut self, path: P) -> FsResult<FileDescriptor> {
+        self.open_dir(path.as_ref(), false)
+    }
+
+    /// Same as opendir, but readdir lists the entries in name order. Only
+    /// the entries that exist when the directory is opened are listed.
+    pub fn opendir_sorted<P: AsRef<[u8]>>(&mut self, path: P) -> FsResult<FileDescriptor> {
+        self.open_dir(path.as_ref(), true)
+    }
+
+    fn open_dir(&mut self, path: &[u8], sorted: bool) -> FsResult<FileDescriptor> {
+        let dir = self.resolve(path)?;
+        if !dir.is_dir() { return Err(FsError::ENOTDIR); }
+        self.cred.check(&dir.attr(), true, R_OK)?;
+
+        let handle = FileHandle::new_dir(dir, sorted)?;
+        self.install(handle)
+    }
+
+    /// The next entry of the directory open as `fd`, starting with "." and
+    /// "..", or None once every entry has been listed. Entries added or
+    /// removed during the listing do not make it skip or repeat the others.
+    pub fn readdir(&self, fd: FileDescriptor) -> FsResult<Option<DirEntry>> {
+        self.handle(fd)?.readdir()
+    }
+
+    /// The cookie of the last entry readdir returned on `fd` (0 before the
+    /// first one).
+    pub fn telldir(&self, fd: FileDescriptor) -> FsResult<u64> {
+        self.handle(fd)?.telldir()
+    }
+
+    /// Makes readdir on `fd` go on after the entry with `cookie`, as
+    /// returned by readdir or telldir. 0 starts over.
+    pub fn seekdir(&mut self, fd: FileDescriptor, cookie: u64) -> FsResult<()> {
+        self.handle(fd)?.seekdir(cookie)
+    }
+
+    pub fn closedir(&mut self, fd: FileDescriptor) -> FsResult<()> {
+        self.close(fd)
+    }
+
+    pub fn close(&mut self, fd: FileDescriptor) -> FsResult<()> {
+        self.fds.remove(fd)?;
+        Ok(())
+    }
+
+    /// A new descriptor, the lowest free one, for the file open as `fd`.
+    /// Both share the offset and the status flags: a read or seek through
+    /// one moves the other too.
+    pub fn dup(&mut self, fd: FileDescriptor) -> FsResult<FileDescriptor> {
+        let handle = self.fds.get(fd)?.clone();
+        self.fds.insert(handle)
+    }
+
+    /// Same as dup, but the new descriptor is `new_fd`; whatever was open
+    /// there is closed first. Nothing happens if `new_fd` is `fd`. Fails
+    /// with EBADF if `new_fd` is negative or not below fd_limit.
+    pub fn dup2(&mut self, fd: FileDescriptor, new_fd: FileDescriptor) -> FsResult<FileDescriptor> {
+        let handle = self.fds.get(fd)?.clone();
+        if new_fd != fd {
+            self.fds.replace(new_fd, handle)?;
+        }
+        Ok(new_fd)
+    }
+
+    /// File descriptor control, like fcntl(2):
+    ///
+    /// - F_DUPFD: dup `fd` to the lowest free descriptor that is at least
+    ///   `arg` and returns it (EINVAL if `arg` is out of range)
+    /// - F_GETFL: returns the access mode and the status flags (O_APPEND,
+    ///   O_NONBLOCK) of `fd`; flags that only matter to open are left out
+    /// - F_SETFL: sets O_APPEND and O_NONBLOCK on `fd` as given in `arg`
+    ///   (other flags are ignored) and returns 0
+    ///
+    /// The flags are those of the open file description, so they are the
+    /// same for every descriptor dup'ed from it.
+    pub fn fcntl(&mut self, fd: FileDescriptor, cmd: u32, arg: isize) -> FsResult<isize> {
+        let handle = self.fds.get(fd)?.clone();
+        match cmd {
+            F_DUPFD => {
+                if arg < 0 || arg as usize >= self.fds.limit() { return Err(FsError::EINVAL); }
+                self.fds.insert_from(arg as usize, handle)
             }
-            Directory(_) => -1,
-            EmptyFile => -2,
+            F_GETFL => Ok((handle.flags() & (O_ACCMODE | O_APPEND | O_NONBLOCK)) as isize),
+            F_SETFL => {
+                handle.set_flags(arg as u32);
+                Ok(0)
+            }
+            _ => Err(FsError::EINVAL)
         }
     }
 
-    pub fn read(&self, fd: FileDescriptor, dst: &mut [u8]) -> usize {
-        let handle = self.fd_table.get(&fd).expect("fd does not exist");
-        handle.read(dst)
+    pub fn unlink<P: AsRef<[u8]>>(&mut self, path: P) -> FsResult<()> {
+        let path = path.as_ref();
+        let _names = self.fs.names_lock();
+        let (mut dir, name) = self.resolve_parent(path)?;
+        let file = dir.remove_if(name, |dir_attr, file| {
+            if file.is_dir() { return Err(FsError::EISDIR); } // Directories go through rmdir
+            self.cred.check(dir_attr, true, W_OK | X_OK)?;
+            self.check_sticky(dir_attr, file)
+        })?;
+        file.get_inode_rc()?.write().dec_nlink();
+        Ok(())
+    }
+
+    /// Adds the name `new` for the file at `existing`. Both names then refer
+    /// to the same inode; its data stays until every name is unlinked and
+    /// every fd on it is closed. Directories cannot be linked (EPERM). A
+    /// symbolic link at `existing` is linked itself, not followed.
+    pub fn link<P: AsRef<[u8]>, Q: AsRef<[u8]>>(&mut self, existing: P, new: Q) -> FsResult<()> {
+        let (existing, new) = (existing.as_ref(), new.as_ref());
+        let _names = self.fs.names_lock();
+        let file = self.resolve_nofollow(existing)?;
+        if file.is_dir() { return Err(FsError::EPERM); }
+
+        let (mut dir, name) = self.resolve_parent(new)?;
+        self.check_dir_write(&dir)?;
+        dir.insert(name, file.clone())?;
+        file.get_inode_rc()?.write().inc_nlink();
+        Ok(())
+    }
+
+    /// Creates a symbolic link at `path` pointing at `target`. The target is
+    /// not checked: it only has to exist when the link is followed.
+    pub fn symlink<T: AsRef<[u8]>, P: AsRef<[u8]>>(&mut self, target: T, path: P) -> FsResult<()> {
+        let (target, path) = (target.as_ref(), path.as_ref());
+        if target.is_empty() { return Err(FsError::ENOENT); }
+        if target.len() > path::PATH_MAX { return Err(FsError::ENAMETOOLONG); }
+
+        let _names = self.fs.names_lock();
+        let (mut dir, name) = self.resolve_parent(path)?;
+        if dir.get(name).is_ok() { return Err(FsError::EEXIST); }
+        self.check_dir_write(&dir)?;
+        let link = File::new_symlink(self.fs.new_inode()?, target)?;
+        // The mode of a symbolic link is always 0o777 and never checked
+        self.init_attr(&dir, &link, 0o777);
+        link.update_attr(|attr| attr.mode = 0o777);
+        dir.insert(name, link)
+    }
+
+    /// The target of the symbolic link at `path`. EINVAL if `path` is not a
+    /// symbolic link.
+    pub fn readlink<P: AsRef<[u8]>>(&self, path: P) -> FsResult<Vec<u8>> {
+        let path = path.as_ref();
+        self.resolve_nofollow(path)?.link_target()
+    }
+
+    /// Creates an empty directory at `path` with mode 0o777 (less the
+    /// umask), see mkdir_mode.
+    pub fn mkdir<P: AsRef<[u8]>>(&mut self, path: P) -> FsResult<()> {
+        self.mkdir_mode(path, 0o777)
+    }
+
+    /// Creates an empty directory at `path` with the permission bits in
+    /// `mode`, less the umask.
+    pub fn mkdir_mode<P: AsRef<[u8]>>(&mut self, path: P, mode: u16) -> FsResult<()> {
+        let path = path.as_ref();
+        let _names = self.fs.names_lock();
+        let (mut dir, name) = self.resolve_parent(path)?;
+        self.check_dir_write(&dir)?;
+        let new_dir = self.fs.new_dir(&dir)?;
+        self.init_attr(&dir, &new_dir, mode);
+        dir.insert(name, new_dir)
     }
 
-    pub fn write(&mut self, fd: FileDescriptor, src: &[u8]) -> usize {
-        let handle = self.fd_table.get_mut(&fd).expect("fd does not exist");
-        handle.write(src)
+    /// Removes the directory at `path`. Only empty directories can be
+    /// removed; nothing can be created in one once it is.
+    pub fn rmdir<P: AsRef<[u8]>>(&mut self, path: P) -> FsResult<()> {
+        let path = path.as_ref();
+        let _names = self.fs.names_lock();
+        let (mut dir, name) = self.resolve_parent(path)?;
+        dir.remove_if(name, |dir_attr, target| {
+            let rc = target.get_dir_rc()?;
+            if !target.is_empty()? { return Err(FsError::ENOTEMPTY); }
+            self.cred.check(dir_attr, true, W_OK | X_OK)?;
+            self.check_sticky(dir_attr, target)?;
+            // Checked again under the lock that keeps entries from coming in
+            let mut content = rc.write();
+            if !content.entries.is_empty() { return Err(FsError::ENOTEMPTY); }
+            content.removed = true;
+            Ok(())
+        }).map(|_| ())
     }
 
-    pub fn seek(&mut self, fd: FileDescriptor, o: isize, whence: Whence) -> usize {
-        let handle = self.fd_table.get_mut(&fd).expect("fd does not exist");
-        handle.seek(o, whence)
+    /// Moves the file or directory at `old` to `new`, like rename(2). An
+    /// existing `new` is replaced: a file by a file, a directory by a
+    /// directory only if it is empty. A directory cannot move below itself
+    /// (EINVAL). On disk the move becomes visible in one step at the next
+    /// sync: either both names are updated or neither is.
+    ///
+    /// Both parent directories must be writable, and a directory moving to
+    /// another parent must be writable itself (its ".." changes).
+    pub fn rename<P: AsRef<[u8]>, Q: AsRef<[u8]>>(&mut self, old: P, new: Q) -> FsResult<()> {
+        let (old, new) = (old.as_ref(), new.as_ref());
+        let fs = self.fs.clone();
+        let _names = fs.names_lock();
+        let _renaming = fs.rename_lock();
+
+        loop {
+            let (old_dir, old_name) = self.resolve_parent(old)?;
+            let (new_dir, new_name) = self.resolve_parent(new)?;
+            let source = old_dir.get(old_name)?;
+            // Which directory is below which only changes by renaming, so
+            // this holds until we are done
+            let below_itself = source.is_dir() && source.is_ancestor_of(&new_dir)?;
+            let new_first = new_dir.is_ancestor_of(&old_dir)?;
+
+            let mut dirs = DirPair::lock(old_dir.get_dir_rc()?, new_dir.get_dir_rc()?, new_first);
+            match dirs.old().entries.get(old_name) {
+                Some(entry) if entry.file.same_as(&source) => {}
+                // Unlinked or replaced since we looked: start over
+                _ => continue
+            }
+            if dirs.new().removed { return Err(FsError::ENOENT); }
+            let target = dirs.new().entries.get(new_name).map(|entry| entry.file.clone());
+
+            if let Some(ref target) = target {
+                // Both names already refer to the same file: nothing to do
+                if target.same_as(&source) { return Ok(()); }
+                match (source.is_dir(), target.is_dir()) {
+                    (true, false) => return Err(FsError::ENOTDIR),
+                    (false, true) => return Err(FsError::EISDIR),
+                    // Moving a directory onto one of its ancestors; that
+                    // one is locked already but surely not empty
+                    (true, true) if target.same_as(&old_dir) => return Err(FsError::ENOTEMPTY),
+                    (true, true) if !target.is_empty()? => return Err(FsError::ENOTEMPTY),
+                    _ => {}
+                }
+            }
+            if below_itself { return Err(FsError::EINVAL); }
+
+            self.cred.check(&dirs.old().attr, true, W_OK | X_OK)?;
+            self.cred.check(&dirs.new().attr, true, W_OK | X_OK)?;
+            self.check_sticky(&dirs.old().attr, &source)?;
+            if let Some(ref target) = target {
+                self.check_sticky(&dirs.new().attr, target)?;
+            }
+            if source.is_dir() && !old_dir.same_as(&new_dir) {
+                self.cred.check(&source.attr(), true, W_OK)?;
+            }
+            // Last check: a directory being replaced takes no new entries
+            // from here on
+            if let Some(Directory(ref rc)) = target {
+                let mut content = rc.write();
+                if !content.entries.is_empty() { return Err(FsError::ENOTEMPTY); }
+                content.removed = true;
+            }
+
+            // Every check is done, none of the steps below can fail
+            if let Some(DataFile(ref rc)) | Some(Symlink(ref rc)) = target {
+                rc.write().dec_nlink();
+            }
+            if target.is_some() { dirs.new().take(new_name)?; }
+            dirs.old().take(old_name)?;
+            dirs.old().attr.modified();
+            dirs.new().add(new_name.to_vec(), source.clone())?;
+            dirs.new().attr.modified();
+            if source.is_dir() { source.set_parent(&new_dir)?; }
+            return Ok(());
+        }
     }
 
-    pub fn close(&mut self, fd: FileDescriptor) {
-        self.fd_table.remove(&fd);
-        self.fds.push(fd);
+    /// Changes the working directory used to resolve relative paths. Takes
+    /// search permission on the new directory.
+    pub fn chdir<P: AsRef<[u8]>>(&mut self, path: P) -> FsResult<()> {
+        let path = path.as_ref();
+        let dir = self.resolve(path)?;
+        if !dir.is_dir() { return Err(FsError::ENOTDIR); }
+        self.cred.check(&dir.attr(), true, X_OK)?;
+        self.cwd = dir;
+        Ok(())
     }
 
-    pub fn unlink(&mut self, path: &'r str) {
-        self.cwd.remove(path);
+    /// Returns the absolute path of the working directory. Fails with ENOENT
+    /// if it has been removed since we moved into it.
+    pub fn getcwd(&self) -> FsResult<Vec<u8>> {
+        let mut names = Vec::new();
+        let mut dir = self.cwd.clone();
+        loop {
+            let parent = dir.parent()?;
+            if parent.same_as(&dir) { break; }
+            names.push(parent.name_of(&dir)?);
+            dir = parent;
+        }
+
+        if !dir.same_as(&self.root) { return Err(FsError::ENOENT); }
+        if names.is_empty() { return Ok(b"/".to_vec()); }
+        let mut path = Vec::new();
+        for name in names.iter().rev() {
+            path.push(b'/');
+            path.extend_from_slice(name);
+        }
+        Ok(path)
     }
 }
 
@@ -114,10 +1011,22 @@ mod proc_tests {
     // extern crate test;
     extern crate rand;
 
-    use super::{Proc, O_RDWR, O_CREAT};
-    use file::Whence::SeekSet;
-    use inode::Inode;
+    use super::{path, Proc, FileSystem, FsError, BlockDevice, FileDevice, IoFuture, MemDevice, mkfs};
+    use super::{AtimePolicy, FileType, Timespec, S_IFDIR, S_IFLNK, S_IFREG};
+    use super::{Cred, F_OK, R_OK, W_OK, X_OK, S_ISGID, S_ISUID, S_ISVTX};
+    use super::{NAME_MAX, PATH_MAX, DEFAULT_FD_LIMIT, F_DUPFD, F_GETFL, F_SETFL};
+    use super::{XATTR_CREATE, XATTR_REPLACE};
+    use super::{FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE};
+    use super::{O_NONBLOCK, O_RDONLY, O_WRONLY, O_RDWR, O_APPEND, O_CREAT, O_EXCL, O_TRUNC, O_NOFOLLOW};
+    use crate::file::Whence::{SeekSet, SeekCur, SeekEnd, SeekData, SeekHole};
+    use crate::inode::{Inode, MAX_FILE_SIZE};
     use self::rand::random;
+    use futures_new::executor::block_on;
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
 
     static mut test_inode_drop: bool = false;
 
@@ -154,33 +1063,32 @@ mod proc_tests {
         let mut buf = [0u8; SIZE];
         let filename = "first_file";
 
-        let fd = p.open(filename, O_RDWR | O_CREAT);
-        p.write(fd, &data);
-        p.seek(fd, 0, SeekSet);
-        p.read(fd, &mut buf);
+        let fd = p.open(filename, O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &data)).unwrap();
+        p.seek(fd, 0, SeekSet).unwrap();
+        block_on(p.read(fd, &mut buf)).unwrap();
 
         assert_eq_buf(&data, &buf);
 
-        let fd2 = p.open(filename, O_RDWR);
+        let fd2 = p.open(filename, O_RDWR).unwrap();
         let mut buf2 = [0u8; SIZE];
-        p.read(fd2, &mut buf2);
+        block_on(p.read(fd2, &mut buf2)).unwrap();
 
         assert_eq_buf(&data, &buf2);
 
-        p.close(fd);
-        p.close(fd2);
+        p.close(fd).unwrap();
+        p.close(fd2).unwrap();
 
-        let fd3 = p.open(filename, O_RDWR);
+        let fd3 = p.open(filename, O_RDWR).unwrap();
         let mut buf3 = [0u8; SIZE];
-        p.read(fd3, &mut buf3);
+        block_on(p.read(fd3, &mut buf3)).unwrap();
 
         assert_eq_buf(&data, &buf3);
-        p.close(fd3);
+        p.close(fd3).unwrap();
 
-        p.unlink(filename);
+        p.unlink(filename).unwrap();
 
-        let fd4 = p.open(filename, O_RDWR);
-        assert_eq!(fd4, -2);
+        assert_eq!(p.open(filename, O_RDWR), Err(FsError::ENOENT));
     }
 
     #[test]
@@ -194,8 +1102,8 @@ mod proc_tests {
         let mut p = Proc::new();
         let mut data = rand_array(SIZE);
 
-        let fd = p.open("file", O_RDWR | O_CREAT);
-        p.write(fd, &mut data);
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &mut data)).unwrap();
     }
 
     /**
@@ -220,17 +1128,17 @@ mod proc_tests {
         let mut buf = [0u8; SIZE];
         let filename = "first_file";
 
-        let fd = p.open(filename, O_RDWR | O_CREAT);
-        p.write(fd, &mut data);
-        p.seek(fd, 0, SeekSet);
-        p.read(fd, &mut buf);
+        let fd = p.open(filename, O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &mut data)).unwrap();
+        p.seek(fd, 0, SeekSet).unwrap();
+        block_on(p.read(fd, &mut buf)).unwrap();
 
         assert_eq_buf(&data, &buf);
 
         // close + unlink should remove both references to inode, dropping it,
         // causing a failure
-        p.close(fd);
-        p.unlink(filename);
+        p.close(fd).unwrap();
+        p.unlink(filename).unwrap();
 
         // If inode is not being dropped properly, ie, on the unlink call this will
         // cause a double failure: once for panic! call, and once when then the Inode
@@ -241,62 +1149,1869 @@ mod proc_tests {
         panic!("Inode not dropped!");
     }
 
+    // Same as test_inode_dealloc with two names: the inode has to survive
+    // the first unlink and go away with the last one.
     #[test]
-    fn test_max_singly_file_size() {
-        const SIZE: usize = 4096 * 256;
+    #[should_panic]
+    fn test_link_dealloc() {
         let mut p = Proc::new();
-        let mut data = rand_array(SIZE);
-        let mut buf = [0u8; SIZE];
-        let filename = "first_file";
+        let data = rand_array(4096 + 17);
+        let mut buf = vec![0u8; data.len()];
 
-        let fd = p.open(filename, O_RDWR | O_CREAT);
-        p.write(fd, &mut data);
-        p.seek(fd, 0, SeekSet);
-        p.read(fd, &mut buf);
+        let fd = p.open("first", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &data)).unwrap();
+        p.link("first", "second").unwrap();
+        p.close(fd).unwrap();
+        p.unlink("first").unwrap();
 
+        let fd = p.open("second", O_RDONLY).unwrap();
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(data.len()));
         assert_eq_buf(&data, &buf);
+        p.close(fd).unwrap();
+
+        unsafe { test_inode_drop = true; }
+        p.unlink("second").unwrap();
+        panic!("Inode not dropped!");
+    }
+
+    #[test]
+    fn test_link() {
+        let mut p = Proc::new();
+        let mut buf = [0u8; 8];
+        p.mkdir("dir").unwrap();
 
-        p.close(fd);
-        p.unlink(filename);
+        let fd = p.open("a", O_RDWR | O_CREAT).unwrap();
+        p.link("a", "dir/b").unwrap();
+        p.link("dir/b", "c").unwrap();
+        block_on(p.write(fd, b"shared")).unwrap();
+        let nlink = |p: &Proc, name: &str| {
+            let file = path::resolve(&p.root, &p.cwd, name.as_bytes(), &p.cred).unwrap();
+            let n = file.get_inode_rc().unwrap().read().nlink();
+            n
+        };
+        assert_eq!(nlink(&p, "a"), 3);
+
+        let r = p.open("dir/b", O_RDONLY).unwrap();
+        assert_eq!(block_on(p.read(r, &mut buf)), Ok(6));
+        assert_eq_buf(b"shared", &buf[..6]);
 
-        let fd4 = p.open(filename, O_RDWR);
-        assert_eq!(fd4, -2);
+        // Replacing one name by rename drops one link; renaming onto
+        // another name of the same file does nothing
+        let other = p.open("other", O_RDWR | O_CREAT).unwrap();
+        p.close(other).unwrap();
+        p.rename("other", "c").unwrap();
+        assert_eq!(nlink(&p, "a"), 2);
+        p.rename("a", "dir/b").unwrap();
+        assert_eq!(nlink(&p, "a"), 2);
+        p.unlink("a").unwrap();
+        assert_eq!(nlink(&p, "dir/b"), 1);
+
+        assert_eq!(p.link("dir", "dir2"), Err(FsError::EPERM));
+        assert_eq!(p.link("dir/b", "c"), Err(FsError::EEXIST));
+        assert_eq!(p.link("nothing", "x"), Err(FsError::ENOENT));
     }
 
     #[test]
-    fn test_max_file_size() {
-        const SIZE: usize = 2 * 4096 * 256;
+    fn test_symlink() {
         let mut p = Proc::new();
-        let mut data1 = rand_array(SIZE);
-        let mut data2 = rand_array(SIZE);
-        let mut buf = vec![0; SIZE];
-        let filename = "first_file";
+        let mut buf = [0u8; 8];
+        p.mkdir("d").unwrap();
+        let fd = p.open("d/f", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, b"target")).unwrap();
 
-        let fd = p.open(filename, O_RDWR | O_CREAT);
-        p.write(fd, &mut data1);
-        p.seek(fd, 4096 * 257 * 256 - SIZE as isize, SeekSet);
-        p.write(fd, &mut data2);
+        // To a file, to a directory, and relative to the link's directory
+        p.symlink("d/f", "l").unwrap();
+        p.symlink("/d", "ld").unwrap();
+        p.symlink("f", "d/rel").unwrap();
+        assert_eq!(p.readlink("l"), Ok(b"d/f".to_vec()));
+        for name in ["l", "ld/f", "d/rel", "ld/rel"].iter() {
+            let fd = p.open(name, O_RDONLY).unwrap();
+            assert_eq!(block_on(p.read(fd, &mut buf)), Ok(6));
+            assert_eq_buf(b"target", &buf[..6]);
+        }
+        p.chdir("ld").unwrap();
+        assert_eq!(p.getcwd(), Ok(b"/d".to_vec()));
+        p.chdir("/").unwrap();
 
-        p.seek(fd, 0, SeekSet);
-        p.read(fd, &mut buf);
-        assert_eq_buf(&data1, &buf);
+        assert_eq!(p.open("l", O_RDONLY | O_NOFOLLOW), Err(FsError::ELOOP));
+        assert!(p.open("ld/f", O_RDONLY | O_NOFOLLOW).is_ok());
+        assert_eq!(p.readlink("d/f"), Err(FsError::EINVAL));
+        assert_eq!(p.symlink("x", "l"), Err(FsError::EEXIST));
+        assert_eq!(p.symlink("", "empty"), Err(FsError::ENOENT));
+        assert_eq!(p.rmdir("ld"), Err(FsError::ENOTDIR));
 
-        p.seek(fd, 4096 * 257 * 256 - SIZE as isize, SeekSet);
-        p.read(fd, &mut buf);
-        assert_eq_buf(&data2, &buf);
+        // Dangling links and loops
+        p.symlink("missing", "dangling").unwrap();
+        assert_eq!(p.open("dangling", O_RDONLY), Err(FsError::ENOENT));
+
+        // O_CREAT creates the target, relative to the link's directory;
+        // O_EXCL does not go through the link
+        assert_eq!(p.open("dangling", O_WRONLY | O_CREAT | O_EXCL), Err(FsError::EEXIST));
+        p.symlink("made", "d/dangling").unwrap();
+        let fd = p.open("d/dangling", O_WRONLY | O_CREAT).unwrap();
+        block_on(p.write(fd, b"made")).unwrap();
+        assert_eq!(p.readlink("d/dangling"), Ok(b"made".to_vec()));
+        let fd = p.open("d/made", O_RDONLY).unwrap();
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(4));
+        assert_eq_buf(b"made", &buf[..4]);
+        p.symlink("nodir/x", "deep").unwrap();
+        assert_eq!(p.open("deep", O_WRONLY | O_CREAT), Err(FsError::ENOENT));
+        p.symlink("loop2", "loop1").unwrap();
+        p.symlink("loop1", "loop2").unwrap();
+        assert_eq!(p.open("loop1", O_RDONLY), Err(FsError::ELOOP));
+        assert_eq!(p.open("loop1/x", O_RDWR | O_CREAT), Err(FsError::ELOOP));
+
+        // A chain of exactly SYMLOOP_MAX links still resolves
+        p.symlink("d/f", "chain0").unwrap();
+        let names: Vec<String> = (0..=path::SYMLOOP_MAX).map(|i| format!("chain{}", i)).collect();
+        for i in 1..names.len() {
+            p.symlink(&names[i - 1], &names[i]).unwrap();
+        }
+        assert!(p.open(&names[path::SYMLOOP_MAX - 1], O_RDONLY).is_ok());
+        assert_eq!(p.open(&names[path::SYMLOOP_MAX], O_RDONLY), Err(FsError::ELOOP));
+
+        // unlink, rename and link act on the link, not its target
+        p.link("l", "l2").unwrap();
+        p.rename("l2", "d/l3").unwrap();
+        assert_eq!(p.readlink("d/l3"), Ok(b"d/f".to_vec()));
+        p.unlink("l").unwrap();
+        assert_eq!(p.open("d/l3", O_RDONLY), Err(FsError::ENOENT));
+        assert!(p.open("d/f", O_RDONLY).is_ok());
     }
 
     #[test]
-    #[should_panic]
-    fn test_morethan_max_file_size() {
-        const SIZE: usize = 2 * 4096 * 256;
+    fn test_stat() {
         let mut p = Proc::new();
-        let mut data = rand_array(SIZE);
-        let filename = "first_file";
+        p.mkdir("d").unwrap();
+        p.mkdir("d/sub").unwrap();
+        let fd = p.open("d/f", O_RDWR | O_CREAT).unwrap();
+        p.seek(fd, 3 * 4096, SeekSet).unwrap();
+        block_on(p.write(fd, &[1u8; 10])).unwrap();
+        p.symlink("d/f", "l").unwrap();
+
+        let st = p.stat("l").unwrap();
+        assert_eq!(p.fstat(fd), Ok(st));
+        assert_eq!(st.file_type, FileType::Regular);
+        assert_eq!(st.mode, S_IFREG | 0o644);
+        assert_eq!((st.nlink, st.uid, st.gid), (1, 0, 0));
+        assert_eq!(st.size, 3 * 4096 + 10);
+        // The hole in front takes no space
+        assert_eq!((st.blocks, st.block_size), (8, 4096));
+        assert!(st.crtime <= st.mtime && st.mtime == st.ctime);
+
+        let lst = p.lstat("l").unwrap();
+        assert_eq!(lst.file_type, FileType::Symlink);
+        assert_eq!(lst.mode, S_IFLNK | 0o777);
+        assert_eq!(lst.size, 3);
+        assert!(lst.ino != st.ino);
+
+        let dst = p.stat("d").unwrap();
+        assert_eq!(dst.file_type, FileType::Directory);
+        assert_eq!(dst.mode, S_IFDIR | 0o755);
+        assert_eq!(dst.nlink, 3);
+        assert_eq!(p.stat("d/sub").unwrap().nlink, 2);
+        assert_eq!(p.stat("/").unwrap().ino, 1);
+        assert_eq!(p.stat("nothing"), Err(FsError::ENOENT));
+        assert_eq!(p.fstat(fd + 1), Err(FsError::EBADF));
+
+        // Links change the inode, names change the directory
+        p.link("d/f", "d/g").unwrap();
+        let after = p.stat("d/f").unwrap();
+        assert_eq!(after.nlink, 2);
+        assert!(after.ctime >= st.ctime && after.mtime == st.mtime);
+        assert!(p.stat("d").unwrap().mtime >= dst.mtime);
+    }
+
+    #[test]
+    fn test_atime() {
+        let mut p = Proc::new();
+        let mut buf = [0u8; 4];
+        let fd = p.open("f", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, b"data")).unwrap();
+
+        // Pretend the file was last read a minute ago, after its last change
+        let set_times = |p: &Proc| {
+            let file = path::resolve(&p.root, &p.cwd, b"f", &p.cred).unwrap();
+            let mut inode = file.get_inode_rc().unwrap().write();
+            let attr = inode.attr_mut();
+            attr.access_time = Timespec::new(attr.access_time.sec - 60, 0);
+            attr.mod_time = Timespec::new(attr.access_time.sec - 60, 0);
+            attr.change_time = attr.mod_time;
+            attr.access_time
+        };
+        let mut read = |p: &mut Proc| {
+            p.seek(fd, 0, SeekSet).unwrap();
+            block_on(p.read(fd, &mut buf)).unwrap();
+            p.fstat(fd).unwrap().atime
+        };
+
+        // Relatime (the default) skips it, Strict does not
+        let old = set_times(&p);
+        assert_eq!(read(&mut p), old);
+        p.set_atime_policy(AtimePolicy::Strict);
+        assert!(read(&mut p) > old);
+
+        // Relatime does update an access time older than the last change
+        p.set_atime_policy(AtimePolicy::Relatime);
+        let old = set_times(&p);
+        block_on(p.write(fd, b"more")).unwrap();
+        assert!(read(&mut p) > old);
+
+        p.set_atime_policy(AtimePolicy::NoAtime);
+        let old = set_times(&p);
+        block_on(p.write(fd, b"more")).unwrap();
+        assert_eq!(read(&mut p), old);
+    }
+
+    #[test]
+    fn test_permissions() {
+        let mut p = Proc::new();
+        let alice = Cred::new(1000, 100, Vec::new());
+        let bob = Cred::new(1001, 101, vec![100]);
+        let eve = Cred::new(1002, 102, Vec::new());
+
+        // The superuser sets up a home for alice and a shared sticky dir
+        p.mkdir("home").unwrap();
+        p.mkdir("home/alice").unwrap();
+        p.chown("home/alice", Some(1000), Some(100)).unwrap();
+        p.mkdir_mode("tmp", 0o777).unwrap();
+        p.chmod("tmp", 0o777 | S_ISVTX).unwrap();
+        assert_eq!(p.stat("tmp").unwrap().mode, S_IFDIR | 0o777 | S_ISVTX as u32);
 
-        let fd = p.open(filename, O_RDWR | O_CREAT);
-        p.write(fd, &mut data);
-        p.seek(fd, 4096 * 257 * 256 + 1 - SIZE as isize, SeekSet);
-        p.write(fd, &mut data);
+        // Files belong to their creator and get the umask applied
+        p.set_cred(alice.clone());
+        assert_eq!(p.umask(0o027), 0o022);
+        let fd = p.open_mode("home/alice/notes", O_RDWR | O_CREAT, 0o666).unwrap();
+        block_on(p.write(fd, b"secret")).unwrap();
+        p.mkdir("home/alice/private").unwrap();
+        let st = p.stat("home/alice/notes").unwrap();
+        assert_eq!((st.mode, st.uid, st.gid), (S_IFREG | 0o640, 1000, 100));
+        assert_eq!(p.stat("home/alice/private").unwrap().mode, S_IFDIR | 0o750);
+        assert_eq!(p.open("newfile", O_RDWR | O_CREAT), Err(FsError::EACCES));
+        assert_eq!(p.chown("home/alice/notes", Some(1001), None), Err(FsError::EPERM));
+        assert_eq!(p.chown("home/alice/notes", None, Some(101)), Err(FsError::EPERM));
+
+        // Group members can read but not write; others get nothing
+        p.set_cred(bob.clone());
+        assert!(p.open("home/alice/notes", O_RDONLY).is_ok());
+        assert_eq!(p.open("home/alice/notes", O_WRONLY), Err(FsError::EACCES));
+        assert_eq!(block_on(p.truncate("home/alice/notes", 0)), Err(FsError::EACCES));
+        assert_eq!(p.access("home/alice/notes", R_OK), Ok(()));
+        assert_eq!(p.access("home/alice/notes", R_OK | W_OK), Err(FsError::EACCES));
+        assert_eq!(p.unlink("home/alice/notes"), Err(FsError::EACCES));
+        assert_eq!(p.chmod("home/alice/notes", 0o666), Err(FsError::EPERM));
+
+        p.set_cred(eve.clone());
+        assert_eq!(p.open("home/alice/notes", O_RDONLY), Err(FsError::EACCES));
+        assert_eq!(p.access("home/alice", X_OK), Ok(()));
+        assert_eq!(p.access("home/alice/private", F_OK), Ok(()));
+        // No search permission on private: nothing below it can be reached
+        assert_eq!(p.stat("home/alice/private/x"), Err(FsError::EACCES));
+        assert_eq!(p.chdir("home/alice/private"), Err(FsError::EACCES));
+        assert_eq!(p.rename("home/alice/notes", "tmp/stolen"), Err(FsError::EACCES));
+
+        // In the sticky dir everyone creates, only owners remove
+        let fd = p.open("tmp/eve", O_RDWR | O_CREAT).unwrap();
+        p.close(fd).unwrap();
+        p.set_cred(bob.clone());
+        assert_eq!(p.unlink("tmp/eve"), Err(FsError::EPERM));
+        assert_eq!(p.rename("tmp/eve", "tmp/bob"), Err(FsError::EPERM));
+        p.set_cred(eve.clone());
+        p.rename("tmp/eve", "tmp/eve2").unwrap();
+        p.unlink("tmp/eve2").unwrap();
+
+        // The owner may move a file to one of their groups, which drops
+        // the set-ID bits
+        p.set_cred(alice.clone());
+        p.chmod("home/alice/notes", 0o755 | S_ISUID).unwrap();
+        p.chown("home/alice/notes", None, Some(100)).unwrap();
+        assert_eq!(p.stat("home/alice/notes").unwrap().mode, S_IFREG | 0o755);
+        p.chmod("home/alice/private", 0o770 | S_ISGID).unwrap();
+        p.set_cred(Cred::root());
+        p.mkdir("home/alice/private/sub").unwrap();
+        let st = p.stat("home/alice/private/sub").unwrap();
+        assert_eq!((st.gid, st.mode & S_ISGID as u32), (100, S_ISGID as u32));
+
+        // Root reads anything but only executes what has an execute bit
+        p.chmod("home/alice/notes", 0).unwrap();
+        assert!(p.open("home/alice/notes", O_RDWR).is_ok());
+        assert_eq!(p.access("home/alice/notes", X_OK), Err(FsError::EACCES));
+        assert_eq!(p.access("home/alice/notes", 8), Err(FsError::EINVAL));
+    }
+
+    fn list(p: &Proc, fd: super::FileDescriptor) -> Vec<String> {
+        let mut names = Vec::new();
+        while let Some(entry) = p.readdir(fd).unwrap() {
+            names.push(String::from_utf8(entry.name).unwrap());
+        }
+        names
+    }
+
+    #[test]
+    fn test_readdir() {
+        let mut p = Proc::new();
+        p.mkdir("d").unwrap();
+        p.mkdir("d/sub").unwrap();
+        p.symlink("sub", "d/link").unwrap();
+        let names: Vec<String> = (0..100).map(|i| format!("f{:03}", (i * 37) % 100)).collect();
+        for name in names.iter() {
+            let fd = p.open(format!("d/{}", name), O_WRONLY | O_CREAT).unwrap();
+            p.close(fd).unwrap();
+        }
+
+        let fd = p.opendir("d").unwrap();
+        let dot = p.readdir(fd).unwrap().unwrap();
+        assert_eq!((&dot.name[..], dot.ino, dot.file_type), (&b"."[..], p.stat("d").unwrap().ino, FileType::Directory));
+        let dotdot = p.readdir(fd).unwrap().unwrap();
+        assert_eq!((&dotdot.name[..], dotdot.ino), (&b".."[..], 1));
+        let sub = p.readdir(fd).unwrap().unwrap();
+        assert_eq!((&sub.name[..], sub.file_type), (&b"sub"[..], FileType::Directory));
+        let link = p.readdir(fd).unwrap().unwrap();
+        assert_eq!((&link.name[..], link.file_type), (&b"link"[..], FileType::Symlink));
+
+        // Remove what was listed and what is still to come, add new names:
+        // the rest comes exactly once, new names at the end
+        let cookie = p.telldir(fd).unwrap();
+        let mut first = Vec::new();
+        for _ in 0..50 {
+            first.push(String::from_utf8(p.readdir(fd).unwrap().unwrap().name).unwrap());
+        }
+        let gone = names.iter().position(|name| *name == first[10]).unwrap();
+        p.unlink(format!("d/{}", names[gone])).unwrap();
+        p.unlink(format!("d/{}", names[70])).unwrap();
+        p.mkdir("d/new").unwrap();
+        let rest = list(&p, fd);
+        assert_eq!(rest.len(), 49 + 1);
+        assert_eq!(rest.last().map(|n| n.as_str()), Some("new"));
+        let mut all: Vec<String> = first.iter().chain(rest.iter()).cloned().collect();
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 100);
+        assert_eq!(p.readdir(fd), Ok(None));
+
+        // Going back to a cookie repeats what came after it
+        p.seekdir(fd, cookie).unwrap();
+        assert_eq!(p.readdir(fd).unwrap().unwrap().name, first[0].as_bytes());
+        p.seekdir(fd, 0).unwrap();
+        assert_eq!(list(&p, fd).len(), 2 + 2 + 98 + 1);
+        p.closedir(fd).unwrap();
+
+        let fd = p.opendir_sorted("d").unwrap();
+        p.unlink("d/f050").unwrap();
+        p.mkdir("d/a").unwrap();
+        let sorted = list(&p, fd);
+        let mut expect: Vec<String> = names.iter().enumerate()
+            .filter(|&(i, name)| i != gone && i != 70 && name != "f050")
+            .map(|(_, name)| name.clone()).collect();
+        expect.sort();
+        expect.extend(vec!["link".to_string(), "new".to_string(), "sub".to_string()]);
+        expect.insert(0, "..".to_string());
+        expect.insert(0, ".".to_string());
+        assert_eq!(sorted, expect);
+
+        // Not a directory handle, not a directory
+        let file = p.open("d/f001", O_RDONLY).unwrap();
+        assert_eq!(p.readdir(file), Err(FsError::ENOTDIR));
+        assert_eq!(block_on(p.read(fd, &mut [0u8; 4])), Err(FsError::EISDIR));
+        assert_eq!(p.opendir("d/f001"), Err(FsError::ENOTDIR));
+        p.chmod("d", 0o311).unwrap();
+        p.set_cred(Cred::new(5, 5, Vec::new()));
+        assert_eq!(p.opendir("d"), Err(FsError::EACCES));
+    }
+
+    #[test]
+    fn test_names() {
+        let mut p = Proc::new();
+        let mut buf = [0u8; 4];
+
+        // Names built at run time, and names that are not UTF-8
+        for i in 0..10 {
+            let fd = p.open(format!("file{}", i), O_WRONLY | O_CREAT).unwrap();
+            block_on(p.write(fd, &[i as u8])).unwrap();
+        }
+        let odd: &[u8] = b"caf\xe9\xff";
+        let fd = p.open(odd, O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, b"odd")).unwrap();
+        p.mkdir(b"dir\x80".to_vec()).unwrap();
+        p.chdir(&b"dir\x80"[..]).unwrap();
+        assert_eq!(p.getcwd(), Ok(b"/dir\x80".to_vec()));
+        p.chdir("/").unwrap();
+
+        let longest = vec![b'n'; NAME_MAX];
+        let fd = p.open(&longest, O_WRONLY | O_CREAT).unwrap();
+        p.close(fd).unwrap();
+        let too_long = vec![b'n'; NAME_MAX + 1];
+        assert_eq!(p.open(&too_long, O_WRONLY | O_CREAT), Err(FsError::ENAMETOOLONG));
+        assert_eq!(p.stat(&too_long), Err(FsError::ENAMETOOLONG));
+        assert_eq!(p.mkdir(b"nul\0byte"), Err(FsError::EINVAL));
+        assert_eq!(p.open(b"nul\0byte", O_RDONLY), Err(FsError::EINVAL));
+        assert_eq!(p.stat(vec![b'a'; PATH_MAX + 1]), Err(FsError::ENAMETOOLONG));
+
+        // They all make it to disk and back
+        let dev: Box<dyn BlockDevice> = Box::new(MemDevice::new(4096, 256));
+        block_on(mkfs(&*dev)).unwrap();
+        let mut m = block_on(Proc::mount(dev)).unwrap();
+        let fd = m.open(odd, O_RDWR | O_CREAT).unwrap();
+        block_on(m.write(fd, b"odd")).unwrap();
+        m.open(&longest, O_WRONLY | O_CREAT).unwrap();
+        let mut m = block_on(Proc::mount(block_on(m.unmount()).unwrap())).unwrap();
+        let fd = m.open(odd, O_RDONLY).unwrap();
+        assert_eq!(block_on(m.read(fd, &mut buf)), Ok(3));
+        assert!(m.stat(&longest).is_ok());
+    }
+
+    #[test]
+    fn test_max_singly_file_size() {
+        const SIZE: usize = 4096 * 256;
+        let mut p = Proc::new();
+        let mut data = rand_array(SIZE);
+        let mut buf = [0u8; SIZE];
+        let filename = "first_file";
+
+        let fd = p.open(filename, O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &mut data)).unwrap();
+        p.seek(fd, 0, SeekSet).unwrap();
+        block_on(p.read(fd, &mut buf)).unwrap();
+
+        assert_eq_buf(&data, &buf);
+
+        p.close(fd).unwrap();
+        p.unlink(filename).unwrap();
+
+        assert_eq!(p.open(filename, O_RDWR), Err(FsError::ENOENT));
+    }
+
+    #[test]
+    fn test_max_file_size() {
+        const SIZE: usize = 2 * 4096 * 256;
+        // Well past what the old single/double indirect lists could hold
+        const FAR: i64 = 5 * (1 << 30) + 123;
+        let mut p = Proc::new();
+        let mut data1 = rand_array(SIZE);
+        let mut data2 = rand_array(SIZE);
+        let mut buf = vec![0; SIZE];
+        let filename = "first_file";
+
+        let fd = p.open(filename, O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &mut data1)).unwrap();
+        p.seek(fd, FAR, SeekSet).unwrap();
+        block_on(p.write(fd, &mut data2)).unwrap();
+        assert_eq!(p.seek(fd, 0, SeekEnd), Ok(FAR as u64 + SIZE as u64));
+
+        p.seek(fd, 0, SeekSet).unwrap();
+        block_on(p.read(fd, &mut buf)).unwrap();
+        assert_eq_buf(&data1, &buf);
+
+        p.seek(fd, FAR, SeekSet).unwrap();
+        block_on(p.read(fd, &mut buf)).unwrap();
+        assert_eq_buf(&data2, &buf);
+        // Data is tracked per page
+        assert_eq!(p.seek(fd, SIZE as i64, SeekData), Ok(FAR as u64 / 4096 * 4096));
+    }
+
+    #[test]
+    fn test_morethan_max_file_size() {
+        let mut p = Proc::new();
+        let data = rand_array(2);
+        let filename = "first_file";
+
+        let fd = p.open(filename, O_RDWR | O_CREAT).unwrap();
+        p.seek(fd, MAX_FILE_SIZE as i64 - 1, SeekSet).unwrap();
+        assert_eq!(block_on(p.write(fd, &data)), Err(FsError::EFBIG));
+        assert_eq!(block_on(p.write(fd, &data[..1])), Ok(1));
+        assert_eq!(p.seek(fd, 0, SeekEnd), Ok(MAX_FILE_SIZE));
+    }
+
+    #[test]
+    fn test_bad_fd() {
+        let mut p = Proc::new();
+        let mut buf = [0u8; 16];
+
+        assert_eq!(block_on(p.read(3, &mut buf)), Err(FsError::EBADF));
+        assert_eq!(block_on(p.write(3, &buf)), Err(FsError::EBADF));
+        assert_eq!(p.seek(3, 0, SeekSet), Err(FsError::EBADF));
+        assert_eq!(p.close(3), Err(FsError::EBADF));
+
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        assert_eq!(p.seek(fd, -1, SeekSet), Err(FsError::EINVAL));
+        p.close(fd).unwrap();
+        assert_eq!(p.close(fd), Err(FsError::EBADF));
+        assert_eq!(FsError::EBADF.errno(), 9);
+    }
+
+    #[test]
+    fn test_fd_table() {
+        let mut p = Proc::new();
+        assert_eq!(p.fd_limit(), DEFAULT_FD_LIMIT);
+
+        // Always the lowest free descriptor
+        let fds: Vec<_> = (0..4).map(|_| p.open("file", O_RDWR | O_CREAT).unwrap()).collect();
+        assert_eq!(fds, vec![0, 1, 2, 3]);
+        p.close(1).unwrap();
+        p.close(2).unwrap();
+        assert_eq!(p.opendir("/"), Ok(1));
+        assert_eq!(p.open("file", O_RDONLY), Ok(2));
+
+        // Nothing is created once the table is full
+        p.set_fd_limit(4);
+        assert_eq!(p.open("other", O_RDWR | O_CREAT), Err(FsError::EMFILE));
+        assert_eq!(p.stat("other").err(), Some(FsError::ENOENT));
+        assert_eq!(p.opendir("/"), Err(FsError::EMFILE));
+        assert_eq!(p.dup(0), Err(FsError::EMFILE));
+        p.set_fd_limit(8);
+        assert_eq!(p.dup2(0, 8), Err(FsError::EBADF));
+        assert_eq!(p.fcntl(0, F_DUPFD, 8), Err(FsError::EINVAL));
+        assert_eq!(p.fcntl(0, F_DUPFD, 7), Ok(7));
+        assert_eq!(p.fcntl(0, F_DUPFD, 7), Err(FsError::EMFILE));
+        assert_eq!(p.fcntl(0, F_DUPFD, 5), Ok(5));
+        assert_eq!(p.dup(0), Ok(4));
+        assert_eq!(p.fcntl(0, 99, 0), Err(FsError::EINVAL));
+    }
+
+    #[test]
+    fn test_dup() {
+        let mut p = Proc::new();
+        let mut buf = [0u8; 4];
+
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, b"abcdefgh")).unwrap();
+        p.seek(fd, 0, SeekSet).unwrap();
+
+        // Duplicates share the offset...
+        let dup = p.dup(fd).unwrap();
+        assert_eq!(block_on(p.read(fd, &mut buf[..2])), Ok(2));
+        assert_eq!(p.seek(dup, 0, SeekCur), Ok(2));
+        assert_eq!(block_on(p.read(dup, &mut buf[..2])), Ok(2));
+        assert_eq_buf(b"cd", &buf[..2]);
+        assert_eq!(p.seek(fd, 0, SeekCur), Ok(4));
+
+        // ...but a second open of the same file does not
+        let other = p.open("file", O_RDONLY).unwrap();
+        assert_eq!(block_on(p.read(other, &mut buf)), Ok(4));
+        assert_eq_buf(b"abcd", &buf);
+
+        // dup2 closes what was open at the target, and the description
+        // lives until its last descriptor is closed
+        assert_eq!(p.dup2(fd, other), Ok(other));
+        assert_eq!(p.dup2(fd, fd), Ok(fd));
+        p.close(fd).unwrap();
+        p.close(dup).unwrap();
+        assert_eq!(block_on(p.read(other, &mut buf)), Ok(4));
+        assert_eq_buf(b"efgh", &buf);
+        assert_eq!(p.dup(fd), Err(FsError::EBADF));
+        assert_eq!(p.dup2(fd, other), Err(FsError::EBADF));
+        assert_eq!(p.dup2(other, -1), Err(FsError::EBADF));
+
+        // So do the status flags
+        let dup = p.fcntl(other, F_DUPFD, 0).unwrap();
+        assert_eq!(p.fcntl(dup, F_GETFL, 0), Ok(O_RDWR as isize));
+        assert_eq!(p.fcntl(other, F_SETFL, (O_APPEND | O_NONBLOCK | O_RDONLY) as isize), Ok(0));
+        assert_eq!(p.fcntl(dup, F_GETFL, 0), Ok((O_RDWR | O_APPEND | O_NONBLOCK) as isize));
+        p.seek(dup, 0, SeekSet).unwrap();
+        block_on(p.write(dup, b"ij")).unwrap();
+        assert_eq!(p.seek(other, 0, SeekCur), Ok(10));
+    }
+
+    #[test]
+    fn test_nested_paths() {
+        const SIZE: usize = 4096 + 123;
+        let mut p = Proc::new();
+        let data = rand_array(SIZE);
+        let mut buf = [0u8; SIZE];
+
+        p.mkdir("a").unwrap();
+        p.mkdir("a/b").unwrap();
+        assert_eq!(p.mkdir("a/b"), Err(FsError::EEXIST));
+        assert_eq!(p.mkdir("x/y"), Err(FsError::ENOENT));
+
+        let fd = p.open("a/b/c.txt", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &data)).unwrap();
+        p.close(fd).unwrap();
+
+        let fd2 = p.open("/a/./b/../b//c.txt", O_RDWR).unwrap();
+        block_on(p.read(fd2, &mut buf)).unwrap();
+        assert_eq_buf(&data, &buf);
+        p.close(fd2).unwrap();
+
+        assert_eq!(p.open("a/c.txt", O_RDWR), Err(FsError::ENOENT));
+        assert_eq!(p.open("a/b/c.txt/d", O_RDWR | O_CREAT), Err(FsError::ENOTDIR));
+        assert_eq!(p.open("a/b", O_RDWR), Err(FsError::EISDIR));
+    }
+
+    #[test]
+    fn test_chdir_getcwd() {
+        let mut p = Proc::new();
+        assert_eq!(p.getcwd(), Ok(b"/".to_vec()));
+
+        p.mkdir("a").unwrap();
+        p.mkdir("a/b").unwrap();
+        p.chdir("a/b").unwrap();
+        assert_eq!(p.getcwd(), Ok(b"/a/b".to_vec()));
+
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        p.close(fd).unwrap();
+        p.chdir("..").unwrap();
+        assert_eq!(p.getcwd(), Ok(b"/a".to_vec()));
+
+        let fd2 = p.open("b/file", O_RDWR).unwrap();
+        p.close(fd2).unwrap();
+
+        p.chdir("/..").unwrap();
+        assert_eq!(p.getcwd(), Ok(b"/".to_vec()));
+        assert_eq!(p.chdir("a/b/file"), Err(FsError::ENOTDIR));
+        assert_eq!(p.chdir("nope"), Err(FsError::ENOENT));
+    }
+
+    #[test]
+    fn test_rmdir() {
+        let mut p = Proc::new();
+        p.mkdir("a").unwrap();
+        p.mkdir("a/b").unwrap();
+        let fd = p.open("a/file", O_RDWR | O_CREAT).unwrap();
+        p.close(fd).unwrap();
+
+        assert_eq!(p.rmdir("a"), Err(FsError::ENOTEMPTY));
+        assert_eq!(p.rmdir("a/file"), Err(FsError::ENOTDIR));
+        p.rmdir("a/b").unwrap();
+        assert_eq!(p.rmdir("a/b"), Err(FsError::ENOENT));
+        assert_eq!(p.rmdir("a/."), Err(FsError::EINVAL));
+
+        assert_eq!(p.unlink("a"), Err(FsError::EISDIR));
+        p.chdir("a").unwrap();
+        p.unlink("/a/file").unwrap();
+        p.chdir("/").unwrap();
+        p.rmdir("a").unwrap();
+        assert_eq!(p.chdir("a"), Err(FsError::ENOENT));
+    }
+
+    #[test]
+    fn test_getcwd_removed() {
+        let mut p = Proc::new();
+        p.mkdir("a").unwrap();
+        p.chdir("a").unwrap();
+        p.rmdir("/a").unwrap();
+        assert_eq!(p.getcwd(), Err(FsError::ENOENT));
+    }
+
+    #[test]
+    fn test_sparse_read() {
+        const SIZE: usize = 4096 + 100;
+        let mut p = Proc::new();
+        let data = rand_array(SIZE);
+        let mut buf = vec![0xffu8; 3 * 4096 + SIZE];
+
+        // Leave the first three pages (and a double-indirect list) as holes
+        let fd = p.open("sparse", O_RDWR | O_CREAT).unwrap();
+        p.seek(fd, 3 * 4096, SeekSet).unwrap();
+        block_on(p.write(fd, &data)).unwrap();
+        p.seek(fd, 4096 * 300, SeekSet).unwrap();
+        block_on(p.write(fd, &data)).unwrap();
+
+        p.seek(fd, 0, SeekSet).unwrap();
+        assert_eq!(block_on(p.read(fd, &mut buf)).unwrap(), buf.len());
+        assert!(buf[..3 * 4096].iter().all(|&b| b == 0));
+        assert_eq_buf(&data, &buf[3 * 4096..]);
+
+        p.seek(fd, 4096 * 299, SeekSet).unwrap();
+        let mut buf2 = vec![0xffu8; 4096];
+        assert_eq!(block_on(p.read(fd, &mut buf2)).unwrap(), 4096);
+        assert!(buf2.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_read_eof() {
+        let mut p = Proc::new();
+        let data = rand_array(5000);
+        let mut buf = [0u8; 4096];
+
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &data)).unwrap();
+        p.seek(fd, 4096, SeekSet).unwrap();
+        assert_eq!(block_on(p.read(fd, &mut buf)).unwrap(), 5000 - 4096);
+        assert_eq_buf(&data[4096..], &buf[..5000 - 4096]);
+        assert_eq!(block_on(p.read(fd, &mut buf)).unwrap(), 0);
+
+        p.seek(fd, 100000, SeekSet).unwrap();
+        assert_eq!(block_on(p.read(fd, &mut buf)).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_seek_data_hole() {
+        let mut p = Proc::new();
+        let data = rand_array(4096);
+
+        // Layout: hole [0, 2p), data [2p, 3p), hole [3p, 600p), data [600p, 601p)
+        let fd = p.open("sparse", O_RDWR | O_CREAT).unwrap();
+        p.seek(fd, 2 * 4096, SeekSet).unwrap();
+        block_on(p.write(fd, &data)).unwrap();
+        p.seek(fd, 600 * 4096, SeekSet).unwrap();
+        block_on(p.write(fd, &data)).unwrap();
+
+        assert_eq!(p.seek(fd, 0, SeekData), Ok(2 * 4096));
+        assert_eq!(p.seek(fd, 2 * 4096 + 10, SeekData), Ok(2 * 4096 + 10));
+        assert_eq!(p.seek(fd, 3 * 4096, SeekData), Ok(600 * 4096));
+        assert_eq!(p.seek(fd, 0, SeekHole), Ok(0));
+        assert_eq!(p.seek(fd, 2 * 4096, SeekHole), Ok(3 * 4096));
+        assert_eq!(p.seek(fd, 600 * 4096, SeekHole), Ok(601 * 4096));
+        assert_eq!(p.seek(fd, 601 * 4096, SeekData), Err(FsError::ENXIO));
+        assert_eq!(p.seek(fd, 601 * 4096, SeekHole), Err(FsError::ENXIO));
+    }
+
+    #[test]
+    fn test_pread_pwrite() {
+        let mut p = Proc::new();
+        let data = rand_array(3 * 4096);
+        let mut buf = vec![0u8; 4096];
+
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        assert_eq!(block_on(p.pwrite(fd, &data, 4096)), Ok(3 * 4096));
+        assert_eq!(p.seek(fd, 0, SeekCur), Ok(0));
+        assert_eq!(p.seek(fd, 0, SeekEnd), Ok(4 * 4096));
+
+        // The hole in front reads back as zeros; reads stop at the end
+        assert_eq!(block_on(p.pread(fd, &mut buf, 10)), Ok(4096));
+        assert!(buf[..4096 - 10].iter().all(|&b| b == 0));
+        assert_eq_buf(&data[..10], &buf[4096 - 10..]);
+        assert_eq!(block_on(p.pread(fd, &mut buf, 4 * 4096 - 100)), Ok(100));
+        assert_eq!(block_on(p.pread(fd, &mut buf, 5 * 4096)), Ok(0));
+        assert_eq!(p.seek(fd, 0, SeekCur), Ok(4 * 4096));
+
+        // O_APPEND does not apply to pwrite
+        let afd = p.open("file", O_WRONLY | O_APPEND).unwrap();
+        assert_eq!(block_on(p.pwrite(afd, b"abc", 0)), Ok(3));
+        assert_eq!(block_on(p.pread(fd, &mut buf[..3], 0)), Ok(3));
+        assert_eq!(&buf[..3], b"abc");
+        assert_eq!(block_on(p.pread(afd, &mut buf, 0)), Err(FsError::EBADF));
+
+        let rfd = p.open("file", O_RDONLY).unwrap();
+        assert_eq!(block_on(p.pwrite(rfd, b"abc", 0)), Err(FsError::EBADF));
+        assert_eq!(block_on(p.pwrite(rfd + 1, b"abc", 0)), Err(FsError::EBADF));
+    }
+
+    #[test]
+    fn test_pread_concurrent() {
+        let mut p = Proc::new();
+        let data = rand_array(64 * 4096);
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &data)).unwrap();
+
+        // Readers sharing one descriptor each get the bytes they asked for
+        let p = Arc::new(p);
+        let data = Arc::new(data);
+        let readers: Vec<_> = (0..4).map(|t| {
+            let (p, data) = (p.clone(), data.clone());
+            thread::spawn(move || {
+                let mut buf = vec![0u8; 1000];
+                for i in 0..200 {
+                    let offset = (i * 4099 + t * 1237) % (data.len() - buf.len());
+                    assert_eq!(block_on(p.pread(fd, &mut buf, offset as u64)), Ok(buf.len()));
+                    assert_eq_buf(&data[offset..offset + buf.len()], &buf);
+                }
+            })
+        }).collect();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        let mut p = Arc::try_unwrap(p).ok().unwrap();
+        assert_eq!(p.seek(fd, 0, SeekCur), Ok(64 * 4096));
+    }
+
+    #[test]
+    fn test_readv_writev() {
+        let mut p = Proc::new();
+        let data = rand_array(2 * 4096 + 100);
+        let (head, rest) = data.split_at(10);
+        let (middle, tail) = rest.split_at(4096);
+
+        let fd = p.open("log", O_RDWR | O_CREAT | O_APPEND).unwrap();
+        assert_eq!(block_on(p.writev(fd, &[head, &[], middle, tail])), Ok(data.len()));
+        assert_eq!(block_on(p.writev(fd, &[])), Ok(0));
+        assert_eq!(p.seek(fd, 0, SeekCur), Ok(data.len() as u64));
+
+        // Buffers are filled in order; the last one is cut short at the end
+        // of the file and the ones after it stay untouched
+        let mut a = vec![0u8; 4000];
+        let mut b = vec![0u8; 5000];
+        let mut c = vec![1u8; 10];
+        p.seek(fd, 0, SeekSet).unwrap();
+        assert_eq!(block_on(p.readv(fd, &mut [&mut a, &mut b, &mut c])), Ok(data.len()));
+        assert_eq_buf(&data[..4000], &a);
+        assert_eq_buf(&data[4000..], &b[..data.len() - 4000]);
+        assert!(c.iter().all(|&b| b == 1));
+        assert_eq!(p.seek(fd, 0, SeekCur), Ok(data.len() as u64));
+
+        // The positional variants leave the offset alone
+        assert_eq!(block_on(p.pwritev(fd, &[&b"ab"[..], &b"cd"[..]], 4096 - 2)), Ok(4));
+        {
+            let (x, y) = c.split_at_mut(1);
+            assert_eq!(block_on(p.preadv(fd, &mut [x, &mut y[..3]], 4096 - 2)), Ok(4));
+        }
+        assert_eq!(&c[..4], b"abcd");
+        assert_eq!(p.seek(fd, 0, SeekCur), Ok(data.len() as u64));
+
+        // Too much data is refused as a whole
+        assert_eq!(block_on(p.pwritev(fd, &[&b"ab"[..], &b"cd"[..]], MAX_FILE_SIZE - 3)), Err(FsError::EFBIG));
+        assert_eq!(p.seek(fd, 0, SeekEnd), Ok(data.len() as u64));
+
+        // Writing nothing past the end leaves the file alone
+        let stat = p.fstat(fd).unwrap();
+        assert_eq!(block_on(p.pwrite(fd, b"", 3 * 4096 + 7)), Ok(0));
+        assert_eq!(block_on(p.pwritev(fd, &[&b""[..], &b""[..]], 5 * 4096 + 1)), Ok(0));
+        assert_eq!(p.fstat(fd).unwrap(), stat);
+    }
+
+    #[test]
+    fn test_map() {
+        let dev = Arc::new(MemDevice::new(4096, 256));
+        block_on(mkfs(&*dev)).unwrap();
+        let crash = CrashDevice { dev: dev.clone(), budget: Arc::new(AtomicUsize::new(usize::max_value())) };
+        let mut p = block_on(Proc::mount(Box::new(crash))).unwrap();
+        let mut want = rand_array(3 * 4096 + 100);
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &want)).unwrap();
+        block_on(p.ftruncate(fd, 5 * 4096)).unwrap();
+        want.resize(5 * 4096, 0);
+
+        // One slice per page, holes included, up to the end of the file
+        {
+            let view = block_on(p.map(fd, 10, 10 * 4096)).unwrap();
+            assert_eq!(view.len(), 5 * 4096 - 10);
+            let pages: Vec<&[u8]> = view.pages().collect();
+            assert_eq!(pages.len(), 5);
+            assert_eq!(pages[0].len(), 4096 - 10);
+            assert_eq_buf(&want[10..], &pages.concat());
+        }
+        assert!(block_on(p.map(fd, 5 * 4096, 1)).unwrap().is_empty());
+        assert_eq!(block_on(p.map(fd, 0, 0)).err(), Some(FsError::EINVAL));
+
+        // A view locks nothing: the file can be changed and synced from the
+        // same thread meanwhile, and the view keeps what it saw
+        {
+            let view = block_on(p.map(fd, 0, 4096)).unwrap();
+            block_on(p.pwrite(fd, b"new", 0)).unwrap();
+            block_on(p.sync()).unwrap();
+            assert_eq_buf(&want[..4096], view.pages().next().unwrap());
+            block_on(p.pwrite(fd, &want[..3], 0)).unwrap();
+        }
+
+        // Writing in place, across a page boundary and past the end
+        {
+            let mut view = block_on(p.map_mut(fd, 4096 - 5, 4096 + 10)).unwrap();
+            for page in view.pages_mut() {
+                for byte in page.iter_mut() { *byte = 0xab; }
+            }
+        }
+        for byte in want[4096 - 5..2 * 4096 + 5].iter_mut() { *byte = 0xab; }
+        block_on(p.sync()).unwrap();
+        {
+            let mut view = block_on(p.map_mut(fd, 6 * 4096, 100)).unwrap();
+            let pages: Vec<&mut [u8]> = view.pages_mut().collect();
+            pages.into_iter().next().unwrap().copy_from_slice(&[7u8; 100]);
+        }
+        want.resize(6 * 4096, 0);
+        want.extend_from_slice(&[7u8; 100]);
+        let mut buf = vec![0u8; want.len() + 1];
+        assert_eq!(block_on(p.pread(fd, &mut buf, 0)), Ok(want.len()));
+        assert_eq_buf(&want, &buf[..want.len()]);
+
+        // What a view writes reaches the file when it is dropped, next to
+        // what was written to the rest of its pages meanwhile. The blocks
+        // of the view cannot go away until then.
+        {
+            let mut view = block_on(p.map_mut(fd, 10, 10)).unwrap();
+            view.pages_mut().next().unwrap().copy_from_slice(b"0123456789");
+            block_on(p.pwrite(fd, b"xy", 30)).unwrap();
+            assert_eq!(block_on(p.pread(fd, &mut buf[..20], 0)), Ok(20));
+            assert_eq_buf(&want[..20], &buf[..20]);
+            assert_eq!(block_on(p.ftruncate(fd, 0)), Err(FsError::EBUSY));
+            let punch = FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE;
+            assert_eq!(block_on(p.fallocate(fd, punch, 0, 4096)), Err(FsError::EBUSY));
+        }
+        want[10..20].copy_from_slice(b"0123456789");
+        want[30..32].copy_from_slice(b"xy");
+        assert_eq!(block_on(p.pread(fd, &mut buf, 0)), Ok(want.len()));
+        assert_eq_buf(&want, &buf[..want.len()]);
+
+        // Inside the range too, only what the view changed goes to the file:
+        // a byte written meanwhile stays unless the view changed it as well
+        block_on(p.pwrite(fd, &[0u8; 100], 100)).unwrap();
+        {
+            let mut view = block_on(p.map_mut(fd, 100, 100)).unwrap();
+            view.pages_mut().next().unwrap()[..10].copy_from_slice(b"abcdefghij");
+            block_on(p.pwrite(fd, b"XYZ", 108)).unwrap();
+            block_on(p.pwrite(fd, b"!", 150)).unwrap();
+        }
+        for byte in want[100..200].iter_mut() { *byte = 0; }
+        want[100..110].copy_from_slice(b"abcdefghij");
+        want[110] = b'Z';
+        want[150] = b'!';
+        assert_eq!(block_on(p.pread(fd, &mut buf, 0)), Ok(want.len()));
+        assert_eq_buf(&want, &buf[..want.len()]);
+
+        // The same through the buffers holding the pages, which are ordinary
+        // memory off SPDK. Bytes outside the range are not part of the view.
+        {
+            let mut view = block_on(p.map_mut(fd, 4096 - 2, 4)).unwrap();
+            for (buf, from, to) in view.bufs_mut() {
+                assert!(buf.env_buf().is_none());
+                for byte in buf[from..to].iter_mut() { *byte = 9; }
+                buf[2] = !buf[2];
+            }
+        }
+        for byte in want[(4096 - 2)..(4096 + 2)].iter_mut() { *byte = 9; }
+        {
+            let view = block_on(p.map(fd, 4096 - 2, 4)).unwrap();
+            let bufs: Vec<_> = view.bufs().collect();
+            assert_eq!(bufs.iter().map(|&(_, from, to)| (from, to)).collect::<Vec<_>>(), vec![(4094, 4096), (0, 2)]);
+            assert_eq_buf(&want[..4096], bufs[0].0.unwrap());
+        }
+        assert_eq!(block_on(p.pread(fd, &mut buf, 0)), Ok(want.len()));
+        assert_eq_buf(&want, &buf[..want.len()]);
+
+        // Pages changed through a view are dirty, so fdatasync writes them
+        block_on(p.fsync(fd)).unwrap();
+        block_on(p.map_mut(fd, 2 * 4096, 4096)).unwrap().pages_mut().next().unwrap()[0] = 1;
+        want[2 * 4096] = 1;
+        block_on(p.fdatasync(fd)).unwrap();
+
+        let ro = p.open("file", O_RDONLY).unwrap();
+        assert_eq!(block_on(p.map_mut(ro, 0, 1)).err(), Some(FsError::EBADF));
+        let wo = p.open("file", O_WRONLY).unwrap();
+        assert_eq!(block_on(p.map(wo, 0, 1)).err(), Some(FsError::EBADF));
+        assert_eq!(block_on(p.map_mut(wo, MAX_FILE_SIZE, 1)).err(), Some(FsError::EFBIG));
+        assert_eq!(p.fstat(fd).unwrap().size, want.len() as u64);
+
+        // Lose the file system without unmounting it
+        drop(p);
+        let crash = CrashDevice { dev: dev.clone(), budget: Arc::new(AtomicUsize::new(usize::max_value())) };
+        let mut p = block_on(Proc::mount(Box::new(crash))).unwrap();
+        let fd = p.open("file", O_RDONLY).unwrap();
+        let view = block_on(p.map(fd, 0, want.len() as u64)).unwrap();
+        assert_eq_buf(&want, &view.pages().collect::<Vec<&[u8]>>().concat());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut p = Proc::new();
+        let data = rand_array(3 * 4096);
+        let mut buf = vec![0u8; 3 * 4096];
+
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &data)).unwrap();
+
+        // Shrink into the middle of the second page, then grow again: the
+        // old tail must not come back
+        block_on(p.ftruncate(fd, 4096 + 10)).unwrap();
+        assert_eq!(p.seek(fd, 0, SeekEnd), Ok(4096 + 10));
+        block_on(p.truncate("file", 3 * 4096)).unwrap();
+        p.seek(fd, 0, SeekSet).unwrap();
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(3 * 4096));
+        assert_eq_buf(&data[..4096 + 10], &buf[..4096 + 10]);
+        assert!(buf[4096 + 10..].iter().all(|&b| b == 0));
+        assert_eq!(p.seek(fd, 0, SeekHole), Ok(2 * 4096));
+
+        // Growing is sparse
+        block_on(p.ftruncate(fd, 1 << 40)).unwrap();
+        assert_eq!(p.seek(fd, 2 * 4096, SeekData), Err(FsError::ENXIO));
+        block_on(p.ftruncate(fd, 0)).unwrap();
+        p.seek(fd, 0, SeekSet).unwrap();
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(0));
+
+        assert_eq!(block_on(p.truncate("nothing", 0)), Err(FsError::ENOENT));
+        p.mkdir("dir").unwrap();
+        assert_eq!(block_on(p.truncate("dir", 0)), Err(FsError::EISDIR));
+        assert_eq!(block_on(p.ftruncate(42, 0)), Err(FsError::EBADF));
+    }
+
+    #[test]
+    fn test_open_trunc() {
+        let dev: Box<dyn BlockDevice> = Box::new(MemDevice::new(4096, 256));
+        block_on(mkfs(&*dev)).unwrap();
+        let mut p = block_on(Proc::mount(dev)).unwrap();
+        let mut buf = [0u8; 16];
+
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &rand_array(16 * 4096))).unwrap();
+        let free = p.statfs().unwrap().free_blocks;
+
+        // The blocks past the new end go back to the allocator
+        let fd = p.open("file", O_RDWR | O_TRUNC).unwrap();
+        assert_eq!(p.statfs().unwrap().free_blocks, free + 16);
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(0));
+        block_on(p.write(fd, b"hello")).unwrap();
+
+        let mut p = block_on(Proc::mount(block_on(p.unmount()).unwrap())).unwrap();
+        let fd = p.open("file", O_RDWR).unwrap();
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(5));
+        assert_eq_buf(b"hello", &buf[..5]);
+    }
+
+    #[test]
+    fn test_open_flags() {
+        let mut p = Proc::new();
+        let mut buf = [0u8; 8];
+
+        assert_eq!(p.open("file", O_CREAT), Err(FsError::EINVAL));
+        assert_eq!(p.open("file", O_RDONLY | O_WRONLY | O_CREAT), Err(FsError::EINVAL));
+        let w = p.open("file", O_WRONLY | O_CREAT | O_EXCL).unwrap();
+        assert_eq!(p.open("file", O_RDWR | O_CREAT | O_EXCL), Err(FsError::EEXIST));
+        assert_eq!(block_on(p.read(w, &mut buf)), Err(FsError::EBADF));
+        block_on(p.write(w, b"abcd")).unwrap();
+
+        let r = p.open("file", O_RDONLY).unwrap();
+        assert_eq!(block_on(p.write(r, b"x")), Err(FsError::EBADF));
+        assert_eq!(block_on(p.ftruncate(r, 0)), Err(FsError::EINVAL));
+        // O_TRUNC only applies to fds that can write
+        let r = p.open("file", O_RDONLY | O_TRUNC).unwrap();
+        assert_eq!(block_on(p.read(r, &mut buf)), Ok(4));
+
+        // Appends go to the end whatever the offset, also after another fd
+        // grew the file
+        let a = p.open("file", O_WRONLY | O_APPEND).unwrap();
+        p.seek(a, 0, SeekSet).unwrap();
+        block_on(p.write(a, b"ef")).unwrap();
+        block_on(p.write(w, b"gh")).unwrap();
+        block_on(p.write(a, b"ij")).unwrap();
+        assert_eq!(p.seek(a, 0, SeekCur), Ok(8));
+
+        let r = p.open("file", O_RDONLY).unwrap();
+        assert_eq!(block_on(p.read(r, &mut buf)), Ok(8));
+        assert_eq_buf(b"abcdghij", &buf);
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut p = Proc::new();
+        let mut buf = [0u8; 8];
+
+        p.mkdir("a").unwrap();
+        p.mkdir("a/b").unwrap();
+        p.mkdir("c").unwrap();
+        let fd = p.open("a/file", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, b"old")).unwrap();
+        let fd = p.open("tmp", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, b"new")).unwrap();
+
+        // Replaces the target; fds on the source follow it
+        p.rename("tmp", "a/file").unwrap();
+        assert_eq!(p.open("tmp", O_RDONLY), Err(FsError::ENOENT));
+        let r = p.open("a/file", O_RDONLY).unwrap();
+        assert_eq!(block_on(p.read(r, &mut buf)), Ok(3));
+        assert_eq_buf(b"new", &buf[..3]);
+        block_on(p.write(fd, b"!")).unwrap();
+        assert_eq!(block_on(p.read(r, &mut buf)), Ok(1));
+
+        // Directories move with everything below them and ".." follows
+        p.chdir("a/b").unwrap();
+        p.rename("/a", "/c/moved").unwrap();
+        assert_eq!(p.getcwd(), Ok(b"/c/moved/b".to_vec()));
+        p.chdir("..").unwrap();
+        assert_eq!(p.getcwd(), Ok(b"/c/moved".to_vec()));
+        p.open("file", O_RDONLY).unwrap();
+        p.chdir("/").unwrap();
+
+        assert_eq!(p.rename("c", "c/moved/b/below"), Err(FsError::EINVAL));
+        assert_eq!(p.rename("c/moved", "c/moved"), Ok(()));
+        assert_eq!(p.rename("c/moved/file", "c/moved/b"), Err(FsError::EISDIR));
+        assert_eq!(p.rename("c/moved/b", "c/moved/file"), Err(FsError::ENOTDIR));
+        assert_eq!(p.rename("c/moved", "c/moved/b/.."), Err(FsError::EINVAL));
+        assert_eq!(p.rename("nothing", "x"), Err(FsError::ENOENT));
+
+        // A directory replaces an empty directory only
+        p.mkdir("empty").unwrap();
+        assert_eq!(p.rename("empty", "c"), Err(FsError::ENOTEMPTY));
+        p.rename("c", "empty").unwrap();
+        p.open("empty/moved/file", O_RDONLY).unwrap();
+        assert_eq!(p.open("c/moved/file", O_RDONLY), Err(FsError::ENOENT));
+    }
+
+    // Wraps a device and fails every write once `budget` writes went
+    // through, like a machine losing power.
+    struct CrashDevice {
+        dev: Arc<MemDevice>,
+        budget: Arc<AtomicUsize>
+    }
+
+    impl BlockDevice for CrashDevice {
+        fn block_size(&self) -> usize { self.dev.block_size() }
+        fn num_blocks(&self) -> u64 { self.dev.num_blocks() }
+
+        fn read_blocks<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> IoFuture<'a, ()> {
+            self.dev.read_blocks(offset, buf)
+        }
+
+        fn write_blocks<'a>(&'a self, offset: u64, buf: &'a [u8]) -> IoFuture<'a, ()> {
+            if self.budget.load(Ordering::SeqCst) == 0 { return Box::pin(async { Err(FsError::EIO) }); }
+            self.budget.fetch_sub(1, Ordering::SeqCst);
+            self.dev.write_blocks(offset, buf)
+        }
+
+        fn flush<'a>(&'a self) -> IoFuture<'a, ()> { self.dev.flush() }
+
+        fn unmap_blocks<'a>(&'a self, offset: u64, num: u64) -> IoFuture<'a, ()> {
+            self.dev.unmap_blocks(offset, num)
+        }
+
+        fn write_zeroes_blocks<'a>(&'a self, offset: u64, num: u64) -> IoFuture<'a, ()> {
+            if self.budget.load(Ordering::SeqCst) == 0 { return Box::pin(async { Err(FsError::EIO) }); }
+            self.budget.fetch_sub(1, Ordering::SeqCst);
+            self.dev.write_zeroes_blocks(offset, num)
+        }
+    }
+
+    #[test]
+    fn test_rename_crash_atomic() {
+        let old = rand_array(3 * 4096);
+        let new = rand_array(5 * 4096);
+
+        // Crash after every possible number of writes until the sync
+        // completes; the tree on disk must be either before or after
+        for budget in 0.. {
+            let dev = Arc::new(MemDevice::new(4096, 256));
+            let left = Arc::new(AtomicUsize::new(usize::max_value()));
+            block_on(mkfs(&*dev)).unwrap();
+
+            let crash = CrashDevice { dev: dev.clone(), budget: left.clone() };
+            let mut p = block_on(Proc::mount(Box::new(crash))).unwrap();
+            p.mkdir("dir").unwrap();
+            let fd = p.open("dir/file", O_RDWR | O_CREAT).unwrap();
+            block_on(p.write(fd, &old)).unwrap();
+            block_on(p.sync()).unwrap();
+
+            let fd = p.open("tmp", O_RDWR | O_CREAT).unwrap();
+            block_on(p.write(fd, &new)).unwrap();
+            p.rename("tmp", "dir/file").unwrap();
+            left.store(budget, Ordering::SeqCst);
+            let done = block_on(p.sync()).is_ok();
+            drop(p);
+
+            let crash = CrashDevice { dev: dev.clone(), budget: Arc::new(AtomicUsize::new(usize::max_value())) };
+            let mut p = block_on(Proc::mount(Box::new(crash))).unwrap();
+            let fd = p.open("dir/file", O_RDONLY).unwrap();
+            let mut buf = vec![0u8; new.len() + 1];
+            let len = block_on(p.read(fd, &mut buf)).unwrap();
+            assert_eq!(p.open("tmp", O_RDONLY), Err(FsError::ENOENT));
+            if len == new.len() {
+                assert_eq_buf(&new, &buf[..len]);
+            } else {
+                assert!(!done);
+                assert_eq_buf(&old, &buf[..len]);
+            }
+            if done { break; }
+        }
+    }
+
+    #[test]
+    fn test_fsync() {
+        let dev = Arc::new(MemDevice::new(4096, 256));
+        let left = Arc::new(AtomicUsize::new(usize::max_value()));
+        block_on(mkfs(&*dev)).unwrap();
+
+        let crash = CrashDevice { dev: dev.clone(), budget: left.clone() };
+        let mut p = block_on(Proc::mount(Box::new(crash))).unwrap();
+        let mut data = rand_array(8 * 4096);
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &data)).unwrap();
+        // The file is not on disk yet, so this syncs the whole tree
+        block_on(p.fdatasync(fd)).unwrap();
+
+        // An overwrite in place only writes the pages it touched, here in
+        // one command, and nothing once they are clean
+        let patch = rand_array(4096);
+        block_on(p.pwrite(fd, &patch, 4096 + 10)).unwrap();
+        data[4096 + 10..2 * 4096 + 10].copy_from_slice(&patch);
+        left.store(1, Ordering::SeqCst);
+        block_on(p.fdatasync(fd)).unwrap();
+        block_on(p.fdatasync(fd)).unwrap();
+        assert_eq!(left.load(Ordering::SeqCst), 0);
+
+        // Growing the file changes its size and blocks on disk, which takes
+        // a sync of the whole tree
+        block_on(p.pwrite(fd, b"x", 8 * 4096)).unwrap();
+        data.push(b'x');
+        assert_eq!(block_on(p.fdatasync(fd)), Err(FsError::EIO));
+        left.store(usize::max_value(), Ordering::SeqCst);
+        block_on(p.fsync(fd)).unwrap();
+
+        block_on(p.pwrite(fd, &patch, 0)).unwrap();
+        data[..4096].copy_from_slice(&patch);
+        block_on(p.fdatasync(fd)).unwrap();
+        assert_eq!(block_on(p.syncfs(fd + 1)), Err(FsError::EBADF));
+
+        // Lose the file system without unmounting it: what was synced is
+        // on the device
+        drop(p);
+        let crash = CrashDevice { dev: dev.clone(), budget: left.clone() };
+        let mut p = block_on(Proc::mount(Box::new(crash))).unwrap();
+        let fd = p.open("file", O_RDONLY).unwrap();
+        let mut buf = vec![0u8; data.len() + 1];
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(data.len()));
+        assert_eq_buf(&data, &buf[..data.len()]);
+        block_on(p.syncfs(fd)).unwrap();
+
+        // Nothing to do without a device
+        let mut p = Proc::new();
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &patch)).unwrap();
+        block_on(p.fsync(fd)).unwrap();
+        block_on(p.fdatasync(fd)).unwrap();
+    }
+
+    #[test]
+    fn test_sync_dirty() {
+        let dev = Arc::new(MemDevice::new(4096, 256));
+        let left = Arc::new(AtomicUsize::new(usize::max_value()));
+        block_on(mkfs(&*dev)).unwrap();
+
+        let crash = CrashDevice { dev: dev.clone(), budget: left.clone() };
+        let mut p = block_on(Proc::mount(Box::new(crash))).unwrap();
+        let mut data = rand_array(16 * 4096);
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &data)).unwrap();
+        block_on(p.sync()).unwrap();
+
+        // Writes taken by a sync with nothing to write
+        let used = |p: &Proc, left: &AtomicUsize| {
+            left.store(usize::max_value(), Ordering::SeqCst);
+            block_on(p.sync()).unwrap();
+            usize::max_value() - left.load(Ordering::SeqCst)
+        };
+        let idle = used(&p, &left);
+
+        // Clean pages are not written again, however many there are
+        let more = rand_array(16 * 4096);
+        block_on(p.write(fd, &more)).unwrap();
+        data.extend_from_slice(&more);
+        block_on(p.sync()).unwrap();
+        assert_eq!(used(&p, &left), idle);
+
+        // Only the page changed, in one more command
+        block_on(p.pwrite(fd, b"x", 5 * 4096)).unwrap();
+        data[5 * 4096] = b'x';
+        assert_eq!(used(&p, &left), idle + 1);
+        assert_eq!(used(&p, &left), idle);
+
+        // New blocks of holes are zeroed once
+        block_on(p.fallocate(fd, FALLOC_FL_KEEP_SIZE, data.len() as u64, 4 * 4096)).unwrap();
+        assert_eq!(used(&p, &left), idle + 1);
+        assert_eq!(used(&p, &left), idle);
+
+        let mut p = block_on(Proc::mount(block_on(p.unmount()).unwrap())).unwrap();
+        let fd = p.open("file", O_RDONLY).unwrap();
+        let mut buf = vec![0u8; data.len() + 4 * 4096];
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(data.len()));
+        assert_eq_buf(&data, &buf[..data.len()]);
+    }
+
+    #[test]
+    fn test_fallocate() {
+        // Blocks still hold old data, as on a used device
+        let dev = Arc::new(MemDevice::new(4096, 256));
+        block_on(dev.write_blocks(0, &rand_array(256 * 4096))).unwrap();
+        let left = Arc::new(AtomicUsize::new(usize::max_value()));
+        block_on(mkfs(&*dev)).unwrap();
+
+        let crash = CrashDevice { dev: dev.clone(), budget: left.clone() };
+        let mut p = block_on(Proc::mount(Box::new(crash))).unwrap();
+        let free = p.statfs().unwrap().free_blocks;
+        let mut want = rand_array(4 * 4096);
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &want)).unwrap();
+
+        // Preallocated pages read back as zeros and are still holes
+        block_on(p.fallocate(fd, 0, 4 * 4096, 4 * 4096)).unwrap();
+        want.resize(8 * 4096, 0);
+        assert_eq!(p.seek(fd, 4 * 4096, SeekData), Err(FsError::ENXIO));
+        block_on(p.fallocate(fd, FALLOC_FL_KEEP_SIZE, 8 * 4096, 2 * 4096)).unwrap();
+        assert_eq!(p.fstat(fd).unwrap().size, 8 * 4096);
+        assert_eq!(p.statfs().unwrap().free_blocks, free - 10);
+
+        // Punching frees the pages wholly inside the hole
+        block_on(p.fallocate(fd, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, 4096 + 10, 2 * 4096)).unwrap();
+        for byte in want[4096 + 10..3 * 4096 + 10].iter_mut() { *byte = 0; }
+        assert_eq!(p.statfs().unwrap().free_blocks, free - 9);
+        assert_eq!(p.fstat(fd).unwrap().size, 8 * 4096);
+        assert_eq!(p.seek(fd, 4096, SeekHole), Ok(2 * 4096));
+
+        // Zeroing keeps the blocks, or gives the range some
+        block_on(p.fallocate(fd, FALLOC_FL_ZERO_RANGE, 10, 4096)).unwrap();
+        for byte in want[10..4096 + 10].iter_mut() { *byte = 0; }
+        assert_eq!(p.statfs().unwrap().free_blocks, free - 9);
+        block_on(p.fallocate(fd, FALLOC_FL_ZERO_RANGE, 10 * 4096, 100)).unwrap();
+        want.resize(10 * 4096 + 100, 0);
+        assert_eq!(p.statfs().unwrap().free_blocks, free - 10);
+
+        let mut buf = vec![0u8; want.len() + 1];
+        assert_eq!(block_on(p.pread(fd, &mut buf, 0)), Ok(want.len()));
+        assert_eq_buf(&want, &buf[..want.len()]);
+
+        assert_eq!(block_on(p.fallocate(fd, 0, 0, 0)), Err(FsError::EINVAL));
+        assert_eq!(block_on(p.fallocate(fd, FALLOC_FL_PUNCH_HOLE, 0, 1)), Err(FsError::EOPNOTSUPP));
+        assert_eq!(block_on(p.fallocate(fd, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE, 0, 1)),
+                   Err(FsError::EOPNOTSUPP));
+        assert_eq!(block_on(p.fallocate(fd, 0x08, 0, 1)), Err(FsError::EOPNOTSUPP));
+        assert_eq!(block_on(p.fallocate(fd, 0, MAX_FILE_SIZE, 1)), Err(FsError::EFBIG));
+        assert_eq!(block_on(p.fallocate(fd, 0, 0, 1000 * 4096)), Err(FsError::ENOSPC));
+        assert_eq!(p.statfs().unwrap().free_blocks, free - 10);
+        let ro = p.open("file", O_RDONLY).unwrap();
+        assert_eq!(block_on(p.fallocate(ro, 0, 0, 1)), Err(FsError::EBADF));
+
+        // Once the layout is on disk, zeroing in place is a single command
+        // that sends no data
+        block_on(p.fsync(fd)).unwrap();
+        block_on(p.fallocate(fd, FALLOC_FL_ZERO_RANGE | FALLOC_FL_KEEP_SIZE, 3 * 4096, 4096)).unwrap();
+        for byte in want[3 * 4096..4 * 4096].iter_mut() { *byte = 0; }
+        left.store(1, Ordering::SeqCst);
+        block_on(p.fdatasync(fd)).unwrap();
+        assert_eq!(left.load(Ordering::SeqCst), 0);
+        left.store(usize::max_value(), Ordering::SeqCst);
+
+        // Punched blocks stay on the device as long as the tree there uses
+        // them, and are unmapped by the sync that drops them from it
+        let page = rand_array(4096);
+        let on_device = |page: &[u8]| {
+            let mut all = vec![0u8; 256 * 4096];
+            block_on(dev.read_blocks(0, &mut all)).unwrap();
+            all.chunks(4096).any(|block| block == page)
+        };
+        block_on(p.pwrite(fd, &page, 0)).unwrap();
+        block_on(p.sync()).unwrap();
+        block_on(p.fallocate(fd, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, 0, 4096)).unwrap();
+        for byte in want[..4096].iter_mut() { *byte = 0; }
+        assert!(on_device(&page));
+        block_on(p.sync()).unwrap();
+        assert!(!on_device(&page));
+
+        // The preallocated blocks were zeroed on the device
+        let dev = block_on(p.unmount()).unwrap();
+        let mut p = block_on(Proc::mount(dev)).unwrap();
+        let fd = p.open("file", O_RDONLY).unwrap();
+        assert_eq!(block_on(p.pread(fd, &mut buf, 0)), Ok(want.len()));
+        assert_eq_buf(&want, &buf[..want.len()]);
+        assert_eq!(block_on(p.pread(fd, &mut buf[..4096], 8 * 4096)), Ok(4096));
+        assert!(buf[..4096].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_mount_roundtrip() {
+        let dev: Box<dyn BlockDevice> = Box::new(MemDevice::new(512, 8192));
+        block_on(mkfs(&*dev)).unwrap();
+
+        let big = rand_array(4096 * 3 + 100);
+        let page = rand_array(4096);
+        let mut p = block_on(Proc::mount(dev)).unwrap();
+        p.mkdir("/a").unwrap();
+        p.mkdir("/a/b").unwrap();
+        let fd = p.open("/a/b/big", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &big)).unwrap();
+        p.close(fd).unwrap();
+
+        // Every other page, so the file needs more extents than fit inline
+        let fd = p.open("/a/sparse", O_RDWR | O_CREAT).unwrap();
+        for i in 0..8 {
+            p.seek(fd, i * 2 * 4096, SeekSet).unwrap();
+            block_on(p.write(fd, &page)).unwrap();
+        }
+        p.close(fd).unwrap();
+        let fd = p.open("/a/far", O_RDWR | O_CREAT).unwrap();
+        p.seek(fd, 3 << 30, SeekSet).unwrap();
+        block_on(p.write(fd, &page)).unwrap();
+        p.open("/empty", O_RDWR | O_CREAT).unwrap();
+        let dev = block_on(p.unmount()).unwrap();
+
+        let mut p = block_on(Proc::mount(dev)).unwrap();
+        let mut buf = vec![0u8; big.len()];
+        let fd = p.open("/a/b/big", O_RDWR).unwrap();
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(big.len()));
+        assert_eq_buf(&big, &buf);
+
+        let fd = p.open("/a/sparse", O_RDWR).unwrap();
+        let mut buf = vec![0u8; 4096];
+        for i in 0..15 {
+            assert_eq!(block_on(p.read(fd, &mut buf)), Ok(4096));
+            if i % 2 == 0 { assert_eq_buf(&page, &buf); } else { assert!(buf.iter().all(|&b| b == 0)); }
+        }
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(0));
+
+        let fd = p.open("/a/far", O_RDWR).unwrap();
+        assert_eq!(p.seek(fd, 0, SeekData), Ok(3 << 30));
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(4096));
+        assert_eq_buf(&page, &buf);
+
+        let fd = p.open("/empty", O_RDWR).unwrap();
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(0));
+        p.chdir("/a/b").unwrap();
+        assert_eq!(p.getcwd(), Ok(b"/a/b".to_vec()));
+
+        // New inodes must not reuse the numbers of loaded ones
+        p.mkdir("/c").unwrap();
+        let fd = p.open("/c/new", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &page)).unwrap();
+        let mut p = block_on(Proc::mount(block_on(p.unmount()).unwrap())).unwrap();
+        let fd = p.open("/c/new", O_RDWR).unwrap();
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(4096));
+        assert_eq_buf(&page, &buf);
+        let fd = p.open("/a/b/big", O_RDWR).unwrap();
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(4096));
+        assert_eq_buf(&big[..4096], &buf);
+    }
+
+    // Wraps a device and counts the bytes read from it.
+    struct CountingDevice {
+        dev: Arc<MemDevice>,
+        read: Arc<AtomicUsize>
+    }
+
+    impl BlockDevice for CountingDevice {
+        fn block_size(&self) -> usize { self.dev.block_size() }
+        fn num_blocks(&self) -> u64 { self.dev.num_blocks() }
+
+        fn read_blocks<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> IoFuture<'a, ()> {
+            self.read.fetch_add(buf.len(), Ordering::SeqCst);
+            self.dev.read_blocks(offset, buf)
+        }
+
+        fn write_blocks<'a>(&'a self, offset: u64, buf: &'a [u8]) -> IoFuture<'a, ()> {
+            self.dev.write_blocks(offset, buf)
+        }
+
+        fn flush<'a>(&'a self) -> IoFuture<'a, ()> { self.dev.flush() }
+
+        fn unmap_blocks<'a>(&'a self, offset: u64, num: u64) -> IoFuture<'a, ()> {
+            self.dev.unmap_blocks(offset, num)
+        }
+
+        fn write_zeroes_blocks<'a>(&'a self, offset: u64, num: u64) -> IoFuture<'a, ()> {
+            self.dev.write_zeroes_blocks(offset, num)
+        }
+    }
+
+    #[test]
+    fn test_mount_on_demand() {
+        let dev = Arc::new(MemDevice::new(4096, 1024));
+        block_on(mkfs(&*dev)).unwrap();
+        let read = Arc::new(AtomicUsize::new(0));
+        let mount = || {
+            let counting = CountingDevice { dev: dev.clone(), read: read.clone() };
+            block_on(Proc::mount(Box::new(counting))).unwrap()
+        };
+
+        let mut p = mount();
+        p.open("big", O_RDWR | O_CREAT).unwrap();
+        block_on(p.unmount()).unwrap();
+        read.store(0, Ordering::SeqCst);
+        let mut p = mount();
+        let empty = read.load(Ordering::SeqCst);
+        let mut data = rand_array(64 * 4096 + 100);
+        let fd = p.open("big", O_RDWR).unwrap();
+        block_on(p.write(fd, &data)).unwrap();
+        block_on(p.unmount()).unwrap();
+
+        // Mounting reads as much as when the file was empty, not its 65
+        // pages, and neither does looking at how they are laid out
+        read.store(0, Ordering::SeqCst);
+        let mut p = mount();
+        assert_eq!(read.load(Ordering::SeqCst), empty);
+        read.store(0, Ordering::SeqCst);
+        let fd = p.open("big", O_RDWR).unwrap();
+        let stat = p.fstat(fd).unwrap();
+        assert_eq!((stat.size, stat.blocks), (data.len() as u64, 65 * 8));
+        assert_eq!(p.seek(fd, 4096, SeekData), Ok(4096));
+        assert_eq!(p.seek(fd, 0, SeekHole), Ok(data.len() as u64));
+        assert_eq!(read.load(Ordering::SeqCst), 0);
+
+        // A page is read the first time it is used, and only then
+        let mut buf = vec![0u8; 100];
+        assert_eq!(block_on(p.pread(fd, &mut buf, 10 * 4096 + 7)), Ok(100));
+        assert_eq_buf(&data[(10 * 4096 + 7)..(10 * 4096 + 107)], &buf);
+        assert_eq!(read.load(Ordering::SeqCst), 4096);
+        block_on(p.pread(fd, &mut buf, 10 * 4096)).unwrap();
+        assert_eq!(read.load(Ordering::SeqCst), 4096);
+
+        // Writes read the pages they only partly cover
+        block_on(p.pwrite(fd, b"abc", 20 * 4096 + 5)).unwrap();
+        data[(20 * 4096 + 5)..(20 * 4096 + 8)].copy_from_slice(b"abc");
+        assert_eq!(read.load(Ordering::SeqCst), 2 * 4096);
+        let page = rand_array(4096);
+        block_on(p.pwrite(fd, &page, 30 * 4096)).unwrap();
+        data[(30 * 4096)..(31 * 4096)].copy_from_slice(&page);
+        assert_eq!(read.load(Ordering::SeqCst), 2 * 4096);
+
+        // So do truncate and punching a hole
+        block_on(p.ftruncate(fd, 40 * 4096 + 9)).unwrap();
+        data.truncate(40 * 4096 + 9);
+        assert_eq!(read.load(Ordering::SeqCst), 3 * 4096);
+        block_on(p.fallocate(fd, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, 2 * 4096 + 1, 3 * 4096)).unwrap();
+        for byte in data[(2 * 4096 + 1)..(5 * 4096 + 1)].iter_mut() { *byte = 0; }
+        assert_eq!(read.load(Ordering::SeqCst), 5 * 4096);
+        assert_eq!(p.fstat(fd).unwrap().blocks, 39 * 8);
+
+        // The rest is read as needed, and all of it reads back as written,
+        // before and after a sync
+        let mut buf = vec![0u8; data.len()];
+        assert_eq!(block_on(p.pread(fd, &mut buf, 0)), Ok(data.len()));
+        assert_eq_buf(&data, &buf);
+        block_on(p.unmount()).unwrap();
+        let mut p = mount();
+        let fd = p.open("big", O_RDONLY).unwrap();
+        assert_eq!(p.fstat(fd).unwrap().blocks, 39 * 8);
+        let mut buf = vec![0u8; data.len()];
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(data.len()));
+        assert_eq_buf(&data, &buf);
+    }
+
+    #[test]
+    fn test_shared_fs() {
+        let dev: Box<dyn BlockDevice> = Box::new(MemDevice::new(4096, 256));
+        block_on(mkfs(&*dev)).unwrap();
+        let fs = block_on(FileSystem::mount(dev)).unwrap();
+        let mut a = Proc::with_fs(fs.clone());
+        let mut b = Proc::with_fs(fs);
+        let mut buf = [0u8; 8];
+
+        // One tree, but a working directory and descriptors per process
+        a.mkdir("dir").unwrap();
+        a.chdir("dir").unwrap();
+        let fa = a.open("file", O_RDWR | O_CREAT).unwrap();
+        block_on(a.write(fa, b"shared")).unwrap();
+        assert_eq!(b.getcwd(), Ok(b"/".to_vec()));
+        let fb = b.open("dir/file", O_RDONLY).unwrap();
+        assert_eq!(fa, fb);
+        assert_eq!(block_on(b.read(fb, &mut buf)), Ok(6));
+        assert_eq_buf(b"shared", &buf[..6]);
+        b.close(fb).unwrap();
+        assert!(a.seek(fa, 0, SeekCur).is_ok());
+
+        // A file one process still has open keeps its inode number after
+        // another one unlinked it
+        let ino = a.fstat(fa).unwrap().ino;
+        b.unlink("dir/file").unwrap();
+        block_on(b.sync()).unwrap();
+        b.mkdir("other").unwrap();
+        assert!(b.stat("other").unwrap().ino != ino);
+
+        assert_eq!(block_on(a.unmount()).err(), Some(FsError::EBUSY));
+        let dev = block_on(b.unmount()).unwrap();
+        let p = block_on(Proc::mount(dev)).unwrap();
+        assert!(p.stat("other").is_ok());
+        assert_eq!(p.stat("dir/file").err(), Some(FsError::ENOENT));
+    }
+
+    #[test]
+    fn test_xattr() {
+        let mut p = Proc::new();
+        let alice = Cred::new(1000, 100, Vec::new());
+        let fd = p.open_mode("file", O_RDWR | O_CREAT, 0o644).unwrap();
+        p.chown("file", Some(1000), Some(100)).unwrap();
+        p.mkdir("dir").unwrap();
+
+        p.setxattr("file", b"user.hash", b"abc", 0).unwrap();
+        p.fsetxattr(fd, b"trusted.tag", b"x", XATTR_CREATE).unwrap();
+        p.setxattr("dir", b"user.empty", b"", 0).unwrap();
+        assert_eq!(p.fgetxattr(fd, b"user.hash"), Ok(b"abc".to_vec()));
+        assert_eq!(p.getxattr("dir", b"user.empty"), Ok(Vec::new()));
+        assert_eq!(p.listxattr("file"), Ok(vec![b"trusted.tag".to_vec(), b"user.hash".to_vec()]));
+        assert_eq!(p.setxattr("file", b"user.hash", b"", XATTR_CREATE), Err(FsError::EEXIST));
+        assert_eq!(p.setxattr("file", b"user.none", b"", XATTR_REPLACE), Err(FsError::ENODATA));
+        assert_eq!(p.getxattr("file", b"user.none"), Err(FsError::ENODATA));
+        assert_eq!(p.getxattr("file", b"system.posix_acl_access"), Err(FsError::EOPNOTSUPP));
+
+        // Changing attributes is a change of the metadata, not the content
+        let before = p.stat("file").unwrap();
+        p.setxattr("file", b"user.hash", b"def", XATTR_REPLACE).unwrap();
+        let after = p.stat("file").unwrap();
+        assert_eq!(after.mtime, before.mtime);
+        assert!(after.ctime >= before.ctime);
+
+        // user. follows the file permissions; trusted. is hidden from users
+        p.set_cred(alice);
+        assert_eq!(p.listxattr("file"), Ok(vec![b"user.hash".to_vec()]));
+        assert_eq!(p.getxattr("file", b"trusted.tag"), Err(FsError::ENODATA));
+        assert_eq!(p.fsetxattr(fd, b"trusted.tag", b"y", 0), Err(FsError::EPERM));
+        assert_eq!(p.removexattr("dir", b"user.empty"), Err(FsError::EACCES));
+        assert_eq!(p.getxattr("dir", b"user.empty"), Ok(Vec::new()));
+        p.fremovexattr(fd, b"user.hash").unwrap();
+        assert_eq!(p.fremovexattr(fd, b"user.hash"), Err(FsError::ENODATA));
+        assert_eq!(p.flistxattr(fd), Ok(Vec::new()));
+        p.set_cred(Cred::root());
+        assert_eq!(p.flistxattr(fd), Ok(vec![b"trusted.tag".to_vec()]));
+    }
+
+    #[test]
+    fn test_mount_xattr() {
+        let dev: Box<dyn BlockDevice> = Box::new(MemDevice::new(4096, 256));
+        block_on(mkfs(&*dev)).unwrap();
+        let mut p = block_on(Proc::mount(dev)).unwrap();
+
+        // One list fits in the inode, the other needs two spill blocks
+        let big = rand_array(6000);
+        p.mkdir("dir").unwrap();
+        p.setxattr("dir", b"user.a", b"1", 0).unwrap();
+        p.open("file", O_RDWR | O_CREAT).unwrap();
+        p.setxattr("file", b"user.big", &big, 0).unwrap();
+        p.setxattr("file", b"trusted.t", b"2", 0).unwrap();
+        let dev = block_on(p.unmount()).unwrap();
+
+        let mut p = block_on(Proc::mount(dev)).unwrap();
+        let free = p.statfs().unwrap().free_blocks;
+        assert_eq!(p.getxattr("dir", b"user.a"), Ok(b"1".to_vec()));
+        assert_eq!(p.getxattr("file", b"user.big"), Ok(big));
+        assert_eq!(p.listxattr("file"), Ok(vec![b"trusted.t".to_vec(), b"user.big".to_vec()]));
+
+        // Shrinking the list back into the inode frees the spill blocks
+        p.removexattr("file", b"user.big").unwrap();
+        block_on(p.sync()).unwrap();
+        assert_eq!(p.statfs().unwrap().free_blocks, free + 2);
+        let dev = block_on(p.unmount()).unwrap();
+        let p = block_on(Proc::mount(dev)).unwrap();
+        assert_eq!(p.listxattr("file"), Ok(vec![b"trusted.t".to_vec()]));
+    }
+
+    #[test]
+    fn test_mount_links() {
+        let dev: Box<dyn BlockDevice> = Box::new(MemDevice::new(4096, 256));
+        block_on(mkfs(&*dev)).unwrap();
+        let mut p = block_on(Proc::mount(dev)).unwrap();
+        let mut buf = [0u8; 8];
+
+        p.mkdir("dir").unwrap();
+        let fd = p.open("dir/a", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, b"linked")).unwrap();
+        p.link("dir/a", "b").unwrap();
+        block_on(p.sync()).unwrap();
+        let free = p.statfs().unwrap().free_blocks;
+
+        // Both names come back as one inode
+        let mut p = block_on(Proc::mount(block_on(p.unmount()).unwrap())).unwrap();
+        assert_eq!(p.statfs().unwrap().free_blocks, free);
+        let fd = p.open("b", O_WRONLY | O_APPEND).unwrap();
+        block_on(p.write(fd, b"!")).unwrap();
+        let fd = p.open("dir/a", O_RDONLY).unwrap();
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(7));
+        assert_eq_buf(b"linked!", &buf[..7]);
+
+        p.chown("dir", Some(7), Some(8)).unwrap();
+        p.chmod("b", 0o4711).unwrap();
+        let st = (p.stat("b").unwrap(), p.stat("dir").unwrap(), p.stat("/").unwrap());
+        let mut p = block_on(Proc::mount(block_on(p.unmount()).unwrap())).unwrap();
+        assert_eq!((p.stat("dir/a").unwrap(), p.stat("dir").unwrap(), p.stat("/").unwrap()), st);
+
+        // Symbolic links come back too, hard links to them included
+        p.symlink("dir/a", "sym").unwrap();
+        p.link("sym", "dir/sym2").unwrap();
+        let mut p = block_on(Proc::mount(block_on(p.unmount()).unwrap())).unwrap();
+        assert_eq!(p.readlink("dir/sym2"), Ok(b"dir/a".to_vec()));
+        let fd = p.open("sym", O_RDONLY).unwrap();
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(7));
+        assert_eq_buf(b"linked!", &buf[..7]);
+    }
+
+    #[test]
+    fn test_mount_file_device() {
+        let path = env::temp_dir().join(format!("rustfs-mount-{}", random::<u32>()));
+        let dev = FileDevice::create(&path, 4096, 64).unwrap();
+        block_on(mkfs(&dev)).unwrap();
+
+        let data = rand_array(10000);
+        let mut p = block_on(Proc::mount(Box::new(dev))).unwrap();
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &data)).unwrap();
+        drop(block_on(p.unmount()).unwrap());
+
+        let dev = FileDevice::open(&path, 4096).unwrap();
+        let mut p = block_on(Proc::mount(Box::new(dev))).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        let fd = p.open("file", O_RDWR).unwrap();
+        assert_eq!(block_on(p.read(fd, &mut buf)), Ok(data.len()));
+        assert_eq_buf(&data, &buf);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_mount_bad_superblock() {
+        let dev: Box<dyn BlockDevice> = Box::new(MemDevice::new(4096, 64));
+        assert!(block_on(Proc::mount(dev)).err() == Some(FsError::EINVAL));
+    }
+
+    #[test]
+    fn test_mount_full() {
+        let dev: Box<dyn BlockDevice> = Box::new(MemDevice::new(4096, 32));
+        block_on(mkfs(&*dev)).unwrap();
+        let mut p = block_on(Proc::mount(dev)).unwrap();
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        let free = p.statfs().unwrap().free_blocks;
+
+        // Space runs out at write time and a failed write takes none of it
+        assert_eq!(block_on(p.write(fd, &rand_array(4096 * 64))), Err(FsError::ENOSPC));
+        assert_eq!(p.statfs().unwrap().free_blocks, free);
+        block_on(p.write(fd, &rand_array(4096 * 2))).unwrap();
+        assert_eq!(p.statfs().unwrap().free_blocks, free - 2);
+        block_on(p.sync()).unwrap();
+    }
+
+    fn extents_of(p: &Proc, name: &str) -> usize {
+        let file = path::resolve(&p.root, &p.cwd, name.as_bytes(), &p.cred).unwrap();
+        let inode = file.get_inode_rc().unwrap().read();
+        inode.blocks().extents().len()
+    }
+
+    #[test]
+    fn test_alloc_free_space() {
+        let dev: Box<dyn BlockDevice> = Box::new(MemDevice::new(4096, 256));
+        block_on(mkfs(&*dev)).unwrap();
+        let mut p = block_on(Proc::mount(dev)).unwrap();
+        let empty = p.statfs().unwrap();
+        assert_eq!(empty.blocks, 256);
+        assert_eq!(empty.free_blocks + empty.used_blocks, 256);
+
+        // Interleaved appends still give each file one extent
+        let page = rand_array(4096);
+        let a = p.open("a", O_RDWR | O_CREAT).unwrap();
+        let b = p.open("b", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(a, &rand_array(4096 * 8))).unwrap();
+        block_on(p.write(b, &rand_array(4096 * 8))).unwrap();
+        for _ in 0..8 {
+            block_on(p.write(a, &page)).unwrap();
+        }
+        assert_eq!(extents_of(&p, "a"), 2);
+        assert_eq!(extents_of(&p, "b"), 1);
+        assert_eq!(p.statfs().unwrap().free_blocks, empty.free_blocks - 24);
+
+        // Unlinking gives the blocks back once the file is closed
+        p.unlink("a").unwrap();
+        assert_eq!(p.statfs().unwrap().free_blocks, empty.free_blocks - 24);
+        p.close(a).unwrap();
+        assert_eq!(p.statfs().unwrap().free_blocks, empty.free_blocks - 8);
+        block_on(p.sync()).unwrap();
+        assert_eq!(p.statfs().unwrap().free_blocks, empty.free_blocks - 8 - 1);
+
+        let p = block_on(Proc::mount(block_on(p.unmount()).unwrap())).unwrap();
+        assert_eq!(extents_of(&p, "b"), 1);
+        assert_eq!(p.statfs().unwrap().free_blocks, empty.free_blocks - 8 - 1);
+    }
+
+    #[test]
+    fn test_alloc_recover_leaked() {
+        let dev: Box<dyn BlockDevice> = Box::new(MemDevice::new(4096, 256));
+        block_on(mkfs(&*dev)).unwrap();
+        let mut p = block_on(Proc::mount(dev)).unwrap();
+        let free = p.statfs().unwrap().free_blocks;
+
+        // Still open when the file system goes away: the bitmap on disk
+        // keeps its blocks, the next mount finds nothing pointing at them
+        let fd = p.open("file", O_RDWR | O_CREAT).unwrap();
+        block_on(p.write(fd, &rand_array(4096 * 16))).unwrap();
+        p.unlink("file").unwrap();
+        block_on(p.sync()).unwrap();
+        assert_eq!(p.statfs().unwrap().free_blocks, free - 16);
+
+        let p = block_on(Proc::mount(block_on(p.unmount()).unwrap())).unwrap();
+        assert_eq!(p.statfs().unwrap().free_blocks, free);
+    }
+
+    fn stress_data(thread: usize, file: usize) -> Vec<u8> {
+        (0..(4096 + 97 * file)).map(|k| (thread * 31 + file * 7 + k) as u8).collect()
+    }
+
+    // Every expected name in `dir` with the content it should have
+    fn check_dir(p: &mut Proc, dir: &str, expected: &[(String, Vec<u8>)]) {
+        let fd = p.opendir_sorted(dir).unwrap();
+        let mut names = list(p, fd);
+        p.closedir(fd).unwrap();
+        names.retain(|name| name != "." && name != "..");
+        let mut want: Vec<String> = expected.iter().map(|e| e.0.clone()).collect();
+        want.sort();
+        assert_eq!(names, want);
+
+        for &(ref name, ref data) in expected {
+            let path = format!("{}/{}", dir, name);
+            assert_eq!(p.stat(&path).unwrap().nlink, 1);
+            let fd = p.open(&path, O_RDONLY).unwrap();
+            let mut buf = vec![0u8; data.len() + 1];
+            assert_eq!(block_on(p.read(fd, &mut buf)), Ok(data.len()));
+            assert_eq_buf(data, &buf[..data.len()]);
+            p.close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_concurrent_stress() {
+        const THREADS: usize = 8;
+        const FILES: usize = 60;
+        let dev: Box<dyn BlockDevice> = Box::new(MemDevice::new(4096, 8192));
+        block_on(mkfs(&*dev)).unwrap();
+        let mut p = block_on(Proc::mount(dev)).unwrap();
+        p.mkdir("shared").unwrap();
+
+        // Each thread creates, writes, unlinks and renames in its own
+        // directory and in a shared one, while two others keep syncing:
+        // a sync waits for the other one rather than failing
+        let workers: Vec<_> = (0..THREADS).map(|i| {
+            let mut p = Proc::with_fs(p.fs().clone());
+            thread::spawn(move || {
+                let own = format!("t{}", i);
+                p.mkdir(&own).unwrap();
+                for j in 0..FILES {
+                    let data = stress_data(i, j);
+                    let shared = format!("shared/{}_{}", i, j);
+                    let fd = p.open(&shared, O_RDWR | O_CREAT | O_EXCL).unwrap();
+                    assert_eq!(block_on(p.write(fd, &data)), Ok(data.len()));
+                    p.close(fd).unwrap();
+                    let fd = p.open(format!("{}/{}", own, j), O_WRONLY | O_CREAT).unwrap();
+                    assert_eq!(block_on(p.write(fd, &data)), Ok(data.len()));
+                    p.close(fd).unwrap();
+
+                    if j % 2 == 1 { p.unlink(&shared).unwrap(); }
+                    if j % 3 == 0 {
+                        p.rename(format!("{}/{}", own, j), format!("shared/r{}_{}", i, j)).unwrap();
+                    }
+                    // One name everybody appends to
+                    let fd = p.open("shared/common", O_WRONLY | O_CREAT | O_APPEND).unwrap();
+                    block_on(p.write(fd, &[i as u8])).unwrap();
+                    p.close(fd).unwrap();
+                }
+            })
+        }).collect();
+        let done = Arc::new(AtomicBool::new(false));
+        let syncers: Vec<_> = (0..2).map(|_| {
+            let (fs, done) = (p.fs().clone(), done.clone());
+            thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) { block_on(fs.sync()).unwrap(); }
+            })
+        }).collect();
+        for worker in workers { worker.join().unwrap(); }
+        done.store(true, Ordering::SeqCst);
+        for syncer in syncers { syncer.join().unwrap(); }
+
+        for round in 0..2 {
+            let mut shared = Vec::new();
+            for i in 0..THREADS {
+                let mut own = Vec::new();
+                for j in 0..FILES {
+                    if j % 2 == 0 { shared.push((format!("{}_{}", i, j), stress_data(i, j))); }
+                    if j % 3 == 0 {
+                        shared.push((format!("r{}_{}", i, j), stress_data(i, j)));
+                    } else {
+                        own.push((format!("{}", j), stress_data(i, j)));
+                    }
+                }
+                check_dir(&mut p, &format!("t{}", i), &own);
+            }
+            let fd = p.open("shared/common", O_RDONLY).unwrap();
+            let mut common = vec![0u8; THREADS * FILES + 1];
+            assert_eq!(block_on(p.read(fd, &mut common)), Ok(THREADS * FILES));
+            p.close(fd).unwrap();
+            common.truncate(THREADS * FILES);
+            for i in 0..THREADS {
+                assert_eq!(common.iter().filter(|&&b| b == i as u8).count(), FILES);
+            }
+            shared.push(("common".to_string(), common));
+            check_dir(&mut p, "shared", &shared);
+
+            // The same tree comes back from the device
+            if round == 0 {
+                p = block_on(Proc::mount(block_on(p.unmount()).unwrap())).unwrap();
+            }
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,152 @@
+/*************************************************************************
+  > File Name:       path.rs
+  > Author:          Zeyuan Hu
+  > Mail:            iamzeyuanhu@utexas.edu
+  > Created Time:    10/17/26
+  > Description:
+
+    Path resolution on top of the directory tree. A path is a byte string
+    (it does not have to be UTF-8); it is split on '/' and walked one
+    component at a time starting either from the root (absolute paths) or
+    from the current working directory (relative paths).
+    "." and ".." are handled here instead of being stored as entries.
+
+    Symbolic links met on the way are followed: the rest of the walk goes
+    on from wherever the link points. The last component is only followed
+    when asked for (see resolve_nofollow). A walk that goes through more
+    than SYMLOOP_MAX links fails with ELOOP.
+
+    Every directory walked through must be searchable (X_OK) by the
+    caller's credentials, or the walk fails with EACCES.
+ ************************************************************************/
+
+use crate::cred::{Cred, X_OK};
+use crate::directory::DirectoryHandle;
+use crate::error::{FsError, FsResult};
+use crate::file::File;
+
+/// Largest number of symbolic links followed while resolving one path.
+pub const SYMLOOP_MAX: usize = 40;
+
+/// Longest path (in bytes) accepted, and longest target of a symbolic link.
+pub const PATH_MAX: usize = 4096;
+
+/// Longest name (in bytes) of a directory entry.
+pub const NAME_MAX: usize = 255;
+
+/// Splits `path` into its components, skipping empty ones (so "a//b/" is
+/// the same as "a/b").
+pub fn components<'p>(path: &'p [u8]) -> impl Iterator<Item = &'p [u8]> {
+    path.split(|&b| b == b'/').filter(|c| !c.is_empty())
+}
+
+/// Checks that `name` can be a directory entry: at most NAME_MAX bytes
+/// (ENAMETOOLONG), neither '/' nor NUL in it and not empty (EINVAL).
+pub fn check_name(name: &[u8]) -> FsResult<()> {
+    if name.len() > NAME_MAX { return Err(FsError::ENAMETOOLONG); }
+    if name.is_empty() || name.iter().any(|&b| b == b'/' || b == 0) {
+        return Err(FsError::EINVAL);
+    }
+    Ok(())
+}
+
+#[inline(always)]
+fn start(root: &File, cwd: &File, path: &[u8]) -> FsResult<File> {
+    if path.is_empty() { return Err(FsError::ENOENT); }
+    if path.len() > PATH_MAX { return Err(FsError::ENAMETOOLONG); }
+    if path[0] == b'/' { Ok(root.clone()) } else { Ok(cwd.clone()) }
+}
+
+/// Moves one step from the directory `dir` following the component `name`.
+fn step(dir: &File, name: &[u8], cred: &Cred) -> FsResult<File> {
+    if !dir.is_dir() { return Err(FsError::ENOTDIR); }
+    cred.check(&dir.attr(), true, X_OK)?;
+
+    match name {
+        b"." => Ok(dir.clone()),
+        b".." => dir.parent(),
+        _ => {
+            check_name(name)?;
+            dir.get(name)
+        }
+    }
+}
+
+/// Walks `names` starting at `file`. Symbolic links are followed, except
+/// for the one the walk ends on unless `follow` is set. `links` counts the
+/// links followed so far.
+fn walk(root: &File, mut file: File, names: &[&[u8]], cred: &Cred,
+        follow: bool, links: &mut usize) -> FsResult<File> {
+    for (i, name) in names.iter().enumerate() {
+        let next = step(&file, name, cred)?;
+        if next.is_symlink() && (follow || i + 1 < names.len()) {
+            *links += 1;
+            if *links > SYMLOOP_MAX { return Err(FsError::ELOOP); }
+            // Relative targets start from the directory holding the link
+            let target = next.link_target()?;
+            let from = start(root, &file, &target)?;
+            let target_names: Vec<&[u8]> = components(&target).collect();
+            file = walk(root, from, &target_names, cred, true, links)?;
+        } else {
+            file = next;
+        }
+    }
+    Ok(file)
+}
+
+/// Looks up the file named by `path`, following symbolic links.
+pub fn resolve(root: &File, cwd: &File, path: &[u8], cred: &Cred) -> FsResult<File> {
+    let names: Vec<&[u8]> = components(path).collect();
+    walk(root, start(root, cwd, path)?, &names, cred, true, &mut 0)
+}
+
+/// Same as resolve, but if the last component is a symbolic link the link
+/// itself is returned.
+pub fn resolve_nofollow(root: &File, cwd: &File, path: &[u8], cred: &Cred) -> FsResult<File> {
+    let names: Vec<&[u8]> = components(path).collect();
+    walk(root, start(root, cwd, path)?, &names, cred, false, &mut 0)
+}
+
+/// Looks up the directory that contains the last component of `path` and
+/// returns it together with that last component. Fails if an intermediate
+/// component is missing or is not a directory, if the last component is not
+/// a valid name (see check_name), or with EINVAL if it is "." or ".."
+/// (those never name a new entry). The last component is not looked at, so
+/// a symbolic link there is not followed.
+pub fn resolve_parent<'p>(root: &File, cwd: &File, path: &'p [u8],
+                          cred: &Cred) -> FsResult<(File, &'p [u8])> {
+    let dir = start(root, cwd, path)?;
+    let mut names: Vec<&'p [u8]> = components(path).collect();
+    let last = names.pop().ok_or(FsError::EINVAL)?;
+    if last == b"." || last == b".." { return Err(FsError::EINVAL); }
+    check_name(last)?;
+
+    let dir = walk(root, dir, &names, cred, true, &mut 0)?;
+    if dir.is_dir() { Ok((dir, last)) } else { Err(FsError::ENOTDIR) }
+}
+
+/// Same as resolve_parent, but if the last component is a symbolic link it
+/// is followed, to the directory and the name its target ends in: where
+/// O_CREAT creates a file through a dangling link, like Linux does.
+pub fn resolve_create(root: &File, cwd: &File, path: &[u8],
+                      cred: &Cred) -> FsResult<(File, Vec<u8>)> {
+    let (mut dir, name) = resolve_parent(root, cwd, path, cred)?;
+    let mut name = name.to_vec();
+    let mut links = 0;
+    loop {
+        let file = match step(&dir, &name, cred) {
+            Ok(file) => file,
+            Err(FsError::ENOENT) => return Ok((dir, name)),
+            Err(e) => return Err(e)
+        };
+        if !file.is_symlink() { return Ok((dir, name)); }
+
+        links += 1;
+        if links > SYMLOOP_MAX { return Err(FsError::ELOOP); }
+        // Relative targets start from the directory holding the link
+        let target = file.link_target()?;
+        let (next, next_name) = resolve_parent(root, &dir, &target, cred)?;
+        name = next_name.to_vec();
+        dir = next;
+    }
+}
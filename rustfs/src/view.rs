@@ -0,0 +1,152 @@
+/*************************************************************************
+  > File Name:       view.rs
+  > Author:          Zeyuan Hu
+  > Mail:            iamzeyuanhu@utexas.edu
+  > Created Time:    10/17/26
+  > Description:
+
+    Views of a range of a file that lend out its pages instead of copying
+    them to or from a buffer, for scans and fills of large files (see
+    Proc::map and Proc::map_mut). Pages are reference counted: a view holds
+    on to the pages of its range, not to a lock on the inode, and the file
+    copies a page before changing it while a view still holds it. A view
+    thus never makes anything else wait, on its own thread or on another.
+
+    Pages are IoBufs. On a file system mounted on an SpdkDevice they are
+    DMA memory, and bufs hands out their env::Bufs to give to a bdev as
+    they are. The pages go to and come from the device of the file system
+    the same way, without a bounce buffer (see BlockDevice::writev_blocks).
+ ************************************************************************/
+
+use crate::device::IoBuf;
+use crate::error::FsResult;
+use crate::file::RcInode;
+use crate::inode::{Inode, Page, ZERO_PAGE};
+use std::cmp;
+use std::sync::Arc;
+
+/// A range of a file to read in place, as it was when the view was made.
+pub struct PageView {
+    pages: Vec<Option<Page>>, // None for holes
+    offset: u64,
+    len: u64
+}
+
+impl PageView {
+    /// Like read, the view stops at the end of the file, and is empty if
+    /// `offset` is at or past it.
+    pub fn new(inode: &Inode, offset: u64, len: u64) -> PageView {
+        let len = if offset >= inode.size() { 0 } else { cmp::min(len, inode.size() - offset) };
+        PageView { pages: inode.share_pages(offset, len), offset: offset, len: len }
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The content of the range one page at a time, in order. Only the
+    /// first and the last slices can be shorter than a page. Holes are
+    /// slices of a shared page of zeros.
+    pub fn pages<'a>(&'a self) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let parts = Inode::page_parts(self.offset, self.offset + self.len);
+        self.pages.iter().zip(parts).map(|(page, (_, from, to))| {
+            match *page {
+                Some(ref page) => &page[from..to],
+                None => &ZERO_PAGE[from..to]
+            }
+        })
+    }
+
+    /// The pages of the range as the buffers holding them, with the part of
+    /// each that is in the range, None for holes. Handing IoBuf::env_buf to
+    /// a bdev writes a page without a copy; it must not be written to.
+    pub fn bufs<'a>(&'a self) -> impl Iterator<Item = (Option<&'a IoBuf>, usize, usize)> + 'a {
+        let parts = Inode::page_parts(self.offset, self.offset + self.len);
+        self.pages.iter().zip(parts).map(|(page, (_, from, to))| (page.as_ref().map(|page| &**page), from, to))
+    }
+}
+
+/// A range of a file to change in place. Every page of the range has a
+/// device block from the start. The bytes the view changed go to the file
+/// when it is dropped; those written to the range meanwhile by other means
+/// stay where the view left them alone. The pages the view changed are
+/// then dirty and the file modified.
+pub struct PageViewMut {
+    inode: RcInode,
+    old: Vec<Option<Page>>, // the range as the view found it, None for holes
+    pages: Vec<Page>,
+    offset: u64,
+    len: u64
+}
+
+impl PageViewMut {
+    /// Prepares the inode for the range (see Inode::prepare) and pins it
+    /// until the view is dropped.
+    pub fn new(inode_rc: RcInode, offset: u64, len: u64) -> FsResult<PageViewMut> {
+        let (old, pages) = {
+            let mut inode = inode_rc.write();
+            inode.prepare(offset, len)?;
+            inode.pin();
+            let old = inode.share_pages(offset, len);
+            // Holes get pages of their own, which nothing else holds
+            let pages = old.iter().map(|page| page.clone().unwrap_or_else(|| inode.new_page())).collect();
+            (old, pages)
+        };
+        Ok(PageViewMut { inode: inode_rc, old: old, pages: pages, offset: offset, len: len })
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Same as PageView::pages.
+    pub fn pages<'a>(&'a self) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let parts = Inode::page_parts(self.offset, self.offset + self.len);
+        self.pages.iter().zip(parts).map(|(page, (_, from, to))| &page[from..to])
+    }
+
+    /// The range one page at a time, to write to. A page that held data
+    /// before is copied the first time, as write copies its buffer; pages
+    /// that were holes are not.
+    pub fn pages_mut<'a>(&'a mut self) -> impl Iterator<Item = &'a mut [u8]> + 'a {
+        let parts = Inode::page_parts(self.offset, self.offset + self.len);
+        self.pages.iter_mut().zip(parts).map(|(page, (_, from, to))| &mut Arc::make_mut(page)[from..to])
+    }
+
+    /// Same as pages_mut, but as the buffers holding the pages, with the
+    /// part of each that is in the range, for a bdev to fill through
+    /// IoBuf::env_buf without a copy. Bytes outside that part are ignored.
+    pub fn bufs_mut<'a>(&'a mut self) -> impl Iterator<Item = (&'a mut IoBuf, usize, usize)> + 'a {
+        let parts = Inode::page_parts(self.offset, self.offset + self.len);
+        self.pages.iter_mut().zip(parts).map(|(page, (_, from, to))| (Arc::make_mut(page), from, to))
+    }
+}
+
+impl Drop for PageViewMut {
+    fn drop(&mut self) {
+        let mut inode = self.inode.write();
+        let parts = Inode::page_parts(self.offset, self.offset + self.len);
+        let mut changed = false;
+        for ((old, page), (num, from, to)) in self.old.iter().zip(self.pages.iter()).zip(parts) {
+            changed |= inode.put_page(num, old.as_ref(), page, from, to);
+        }
+        if changed { inode.attr_mut().modified(); }
+        inode.unpin();
+    }
+}
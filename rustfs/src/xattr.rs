@@ -0,0 +1,173 @@
+/*************************************************************************
+  > File Name:       xattr.rs
+  > Author:          Zeyuan Hu
+  > Mail:            iamzeyuanhu@utexas.edu
+  > Created Time:    10/17/26
+  > Description:
+
+    Extended attributes: small name/value pairs attached to a file next to
+    its metadata. Every name starts with a namespace, which decides who may
+    use it (see Proc::getxattr):
+
+    - user.: the content of regular files and directories, open to anyone
+      who may read (or write) the file itself
+    - trusted.: only seen and changed by the superuser
+
+    On disk the attributes of an inode are one encoded list (see
+    layout::encode_xattrs): in the inode record if it is short enough,
+    otherwise in a chain of spill blocks.
+ ************************************************************************/
+
+use crate::alloc::{Blocks, RcAllocator};
+use crate::error::{FsError, FsResult};
+use crate::layout::{encode_xattrs, xattr_size};
+use std::collections::BTreeMap;
+
+/// Longest attribute name, namespace included.
+pub const XATTR_NAME_MAX: usize = 255;
+/// Largest attribute value.
+pub const XATTR_SIZE_MAX: usize = 65536;
+/// Room for all the attributes of one file, as encoded on disk.
+pub const XATTR_LIST_MAX: usize = 2 * XATTR_SIZE_MAX;
+
+/// setxattr flag: fail with EEXIST if the attribute exists.
+pub const XATTR_CREATE: u32 = 1;
+/// setxattr flag: fail with ENODATA if the attribute does not exist.
+pub const XATTR_REPLACE: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Namespace {
+    User,
+    Trusted
+}
+
+/// The namespace of the attribute called `name`. Fails with ERANGE if the
+/// name is too long, with EINVAL if nothing follows the namespace and with
+/// EOPNOTSUPP for any namespace but user. and trusted.
+pub fn namespace(name: &[u8]) -> FsResult<Namespace> {
+    if name.len() > XATTR_NAME_MAX { return Err(FsError::ERANGE); }
+    let (ns, prefix): (_, &[u8]) = if name.starts_with(b"user.") {
+        (Namespace::User, b"user.")
+    } else if name.starts_with(b"trusted.") {
+        (Namespace::Trusted, b"trusted.")
+    } else {
+        return Err(FsError::EOPNOTSUPP);
+    };
+    if name.len() == prefix.len() { return Err(FsError::EINVAL); }
+    Ok(ns)
+}
+
+/// The extended attributes of one file.
+pub struct Xattrs {
+    map: BTreeMap<Vec<u8>, Vec<u8>>,
+    size: usize, // of the encoded list
+    // The spill blocks on disk. They are the overflow list of a Blocks that
+    // maps no pages, which frees them the same way as extent overflow
+    // blocks.
+    spill: Blocks
+}
+
+impl Xattrs {
+    pub fn new(alloc: Option<RcAllocator>) -> Xattrs {
+        Xattrs { map: BTreeMap::new(), size: 0, spill: Blocks::new(alloc) }
+    }
+
+    /// Attributes read back from disk, with the spill blocks they were
+    /// read from. The caller has already claimed the blocks.
+    pub fn from_disk(list: Vec<(Vec<u8>, Vec<u8>)>, spill: Blocks) -> Xattrs {
+        let mut xattrs = Xattrs { map: BTreeMap::new(), size: 0, spill: spill };
+        for (name, value) in list {
+            xattrs.size += xattr_size(&name, &value);
+            xattrs.map.insert(name, value);
+        }
+        xattrs
+    }
+
+    pub fn get(&self, name: &[u8]) -> Option<&[u8]> {
+        self.map.get(name).map(|value| &value[..])
+    }
+
+    /// Every attribute name, in order.
+    pub fn names(&self) -> Vec<&[u8]> {
+        self.map.keys().map(|name| &name[..]).collect()
+    }
+
+    /// Sets `name` to `value`, with XATTR_CREATE or XATTR_REPLACE in `flags`
+    /// to require that the attribute does not or does exist. Fails with
+    /// E2BIG if the value is too large and with ENOSPC if the attributes of
+    /// the file would not fit in XATTR_LIST_MAX.
+    pub fn set(&mut self, name: &[u8], value: &[u8], flags: u32) -> FsResult<()> {
+        if flags & !(XATTR_CREATE | XATTR_REPLACE) != 0 { return Err(FsError::EINVAL); }
+        if value.len() > XATTR_SIZE_MAX { return Err(FsError::E2BIG); }
+
+        let old = match self.map.get(name) {
+            Some(_) if (flags & XATTR_CREATE) != 0 => return Err(FsError::EEXIST),
+            Some(old) => xattr_size(name, old),
+            None if (flags & XATTR_REPLACE) != 0 => return Err(FsError::ENODATA),
+            None => 0
+        };
+        let size = self.size - old + xattr_size(name, value);
+        if size > XATTR_LIST_MAX { return Err(FsError::ENOSPC); }
+
+        self.map.insert(name.to_vec(), value.to_vec());
+        self.size = size;
+        Ok(())
+    }
+
+    /// Fails with ENODATA if there is no attribute called `name`.
+    pub fn remove(&mut self, name: &[u8]) -> FsResult<()> {
+        let value = self.map.remove(name).ok_or(FsError::ENODATA)?;
+        self.size -= xattr_size(name, &value);
+        Ok(())
+    }
+
+    /// The list as stored on disk.
+    pub fn encode(&self) -> Vec<u8> {
+        encode_xattrs(self.map.iter().map(|(name, value)| (&name[..], &value[..])))
+    }
+
+    pub fn spill(&self) -> &Blocks {
+        &self.spill
+    }
+
+    pub fn spill_mut(&mut self) -> &mut Blocks {
+        &mut self.spill
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        assert_eq!(namespace(b"user.hash"), Ok(Namespace::User));
+        assert_eq!(namespace(b"trusted.x"), Ok(Namespace::Trusted));
+        assert_eq!(namespace(b"user."), Err(FsError::EINVAL));
+        assert_eq!(namespace(b"security.selinux"), Err(FsError::EOPNOTSUPP));
+        assert_eq!(namespace(b"user"), Err(FsError::EOPNOTSUPP));
+        let mut long = b"user.".to_vec();
+        long.resize(XATTR_NAME_MAX + 1, b'a');
+        assert_eq!(namespace(&long), Err(FsError::ERANGE));
+
+        let mut xattrs = Xattrs::new(None);
+        let big = vec![1u8; XATTR_SIZE_MAX];
+        assert_eq!(xattrs.set(b"user.a", &big, 0), Ok(()));
+        assert_eq!(xattrs.set(b"user.b", &big[..1], XATTR_REPLACE), Err(FsError::ENODATA));
+        assert_eq!(xattrs.set(b"user.a", b"", XATTR_CREATE), Err(FsError::EEXIST));
+        assert_eq!(xattrs.set(b"user.b", &[0u8; XATTR_SIZE_MAX + 1], 0), Err(FsError::E2BIG));
+        assert_eq!(xattrs.set(b"user.b", &big, 0), Err(FsError::ENOSPC));
+        assert_eq!(xattrs.set(b"user.b", &big, 4), Err(FsError::EINVAL));
+
+        // Replacing a value makes room for the new one first
+        assert_eq!(xattrs.set(b"user.a", b"small", XATTR_REPLACE), Ok(()));
+        assert_eq!(xattrs.set(b"user.b", &big, 0), Ok(()));
+        assert_eq!(xattrs.get(b"user.a"), Some(&b"small"[..]));
+        assert_eq!(xattrs.names(), vec![&b"user.a"[..], &b"user.b"[..]]);
+        assert_eq!(xattrs.size, xattrs.encode().len());
+
+        assert_eq!(xattrs.remove(b"user.b"), Ok(()));
+        assert_eq!(xattrs.remove(b"user.b"), Err(FsError::ENODATA));
+        assert_eq!(xattrs.size, xattrs.encode().len());
+    }
+}
@@ -34,12 +34,33 @@ pub enum BdevError {
     #[fail(display = "Error in write zeroes({}): {}", _0, _1)]
     WriteZeroesError(String, i32),
 
+    #[fail(display = "Error in unmap blocks({}): {}", _0, _1)]
+    UnmapBlocksError(String, i32),
+
+    #[fail(display = "Error in flush({}): {}", _0, _1)]
+    FlushError(String, i32),
+
+    #[fail(display = "Error in flush blocks({}): {}", _0, _1)]
+    FlushBlocksError(String, i32),
+
     #[fail(
         display = "Error in read completion({}): {}, offset: {}, length: {}",
         _0, _1, _2, _3
     )]
     ReadError(String, i32, u64, u64),
 
+    #[fail(
+        display = "Error in write blocks completion({}): {}, offset_blocks: {}, num_blocks: {}",
+        _0, _1, _2, _3
+    )]
+    WriteBlocksError(String, i32, u64, u64),
+
+    #[fail(
+        display = "Error in read blocks completion({}): {}, offset_blocks: {}, num_blocks: {}",
+        _0, _1, _2, _3
+    )]
+    ReadBlocksError(String, i32, u64, u64),
+
     #[fail(display = "Could not find a bdev: {}", _0)]
     NotFound(String),
 
@@ -245,6 +266,123 @@ pub async fn write_zeroes_blocks<'a>(
     }
 }
 
+/// spdk_bdev_unmap_blocks()
+pub async fn unmap_blocks<'a>(
+    desc: SpdkBdevDesc,
+    ch: &'a thread::SpdkIoChannel,
+    offset_blocks: u64,
+    num_blocks: u64,
+) -> Result<(), Error> {
+    let (sender, receiver) = oneshot::channel();
+    let arg = cb_arg::<()>(sender);
+    let ret = unsafe {
+        raw::spdk_bdev_unmap_blocks(
+            desc.raw,
+            ch.to_raw(),
+            offset_blocks,
+            num_blocks,
+            Some(spdk_bdev_io_completion_cb),
+            arg,
+        )
+    };
+    if ret != 0 {
+        // The I/O was never submitted, so the callback will not free the
+        // sender
+        unsafe { drop(Box::from_raw(arg as *mut Sender<Result<(), i32>>)) };
+        return Err(BdevError::UnmapBlocksError(
+            desc.spdk_bdev_desc_get_bdev().name().to_string(),
+            ret,
+        ))?;
+    }
+    let res = await!(receiver).expect("Cancellation is not supported");
+
+    match res {
+        Ok(()) => Ok(()),
+        Err(_e) => Err(BdevError::UnmapBlocksError(
+            desc.spdk_bdev_desc_get_bdev().name().to_string(),
+            -1,
+        ))?,
+    }
+}
+
+/// spdk_bdev_flush()
+pub async fn flush<'a>(
+    desc: SpdkBdevDesc,
+    ch: &'a thread::SpdkIoChannel,
+    offset: u64,
+    length: u64,
+) -> Result<(), Error> {
+    let (sender, receiver) = oneshot::channel();
+    let arg = cb_arg::<()>(sender);
+    let ret = unsafe {
+        raw::spdk_bdev_flush(
+            desc.raw,
+            ch.to_raw(),
+            offset,
+            length,
+            Some(spdk_bdev_io_completion_cb),
+            arg,
+        )
+    };
+    if ret != 0 {
+        // The I/O was never submitted, so the callback will not free the
+        // sender
+        unsafe { drop(Box::from_raw(arg as *mut Sender<Result<(), i32>>)) };
+        return Err(BdevError::FlushError(
+            desc.spdk_bdev_desc_get_bdev().name().to_string(),
+            ret,
+        ))?;
+    }
+    let res = await!(receiver).expect("Cancellation is not supported");
+
+    match res {
+        Ok(()) => Ok(()),
+        Err(_e) => Err(BdevError::FlushError(
+            desc.spdk_bdev_desc_get_bdev().name().to_string(),
+            -1,
+        ))?,
+    }
+}
+
+/// spdk_bdev_flush_blocks()
+pub async fn flush_blocks<'a>(
+    desc: SpdkBdevDesc,
+    ch: &'a thread::SpdkIoChannel,
+    offset_blocks: u64,
+    num_blocks: u64,
+) -> Result<(), Error> {
+    let (sender, receiver) = oneshot::channel();
+    let arg = cb_arg::<()>(sender);
+    let ret = unsafe {
+        raw::spdk_bdev_flush_blocks(
+            desc.raw,
+            ch.to_raw(),
+            offset_blocks,
+            num_blocks,
+            Some(spdk_bdev_io_completion_cb),
+            arg,
+        )
+    };
+    if ret != 0 {
+        // The I/O was never submitted, so the callback will not free the
+        // sender
+        unsafe { drop(Box::from_raw(arg as *mut Sender<Result<(), i32>>)) };
+        return Err(BdevError::FlushBlocksError(
+            desc.spdk_bdev_desc_get_bdev().name().to_string(),
+            ret,
+        ))?;
+    }
+    let res = await!(receiver).expect("Cancellation is not supported");
+
+    match res {
+        Ok(()) => Ok(()),
+        Err(_e) => Err(BdevError::FlushBlocksError(
+            desc.spdk_bdev_desc_get_bdev().name().to_string(),
+            -1,
+        ))?,
+    }
+}
+
 /// spdk_bdev_read()
 pub async fn read<'a>(
     desc: SpdkBdevDesc,
@@ -281,6 +419,190 @@ pub async fn read<'a>(
     }
 }
 
+/// spdk_bdev_writev()
+pub async fn writev<'a>(
+    desc: SpdkBdevDesc,
+    ch: &'a thread::SpdkIoChannel,
+    iovs: &'a mut env::IoVecs,
+    offset: u64,
+    nbytes: u64,
+) -> Result<(), Error> {
+    let (sender, receiver) = oneshot::channel();
+    let arg = cb_arg::<()>(sender);
+    let ret = unsafe {
+        raw::spdk_bdev_writev(
+            desc.raw,
+            ch.to_raw(),
+            iovs.to_raw(),
+            iovs.len() as i32,
+            offset,
+            nbytes,
+            Some(spdk_bdev_io_completion_cb),
+            arg,
+        )
+    };
+    if ret != 0 {
+        // The I/O was never submitted, so the callback will not free the
+        // sender
+        unsafe { drop(Box::from_raw(arg as *mut Sender<Result<(), i32>>)) };
+        return Err(BdevError::WriteError(
+            desc.spdk_bdev_desc_get_bdev().name().to_string(),
+            ret,
+            offset,
+            nbytes,
+        ))?;
+    }
+    let res = await!(receiver).expect("Cancellation is not supported");
+
+    match res {
+        Ok(()) => Ok(()),
+        Err(_e) => Err(BdevError::WriteError(
+            desc.spdk_bdev_desc_get_bdev().name().to_string(),
+            -1,
+            offset,
+            nbytes,
+        ))?,
+    }
+}
+
+/// spdk_bdev_writev_blocks()
+pub async fn writev_blocks<'a>(
+    desc: SpdkBdevDesc,
+    ch: &'a thread::SpdkIoChannel,
+    iovs: &'a mut env::IoVecs,
+    offset_blocks: u64,
+    num_blocks: u64,
+) -> Result<(), Error> {
+    let (sender, receiver) = oneshot::channel();
+    let arg = cb_arg::<()>(sender);
+    let ret = unsafe {
+        raw::spdk_bdev_writev_blocks(
+            desc.raw,
+            ch.to_raw(),
+            iovs.to_raw(),
+            iovs.len() as i32,
+            offset_blocks,
+            num_blocks,
+            Some(spdk_bdev_io_completion_cb),
+            arg,
+        )
+    };
+    if ret != 0 {
+        // The I/O was never submitted, so the callback will not free the
+        // sender
+        unsafe { drop(Box::from_raw(arg as *mut Sender<Result<(), i32>>)) };
+        return Err(BdevError::WriteBlocksError(
+            desc.spdk_bdev_desc_get_bdev().name().to_string(),
+            ret,
+            offset_blocks,
+            num_blocks,
+        ))?;
+    }
+    let res = await!(receiver).expect("Cancellation is not supported");
+
+    match res {
+        Ok(()) => Ok(()),
+        Err(_e) => Err(BdevError::WriteBlocksError(
+            desc.spdk_bdev_desc_get_bdev().name().to_string(),
+            -1,
+            offset_blocks,
+            num_blocks,
+        ))?,
+    }
+}
+
+/// spdk_bdev_readv()
+pub async fn readv<'a>(
+    desc: SpdkBdevDesc,
+    ch: &'a thread::SpdkIoChannel,
+    iovs: &'a mut env::IoVecs,
+    offset: u64,
+    nbytes: u64,
+) -> Result<(), Error> {
+    let (sender, receiver) = oneshot::channel();
+    let arg = cb_arg::<()>(sender);
+    let ret = unsafe {
+        raw::spdk_bdev_readv(
+            desc.raw,
+            ch.to_raw(),
+            iovs.to_raw(),
+            iovs.len() as i32,
+            offset,
+            nbytes,
+            Some(spdk_bdev_io_completion_cb),
+            arg,
+        )
+    };
+    if ret != 0 {
+        // The I/O was never submitted, so the callback will not free the
+        // sender
+        unsafe { drop(Box::from_raw(arg as *mut Sender<Result<(), i32>>)) };
+        return Err(BdevError::ReadError(
+            desc.spdk_bdev_desc_get_bdev().name().to_string(),
+            ret,
+            offset,
+            nbytes,
+        ))?;
+    }
+    let res = await!(receiver).expect("Cancellation is not supported");
+
+    match res {
+        Ok(()) => Ok(()),
+        Err(_e) => Err(BdevError::ReadError(
+            desc.spdk_bdev_desc_get_bdev().name().to_string(),
+            -1,
+            offset,
+            nbytes,
+        ))?,
+    }
+}
+
+/// spdk_bdev_readv_blocks()
+pub async fn readv_blocks<'a>(
+    desc: SpdkBdevDesc,
+    ch: &'a thread::SpdkIoChannel,
+    iovs: &'a mut env::IoVecs,
+    offset_blocks: u64,
+    num_blocks: u64,
+) -> Result<(), Error> {
+    let (sender, receiver) = oneshot::channel();
+    let arg = cb_arg::<()>(sender);
+    let ret = unsafe {
+        raw::spdk_bdev_readv_blocks(
+            desc.raw,
+            ch.to_raw(),
+            iovs.to_raw(),
+            iovs.len() as i32,
+            offset_blocks,
+            num_blocks,
+            Some(spdk_bdev_io_completion_cb),
+            arg,
+        )
+    };
+    if ret != 0 {
+        // The I/O was never submitted, so the callback will not free the
+        // sender
+        unsafe { drop(Box::from_raw(arg as *mut Sender<Result<(), i32>>)) };
+        return Err(BdevError::ReadBlocksError(
+            desc.spdk_bdev_desc_get_bdev().name().to_string(),
+            ret,
+            offset_blocks,
+            num_blocks,
+        ))?;
+    }
+    let res = await!(receiver).expect("Cancellation is not supported");
+
+    match res {
+        Ok(()) => Ok(()),
+        Err(_e) => Err(BdevError::ReadBlocksError(
+            desc.spdk_bdev_desc_get_bdev().name().to_string(),
+            -1,
+            offset_blocks,
+            num_blocks,
+        ))?,
+    }
+}
+
 /// spdk_bdev_has_write_cache()
 pub fn has_write_cache(bdev: SpdkBdev) -> bool {
     unsafe { raw::spdk_bdev_has_write_cache(bdev.to_raw()) }
@@ -165,6 +165,41 @@ impl Buf {
     }
 }
 
+/// A list of buffers for vectored I/O, passed to SPDK as an array of
+/// `struct iovec`. It does not own the buffers, which must stay allocated
+/// until the I/O using them completes.
+pub struct IoVecs {
+    iovs: Vec<raw::iovec>,
+}
+
+impl IoVecs {
+    pub fn new() -> IoVecs {
+        IoVecs { iovs: Vec::new() }
+    }
+
+    /// Append the first `len` bytes of `buf`
+    pub fn push(&mut self, buf: &Buf, len: usize) {
+        self.iovs.push(raw::iovec {
+            iov_base: buf.to_raw(),
+            iov_len: len,
+        });
+    }
+
+    /// Number of buffers
+    pub fn len(&self) -> usize {
+        self.iovs.len()
+    }
+
+    /// Total number of bytes over all buffers
+    pub fn nbytes(&self) -> u64 {
+        self.iovs.iter().map(|iov| iov.iov_len as u64).sum()
+    }
+
+    pub fn to_raw(&mut self) -> *mut raw::iovec {
+        self.iovs.as_mut_ptr()
+    }
+}
+
 /// spdk_dma_zmalloc()
 pub fn dma_zmalloc(size: usize, align: usize) -> Buf {
     let ptr;
@@ -175,6 +210,11 @@ pub fn dma_zmalloc(size: usize, align: usize) -> Buf {
     Buf { raw: ptr }
 }
 
+/// spdk_dma_free()
+pub fn dma_free(buf: Buf) {
+    unsafe { raw::spdk_dma_free(buf.to_raw()) }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
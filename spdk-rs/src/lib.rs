@@ -32,5 +32,5 @@ pub mod thread;
 pub use bdev::{SpdkBdev, SpdkBdevDesc};
 pub use bdev_module::SpdkBdevIO;
 pub use context::{AppContext, SpdkBdevIoCompletionCb};
-pub use env::Buf;
+pub use env::{Buf, IoVecs};
 pub use event::{app_stop, SpdkAppOpts};